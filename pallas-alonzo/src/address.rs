@@ -0,0 +1,64 @@
+use pallas_network_params::Network;
+
+use crate::{NetworkId, TransactionOutput};
+
+/// The network id in the header of an address (or reward account), `None`
+/// for Byron addresses, which carry the protocol magic in their attributes
+/// instead
+pub fn address_network_id(address: &[u8]) -> Option<u8> {
+    let header = address.first()?;
+
+    match header >> 4 {
+        // base, pointer, enterprise and reward addresses
+        0..=7 | 14 | 15 => Some(header & 0x0f),
+        _ => None,
+    }
+}
+
+/// Whether the address belongs to the network, `None` if it can't be told
+/// from its header
+pub fn address_is_on(address: &[u8], network: Network) -> Option<bool> {
+    address_network_id(address).map(|id| id == network.network_id())
+}
+
+impl TransactionOutput {
+    /// The network id of the address of the output, see [address_network_id]
+    pub fn network_id(&self) -> Option<u8> {
+        address_network_id(&self.address)
+    }
+}
+
+impl From<Network> for NetworkId {
+    fn from(network: Network) -> Self {
+        match network.network_id() {
+            0 => NetworkId::One,
+            _ => NetworkId::Two,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas_network_params::Network;
+
+    use crate::{address_is_on, address_network_id, NetworkId};
+
+    #[test]
+    fn network_id_is_read_from_shelley_headers() {
+        // enterprise address on mainnet, base address on a testnet
+        assert_eq!(address_network_id(&[0x61, 0xaa]), Some(1));
+        assert_eq!(address_network_id(&[0x00, 0xaa]), Some(0));
+        assert_eq!(address_is_on(&[0x61, 0xaa], Network::Mainnet), Some(true));
+        assert_eq!(address_is_on(&[0x61, 0xaa], Network::Preview), Some(false));
+
+        // byron addresses start with a cbor array
+        assert_eq!(address_network_id(&[0x82, 0xd8]), None);
+        assert_eq!(address_network_id(&[]), None);
+    }
+
+    #[test]
+    fn tx_body_network_id_matches_address_id() {
+        assert_eq!(NetworkId::from(Network::Mainnet), NetworkId::Two);
+        assert_eq!(NetworkId::from(Network::Preprod), NetworkId::One);
+    }
+}
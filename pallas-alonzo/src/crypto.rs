@@ -4,7 +4,7 @@ use minicbor::{to_vec, Encode};
 
 pub type Hash32 = [u8; 32];
 
-pub type Error = Box<dyn std::error::Error>;
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
 // TODO: think if we should turn this into a blanket implementation of a new
 // trait
@@ -43,11 +43,12 @@ mod tests {
         let block_idx = 1;
         let block_str = include_str!("test_data/test1.block");
 
-        let block_bytes = hex::decode(block_str).expect(&format!("bad block file {}", block_idx));
+        let block_bytes =
+            hex::decode(block_str).unwrap_or_else(|_| panic!("bad block file {}", block_idx));
         let block_model = BlockWrapper::decode_fragment(&block_bytes[..])
-            .expect(&format!("error decoding cbor for file {}", block_idx));
+            .unwrap_or_else(|_| panic!("error decoding cbor for file {}", block_idx));
 
-        let valid_hashes = vec![
+        let valid_hashes = [
             "8ae0cd531635579a9b52b954a840782d12235251fb1451e5c699e864c677514a",
             "bb5bb4e1c09c02aa199c60e9f330102912e3ef977bb73ecfd8f790945c6091d4",
             "8cdd88042ddb6c800714fb1469fb1a1a93152aae3c87a81f2a3016f2ee5c664a",
@@ -56,10 +57,8 @@ mod tests {
         ];
 
         for (tx_idx, tx) in block_model.1.transaction_bodies.iter().enumerate() {
-            let computed_hash = hash_transaction(tx).expect(&format!(
-                "error hashing tx {} from block {}",
-                tx_idx, block_idx
-            ));
+            let computed_hash = hash_transaction(tx)
+                .unwrap_or_else(|_| panic!("error hashing tx {} from block {}", tx_idx, block_idx));
             let known_hash = valid_hashes[tx_idx];
             assert_eq!(hex::encode(computed_hash), known_hash)
         }
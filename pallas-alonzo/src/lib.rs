@@ -1,9 +1,11 @@
 //! Ledger primitives and cbor codec for the Alonzo era
 
+mod address;
 mod framework;
 mod model;
 mod utils;
 
+pub use address::*;
 pub use framework::*;
 pub use model::*;
 
@@ -1291,7 +1291,7 @@ impl<'b> minicbor::Decode<'b> for Metadatum {
             }
             minicbor::data::Type::I64 => {
                 let i = d.i64()?;
-                Ok(Metadatum::Int(i as i64))
+                Ok(Metadatum::Int(i))
             }
             minicbor::data::Type::Bytes => Ok(Metadatum::Bytes(d.decode()?)),
             minicbor::data::Type::String => Ok(Metadatum::Text(d.decode()?)),
@@ -1448,13 +1448,13 @@ mod tests {
 
         for (idx, block_str) in test_blocks.iter().enumerate() {
             println!("decoding test block {}", idx + 1);
-            let bytes = hex::decode(block_str).expect(&format!("bad block file {}", idx));
+            let bytes = hex::decode(block_str).unwrap_or_else(|_| panic!("bad block file {}", idx));
 
             let block = BlockWrapper::decode_fragment(&bytes[..])
-                .expect(&format!("error decoding cbor for file {}", idx));
+                .unwrap_or_else(|_| panic!("error decoding cbor for file {}", idx));
 
-            let bytes2 =
-                to_vec(block).expect(&format!("error encoding block cbor for file {}", idx));
+            let bytes2 = to_vec(block)
+                .unwrap_or_else(|_| panic!("error encoding block cbor for file {}", idx));
 
             assert_eq!(bytes, bytes2);
         }
@@ -1,9 +1,9 @@
-use std::sync::mpsc::Receiver;
+use std::{collections::VecDeque, sync::mpsc::Receiver, time::Duration};
 
 use log::debug;
 use pallas_machines::{
-    primitives::Point, Agent, CodecError, DecodePayload, EncodePayload, MachineOutput,
-    PayloadDecoder, PayloadEncoder, Transition,
+    primitives::Point, Agent, DecodePayload, EncodePayload, MachineError, MachineOutput,
+    PipelinedAgent, Reply, Responder, Transition, LONG_WAIT,
 };
 
 #[derive(Debug, PartialEq, Clone)]
@@ -14,81 +14,45 @@ pub enum State {
     Done,
 }
 
-#[derive(Debug)]
-pub enum Message {
-    RequestRange { range: (Point, Point) },
-    ClientDone,
-    StartBatch,
-    NoBlocks,
-    Block { body: Vec<u8> },
-    BatchDone,
-}
-
-impl EncodePayload for Message {
-    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), Box<dyn std::error::Error>> {
+impl State {
+    /// How long the server can take to reply in this state, as set by the
+    /// node-to-node spec
+    pub fn time_limit(&self) -> Option<Duration> {
         match self {
-            Message::RequestRange { range } => {
-                e.array(3)?.u16(0)?;
-                range.0.encode_payload(e)?;
-                range.1.encode_payload(e)?;
-                Ok(())
-            }
-            Message::ClientDone => {
-                e.array(1)?.u16(1)?;
-                Ok(())
-            }
-            Message::StartBatch => {
-                e.array(1)?.u16(2)?;
-                Ok(())
-            }
-            Message::NoBlocks => {
-                e.array(1)?.u16(3)?;
-                Ok(())
-            }
-            Message::Block { body } => {
-                e.array(2)?.u16(4)?;
-                e.bytes(body)?;
-                Ok(())
-            }
-            Message::BatchDone => {
-                e.array(1)?.u16(5)?;
-                Ok(())
-            }
+            State::Busy => Some(LONG_WAIT),
+            State::Streaming => Some(LONG_WAIT),
+            _ => None,
         }
     }
 }
 
-impl DecodePayload for Message {
-    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, Box<dyn std::error::Error>> {
-        d.array()?;
-        let label = d.u16()?;
-
-        match label {
-            0 => {
-                let point1 = Point::decode_payload(d)?;
-                let point2 = Point::decode_payload(d)?;
-                Ok(Message::RequestRange {
-                    range: (point1, point2),
-                })
-            }
-            1 => Ok(Message::ClientDone),
-            2 => Ok(Message::StartBatch),
-            3 => Ok(Message::NoBlocks),
-            4 => {
-                d.tag()?;
-                let body = d.bytes()?;
-                Ok(Message::Block {
-                    body: Vec::from(body),
-                })
-            }
-            5 => Ok(Message::BatchDone),
-            x => Err(Box::new(CodecError::BadLabel(x))),
-        }
-    }
+#[derive(Debug, EncodePayload, DecodePayload)]
+pub enum Message {
+    #[label(0)]
+    RequestRange {
+        #[flatten]
+        range: (Point, Point),
+    },
+    #[label(1)]
+    ClientDone,
+    #[label(2)]
+    StartBatch,
+    #[label(3)]
+    NoBlocks,
+    #[label(4)]
+    Block {
+        #[wrapped]
+        body: Vec<u8>,
+    },
+    #[label(5)]
+    BatchDone,
 }
 
 pub trait Observer {
-    fn on_block_received(&self, body: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+    fn on_block_received(
+        &self,
+        body: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         log::debug!("block received, sice: {}", body.len());
         Ok(())
     }
@@ -96,7 +60,7 @@ pub trait Observer {
     fn on_block_range_requested(
         &self,
         range: &(Point, Point),
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         log::debug!(
             "block range requested, from: {:?}, to: {:?}",
             range.0,
@@ -167,6 +131,10 @@ where
         self.state == State::Done
     }
 
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
     fn has_agency(&self) -> bool {
         match self.state {
             State::Idle => true,
@@ -198,9 +166,13 @@ where
                 state: State::Done,
                 ..self
             }),
-            _ => panic!("I have agency, I don't expect messages"),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
         }
     }
+
+    fn state_timeout(&self) -> Option<Duration> {
+        self.state.time_limit()
+    }
 }
 
 #[derive(Debug)]
@@ -261,6 +233,10 @@ where
         false
     }
 
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
     fn has_agency(&self) -> bool {
         match self.state {
             State::Idle => true,
@@ -292,7 +268,255 @@ where
                 state: State::Idle,
                 ..self
             }),
-            _ => panic!("I have agency, I don't expect messages"),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
+        }
+    }
+
+    fn state_timeout(&self) -> Option<Duration> {
+        self.state.time_limit()
+    }
+}
+
+/// A client that requests several ranges ahead of the batches that answer
+/// them
+///
+/// The state follows the oldest request waiting for its batch.
+#[derive(Debug)]
+pub struct PipelinedBatchClient<O>
+where
+    O: Observer,
+{
+    pub state: State,
+    pub ranges: VecDeque<(Point, Point)>,
+    pub requested: usize,
+    pub completed: usize,
+    pub observer: O,
+}
+
+impl<O> PipelinedBatchClient<O>
+where
+    O: Observer,
+{
+    pub fn initial(ranges: Vec<(Point, Point)>, observer: O) -> Self {
+        Self {
+            state: State::Idle,
+            ranges: ranges.into(),
+            requested: 0,
+            completed: 0,
+            observer,
+        }
+    }
+
+    fn on_batch_completed(self) -> Transition<Reply<Self>> {
+        let completed = self.completed + 1;
+
+        // busy only while replies to other requests are still outstanding
+        let state = if completed == self.requested {
+            State::Idle
+        } else {
+            State::Busy
+        };
+
+        Ok(Reply::Complete(Self {
+            state,
+            completed,
+            ..self
+        }))
+    }
+}
+
+impl<O> PipelinedAgent for PipelinedBatchClient<O>
+where
+    O: Observer,
+{
+    type Message = Message;
+
+    fn is_done(&self) -> bool {
+        self.ranges.is_empty() && self.requested == self.completed
+    }
+
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    fn has_request(&self) -> bool {
+        !self.ranges.is_empty()
+    }
+
+    fn send_request(mut self, tx: &impl MachineOutput) -> Transition<Self> {
+        let range = self.ranges.pop_front().expect("no range left to request");
+
+        tx.send_msg(&Message::RequestRange {
+            range: range.clone(),
+        })?;
+
+        self.observer.on_block_range_requested(&range)?;
+
+        let state = match self.state {
+            State::Idle => State::Busy,
+            state => state,
+        };
+
+        Ok(Self {
+            state,
+            requested: self.requested + 1,
+            ..self
+        })
+    }
+
+    fn receive_reply(self, msg: Self::Message) -> Transition<Reply<Self>> {
+        match (&self.state, msg) {
+            (State::Busy, Message::StartBatch) => Ok(Reply::Partial(Self {
+                state: State::Streaming,
+                ..self
+            })),
+            (State::Busy, Message::NoBlocks) => self.on_batch_completed(),
+            (State::Streaming, Message::Block { body }) => {
+                debug!("received block body, size {}", body.len());
+                self.observer.on_block_received(body)?;
+                Ok(Reply::Partial(self))
+            }
+            (State::Streaming, Message::BatchDone) => self.on_batch_completed(),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
+        }
+    }
+
+    fn reply_timeout(&self) -> Option<Duration> {
+        self.state.time_limit()
+    }
+}
+
+/// The source of the blocks served by a [Server], provided by the application
+pub trait BlockProvider {
+    /// The bodies of the blocks in the range, both ends included. `None` if
+    /// any of the blocks isn't available.
+    fn read_range(
+        &mut self,
+        range: &(Point, Point),
+    ) -> Result<Option<Vec<Vec<u8>>>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// The server side of block-fetch, which streams the blocks of a
+/// [BlockProvider]
+#[derive(Debug)]
+pub struct Server {
+    pub state: State,
+    pub range: Option<(Point, Point)>,
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl Server {
+    pub fn initial() -> Self {
+        Self {
+            state: State::Idle,
+            range: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn send_batch_start(
+        self,
+        tx: &impl MachineOutput,
+        provider: &mut impl BlockProvider,
+    ) -> Transition<Self> {
+        let range = self.range.as_ref().expect("busy without a requested range");
+
+        match provider.read_range(range)? {
+            Some(bodies) => {
+                tx.send_msg(&Message::StartBatch)?;
+
+                Ok(Self {
+                    state: State::Streaming,
+                    pending: bodies.into(),
+                    ..self
+                })
+            }
+            None => {
+                debug!("no blocks available for range {:?}", range);
+                tx.send_msg(&Message::NoBlocks)?;
+
+                Ok(Self {
+                    state: State::Idle,
+                    range: None,
+                    ..self
+                })
+            }
+        }
+    }
+
+    fn send_block(mut self, tx: &impl MachineOutput) -> Transition<Self> {
+        match self.pending.pop_front() {
+            Some(body) => {
+                debug!("sending block body, size {}", body.len());
+                tx.send_msg(&Message::Block { body })?;
+
+                Ok(self)
+            }
+            None => {
+                tx.send_msg(&Message::BatchDone)?;
+
+                Ok(Self {
+                    state: State::Idle,
+                    range: None,
+                    ..self
+                })
+            }
+        }
+    }
+
+    fn on_range_requested(self, range: (Point, Point)) -> Transition<Self> {
+        debug!(
+            "block range requested, from: {:?}, to: {:?}",
+            range.0, range.1
+        );
+
+        Ok(Self {
+            state: State::Busy,
+            range: Some(range),
+            ..self
+        })
+    }
+}
+
+impl<P> Responder<P> for Server
+where
+    P: BlockProvider,
+{
+    type Message = Message;
+
+    fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    fn has_agency(&self) -> bool {
+        match self.state {
+            State::Idle => false,
+            State::Busy => true,
+            State::Streaming => true,
+            State::Done => false,
+        }
+    }
+
+    fn send_next(self, tx: &impl MachineOutput, provider: &mut P) -> Transition<Self> {
+        match self.state {
+            State::Busy => self.send_batch_start(tx, provider),
+            State::Streaming => self.send_block(tx),
+            _ => panic!("I don't have agency, don't know what to do"),
+        }
+    }
+
+    fn receive_next(self, msg: Self::Message) -> Transition<Self> {
+        match (&self.state, msg) {
+            (State::Idle, Message::RequestRange { range }) => self.on_range_requested(range),
+            (State::Idle, Message::ClientDone) => Ok(Self {
+                state: State::Done,
+                ..self
+            }),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
         }
     }
 }
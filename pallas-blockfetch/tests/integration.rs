@@ -0,0 +1,222 @@
+use std::{cell::RefCell, thread};
+
+use minicbor::{data::Tag, Encoder};
+use pallas_blockfetch::{
+    BatchClient, BlockProvider, Message, Observer, PipelinedBatchClient, Server, State,
+};
+use pallas_machines::{
+    primitives::Point,
+    run_agent, run_pipelined, run_responder,
+    testing::{ScriptError, ScriptedPeer},
+    to_payload, MachineError, MachineOutput,
+};
+use pallas_multiplexer::{Channel, MemoryBearer, Multiplexer, Role};
+
+fn setup_muxers() -> (Multiplexer, Multiplexer) {
+    let (client_bearer, server_bearer) = MemoryBearer::pair();
+
+    let client = Multiplexer::setup(client_bearer, Role::Initiator, &[3]).unwrap();
+    let server = Multiplexer::setup(server_bearer, Role::Responder, &[3]).unwrap();
+
+    (client, server)
+}
+
+#[derive(Debug, Default)]
+struct BlockCollector(RefCell<Vec<Vec<u8>>>);
+
+impl Observer for BlockCollector {
+    fn on_block_received(
+        &self,
+        body: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.0.borrow_mut().push(body);
+        Ok(())
+    }
+}
+
+fn block_payload(body: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    let mut e = Encoder::new(&mut payload);
+    e.array(2).unwrap().u16(4).unwrap();
+    e.tag(Tag::Cbor).unwrap().bytes(body).unwrap();
+
+    payload
+}
+
+#[test]
+fn batch_client_collects_requested_blocks() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(3).unwrap();
+    let Channel(server_tx, server_rx) = server_muxer.use_channel(3).unwrap();
+
+    let range = (
+        Point::Specific(10, [0xaa; 32]),
+        Point::Specific(20, [0xbb; 32]),
+    );
+    let request = to_payload(&Message::RequestRange {
+        range: range.clone(),
+    })
+    .unwrap();
+
+    let client = thread::spawn(move || {
+        let agent = BatchClient::initial(range, BlockCollector::default());
+        run_agent(agent, &mut client_channel).unwrap()
+    });
+
+    assert_eq!(server_rx.recv().unwrap().unwrap(), request);
+
+    server_tx.send_msg(&Message::StartBatch).unwrap();
+    server_tx.send(block_payload(&[1, 2, 3])).unwrap();
+    server_tx.send(block_payload(&[4, 5, 6])).unwrap();
+    server_tx.send_msg(&Message::BatchDone).unwrap();
+
+    let client = client.join().unwrap();
+    assert_eq!(client.state, State::Done);
+    assert_eq!(
+        client.observer.0.into_inner(),
+        vec![vec![1, 2, 3], vec![4, 5, 6]]
+    );
+}
+
+/// Blocks indexed by slot, each body made of the slot repeated
+struct SlotBlocks(Vec<u64>);
+
+impl BlockProvider for SlotBlocks {
+    fn read_range(
+        &mut self,
+        range: &(Point, Point),
+    ) -> Result<Option<Vec<Vec<u8>>>, Box<dyn std::error::Error + Send + Sync>> {
+        let bodies: Vec<_> = self
+            .0
+            .iter()
+            .filter(|slot| {
+                **slot >= range.0.slot_or_default() && **slot <= range.1.slot_or_default()
+            })
+            .map(|slot| vec![*slot as u8; 4])
+            .collect();
+
+        match bodies.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(bodies)),
+        }
+    }
+}
+
+#[test]
+fn server_streams_blocks_to_a_batch_client() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(3).unwrap();
+    let mut server_channel = server_muxer.use_channel(3).unwrap();
+
+    thread::spawn(move || {
+        let mut blocks = SlotBlocks(vec![5, 10, 15, 20, 25]);
+        run_responder(Server::initial(), &mut blocks, &mut server_channel).ok();
+    });
+
+    let range = (
+        Point::Specific(10, [0xaa; 32]),
+        Point::Specific(20, [0xbb; 32]),
+    );
+    let agent = BatchClient::initial(range, BlockCollector::default());
+    let client = run_agent(agent, &mut client_channel).unwrap();
+
+    assert_eq!(
+        client.observer.0.into_inner(),
+        vec![vec![10; 4], vec![15; 4], vec![20; 4]]
+    );
+
+    // a range without blocks ends the batch right away
+    let range = (
+        Point::Specific(30, [0xaa; 32]),
+        Point::Specific(40, [0xbb; 32]),
+    );
+    let agent = BatchClient::initial(range, BlockCollector::default());
+    let client = run_agent(agent, &mut client_channel).unwrap();
+
+    assert_eq!(client.state, State::Done);
+    assert!(client.observer.0.into_inner().is_empty());
+}
+
+#[test]
+fn pipelined_client_fetches_ranges_in_order() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(3).unwrap();
+    let mut server_channel = server_muxer.use_channel(3).unwrap();
+
+    thread::spawn(move || {
+        let mut blocks = SlotBlocks(vec![5, 10, 15, 20, 25]);
+        run_responder(Server::initial(), &mut blocks, &mut server_channel).ok();
+    });
+
+    let ranges = vec![
+        (
+            Point::Specific(5, [0xaa; 32]),
+            Point::Specific(10, [0xbb; 32]),
+        ),
+        (
+            Point::Specific(12, [0xaa; 32]),
+            Point::Specific(14, [0xbb; 32]),
+        ),
+        (
+            Point::Specific(20, [0xaa; 32]),
+            Point::Specific(25, [0xbb; 32]),
+        ),
+    ];
+
+    let agent = PipelinedBatchClient::initial(ranges, BlockCollector::default());
+    let client = run_pipelined(agent, 2, &mut client_channel).unwrap();
+
+    assert_eq!(client.completed, 3);
+    // no reply is outstanding once the last batch is done
+    assert_eq!(client.state, State::Idle);
+    assert_eq!(
+        client.observer.0.into_inner(),
+        vec![vec![5; 4], vec![10; 4], vec![20; 4], vec![25; 4]]
+    );
+}
+
+#[test]
+fn scripted_batch_client_is_done_without_blocks() {
+    let range = (
+        Point::Specific(10, [0xaa; 32]),
+        Point::Specific(20, [0xbb; 32]),
+    );
+    let request = Message::RequestRange {
+        range: range.clone(),
+    };
+
+    let agent = ScriptedPeer::new()
+        .expects(&request)
+        .sends(&Message::NoBlocks)
+        .run(BatchClient::initial(range, BlockCollector::default()))
+        .unwrap();
+
+    assert_eq!(agent.state, State::Done);
+    assert!(agent.observer.0.into_inner().is_empty());
+}
+
+#[test]
+fn scripted_batch_client_rejects_a_new_batch_while_streaming() {
+    let range = (
+        Point::Specific(10, [0xaa; 32]),
+        Point::Specific(20, [0xbb; 32]),
+    );
+    let request = Message::RequestRange {
+        range: range.clone(),
+    };
+
+    let result = ScriptedPeer::new()
+        .expects(&request)
+        .sends(&Message::StartBatch)
+        .sends_raw(block_payload(&[1, 2, 3]))
+        .sends(&Message::StartBatch)
+        .run(BatchClient::initial(range, BlockCollector::default()));
+
+    assert!(matches!(
+        result,
+        Err(ScriptError::Agent(MachineError::InvalidMsgForState { .. }))
+    ));
+}
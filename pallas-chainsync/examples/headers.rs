@@ -1,44 +1,46 @@
 use minicbor::data::Tag;
 use net2::TcpStreamExt;
-use pallas_alonzo::{crypto, Fragment, Header};
+use pallas_alonzo::{crypto, Header};
 use pallas_machines::primitives::Point;
 use std::net::TcpStream;
 
 use pallas_chainsync::{BlockLike, Consumer, NoopObserver};
 use pallas_handshake::n2n::{Client, VersionTable};
-use pallas_handshake::MAINNET_MAGIC;
-use pallas_machines::{run_agent, DecodePayload, EncodePayload, PayloadDecoder, PayloadEncoder};
-use pallas_multiplexer::Multiplexer;
+use pallas_handshake::Network;
+use pallas_machines::{
+    run_agent, CodecError, DecodePayload, EncodePayload, PayloadDecoder, PayloadEncoder,
+};
+use pallas_multiplexer::{Multiplexer, Role};
 
 #[derive(Debug)]
 pub struct Content(u32, Header);
 
 impl EncodePayload for Content {
-    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), Box<dyn std::error::Error>> {
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
         e.array(2)?;
         e.u32(self.0)?;
         e.tag(Tag::Cbor)?;
-        e.bytes(&self.1.encode_fragment()?)?;
+        e.bytes(&minicbor::to_vec(&self.1)?)?;
 
         Ok(())
     }
 }
 
 impl DecodePayload for Content {
-    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, Box<dyn std::error::Error>> {
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
         d.array()?;
         let unknown = d.u32()?; // WTF is this value?
         d.tag()?;
         let bytes = d.bytes()?;
-        let header = Header::decode_fragment(bytes)?;
+        let header = minicbor::decode(bytes)?;
         Ok(Content(unknown, header))
     }
 }
 
 impl BlockLike for Content {
-    fn block_point(&self) -> Result<Point, Box<dyn std::error::Error>> {
+    fn block_point(&self) -> Result<Point, Box<dyn std::error::Error + Send + Sync>> {
         let hash = crypto::hash_block_header(&self.1)?;
-        Ok(Point(self.1.header_body.slot, Vec::from(hash)))
+        Ok(Point::Specific(self.1.header_body.slot, hash))
     }
 }
 
@@ -49,19 +51,20 @@ fn main() {
     bearer.set_nodelay(true).unwrap();
     bearer.set_keepalive_ms(Some(30_000u32)).unwrap();
 
-    let mut muxer = Multiplexer::setup(bearer, &vec![0, 2]).unwrap();
-    let mut hs_channel = muxer.use_channel(0);
+    let mut muxer = Multiplexer::setup(bearer, Role::Initiator, &[0, 2]).unwrap();
+    let mut hs_channel = muxer.use_channel(0).unwrap();
 
-    let versions = VersionTable::v4_and_above(MAINNET_MAGIC);
+    let versions = VersionTable::v4_and_above(Network::Mainnet);
     let last = run_agent(Client::initial(versions), &mut hs_channel).unwrap();
     println!("{:?}", last);
 
-    let known_points = vec![Point(
-        43847831u64,
-        hex::decode("15b9eeee849dd6386d3770b0745e0450190f7560e5159b1b3ab13b14b2684a45").unwrap(),
-    )];
+    let known_points = vec![
+        "43847831,15b9eeee849dd6386d3770b0745e0450190f7560e5159b1b3ab13b14b2684a45"
+            .parse::<Point>()
+            .unwrap(),
+    ];
 
-    let mut cs_channel = muxer.use_channel(2);
+    let mut cs_channel = muxer.use_channel(2).unwrap();
 
     let cs = Consumer::<Content, _>::initial(known_points, NoopObserver {});
     let cs = run_agent(cs, &mut cs_channel).unwrap();
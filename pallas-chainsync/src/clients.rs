@@ -1,9 +1,10 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
 
 use log::{debug, log_enabled, trace};
 
 use pallas_machines::{
-    primitives::Point, Agent, DecodePayload, EncodePayload, MachineError, MachineOutput, Transition,
+    primitives::Point, Agent, CodecError, DecodePayload, EncodePayload, MachineError,
+    MachineOutput, PipelinedAgent, Reply, Transition,
 };
 
 use crate::{Message, State, Tip};
@@ -11,7 +12,7 @@ use crate::{Message, State, Tip};
 /// A trait to deal with polymorphic payloads in the ChainSync protocol
 /// (WrappedHeader vs BlockBody)
 pub trait BlockLike: EncodePayload + DecodePayload + Debug {
-    fn block_point(&self) -> Result<Point, Box<dyn std::error::Error>>;
+    fn block_point(&self) -> Result<Point, Box<dyn std::error::Error + Send + Sync>>;
 }
 
 /// An observer of chain-sync events sent by the state-machine
@@ -23,7 +24,7 @@ where
         &self,
         cursor: &Option<Point>,
         content: &C,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         log::debug!(
             "asked to save block content {:?} at cursor {:?}",
             content,
@@ -36,16 +37,16 @@ where
         &self,
         point: &Point,
         tip: &Tip,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         log::debug!("intersect was found {:?} (tip: {:?})", point, tip);
         Ok(())
     }
 
-    fn on_rollback(&self, point: &Point) -> Result<(), Box<dyn std::error::Error>> {
+    fn on_rollback(&self, point: &Point) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         log::debug!("asked to roll back {:?}", point);
         Ok(())
     }
-    fn on_tip_reached(&self) -> Result<(), Box<dyn std::error::Error>> {
+    fn on_tip_reached(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         log::debug!("tip was reached");
         Ok(())
     }
@@ -194,6 +195,10 @@ where
         self.state == State::Done
     }
 
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
     fn has_agency(&self) -> bool {
         match self.state {
             State::Idle => true,
@@ -233,7 +238,106 @@ where
                 self.on_intersect_found(point, tip)
             }
             (State::Intersect, Message::IntersectNotFound(tip)) => self.on_intersect_not_found(tip),
-            (_, msg) => Err(MachineError::InvalidMsgForState(self.state, msg).into()),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
+        }
+    }
+
+    fn state_timeout(&self) -> Option<Duration> {
+        self.state.time_limit()
+    }
+}
+
+/// A [Consumer] that requests the next blocks ahead of the replies
+///
+/// Once the intersection is found, `RequestNext` messages are sent without
+/// waiting for the previous ones to be answered, up to the depth given to
+/// the driver. The state of the inner consumer follows the oldest request
+/// waiting for a reply.
+#[derive(Debug)]
+pub struct PipelinedConsumer<C, O>
+where
+    O: Observer<C>,
+    C: Debug,
+{
+    pub consumer: Consumer<C, O>,
+}
+
+impl<C, O> PipelinedConsumer<C, O>
+where
+    C: BlockLike + EncodePayload + DecodePayload + Debug + 'static,
+    O: Observer<C>,
+{
+    pub fn initial(known_points: Vec<Point>, observer: O) -> Self {
+        Self {
+            consumer: Consumer::initial(known_points, observer),
+        }
+    }
+}
+
+impl<C, O> PipelinedAgent for PipelinedConsumer<C, O>
+where
+    C: BlockLike + EncodePayload + DecodePayload + Debug + 'static,
+    O: Observer<C>,
+{
+    type Message = Message<C>;
+
+    fn is_done(&self) -> bool {
+        self.consumer.is_done()
+    }
+
+    fn state_name(&self) -> String {
+        format!("{:?}", self.consumer.state)
+    }
+
+    fn has_request(&self) -> bool {
+        match self.consumer.state {
+            State::Idle => true,
+            // only requests for the next block can be pipelined
+            State::CanAwait => self.consumer.cursor.is_some(),
+            State::MustReply => self.consumer.cursor.is_some(),
+            State::Intersect => false,
+            State::Done => false,
+        }
+    }
+
+    fn send_request(self, tx: &impl MachineOutput) -> Transition<Self> {
+        let consumer = self.consumer;
+
+        let consumer = match (&consumer.state, &consumer.cursor) {
+            (State::Idle, None) => consumer.send_find_intersect(tx)?,
+            (State::Idle, Some(_)) => consumer.send_request_next(tx)?,
+            // the state follows the oldest request, which is already sent
+            (state, _) => {
+                let state = state.clone();
+                let consumer = consumer.send_request_next(tx)?;
+                Consumer { state, ..consumer }
+            }
+        };
+
+        Ok(Self { consumer })
+    }
+
+    fn receive_reply(self, msg: Self::Message) -> Transition<Reply<Self>> {
+        let mut consumer = self.consumer;
+
+        // the previous request was completed, the next one was sent already
+        if consumer.state == State::Idle {
+            consumer.state = State::CanAwait;
+        }
+
+        let partial = matches!(msg, Message::AwaitReply);
+        let consumer = consumer.receive_next(msg)?;
+
+        match partial {
+            true => Ok(Reply::Partial(Self { consumer })),
+            false => Ok(Reply::Complete(Self { consumer })),
+        }
+    }
+
+    fn reply_timeout(&self) -> Option<Duration> {
+        match self.consumer.state {
+            State::Idle => State::CanAwait.time_limit(),
+            ref state => state.time_limit(),
         }
     }
 }
@@ -290,24 +394,19 @@ impl TipFinder {
 pub struct NoopContent {}
 
 impl EncodePayload for NoopContent {
-    fn encode_payload(
-        &self,
-        _e: &mut pallas_machines::PayloadEncoder,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    fn encode_payload(&self, _e: &mut pallas_machines::PayloadEncoder) -> Result<(), CodecError> {
         todo!()
     }
 }
 
 impl DecodePayload for NoopContent {
-    fn decode_payload(
-        _d: &mut pallas_machines::PayloadDecoder,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
+    fn decode_payload(_d: &mut pallas_machines::PayloadDecoder) -> Result<Self, CodecError> {
         todo!()
     }
 }
 
 impl BlockLike for NoopContent {
-    fn block_point(&self) -> Result<Point, Box<dyn std::error::Error>> {
+    fn block_point(&self) -> Result<Point, Box<dyn std::error::Error + Send + Sync>> {
         todo!()
     }
 }
@@ -319,6 +418,10 @@ impl Agent for TipFinder {
         self.state == State::Done
     }
 
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
     fn has_agency(&self) -> bool {
         match self.state {
             State::Idle => true,
@@ -342,7 +445,11 @@ impl Agent for TipFinder {
                 self.on_intersect_found(tip)
             }
             (State::Intersect, Message::IntersectNotFound(tip)) => self.on_intersect_not_found(tip),
-            (_, msg) => Err(MachineError::InvalidMsgForState(self.state, msg).into()),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
         }
     }
+
+    fn state_timeout(&self) -> Option<Duration> {
+        self.state.time_limit()
+    }
 }
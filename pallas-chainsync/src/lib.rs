@@ -1,7 +1,8 @@
 mod clients;
 mod codec;
 mod protocol;
+mod servers;
 
 pub use clients::*;
-pub use codec::*;
 pub use protocol::*;
+pub use servers::*;
@@ -1,6 +1,6 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
 
-use pallas_machines::{primitives::Point, DecodePayload, EncodePayload};
+use pallas_machines::{primitives::Point, DecodePayload, EncodePayload, SHORT_WAIT};
 
 #[derive(Debug)]
 pub struct Tip(pub Point, pub u64);
@@ -14,18 +14,42 @@ pub enum State {
     Done,
 }
 
+/// The upper bound of the random limit the spec sets for `MustReply`
+const MUST_REPLY_WAIT: Duration = Duration::from_secs(269);
+
+impl State {
+    /// How long the server can take to reply in this state, as set by the
+    /// node-to-node spec
+    pub fn time_limit(&self) -> Option<Duration> {
+        match self {
+            State::Intersect => Some(SHORT_WAIT),
+            State::CanAwait => Some(SHORT_WAIT),
+            State::MustReply => Some(MUST_REPLY_WAIT),
+            _ => None,
+        }
+    }
+}
+
 /// A generic chain-sync message for either header or block content
-#[derive(Debug)]
+#[derive(Debug, EncodePayload, DecodePayload)]
 pub enum Message<C>
 where
     C: EncodePayload + DecodePayload + Sized,
 {
+    #[label(0)]
     RequestNext,
+    #[label(1)]
     AwaitReply,
+    #[label(2)]
     RollForward(C, Tip),
+    #[label(3)]
     RollBackward(Point, Tip),
+    #[label(4)]
     FindIntersect(Vec<Point>),
+    #[label(5)]
     IntersectFound(Point, Tip),
+    #[label(6)]
     IntersectNotFound(Tip),
+    #[label(7)]
     Done,
 }
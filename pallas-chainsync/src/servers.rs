@@ -0,0 +1,198 @@
+use std::fmt::Debug;
+
+use log::debug;
+
+use pallas_machines::{
+    primitives::Point, DecodePayload, EncodePayload, MachineError, MachineOutput, Responder,
+    Transition,
+};
+
+use crate::{Message, State, Tip};
+
+/// A change of the chain to be sent to the client
+#[derive(Debug)]
+pub enum ChainUpdate<C> {
+    RollForward(C, Tip),
+    RollBackward(Point, Tip),
+}
+
+/// The source of the chain served by a [Producer], provided by the
+/// application
+///
+/// The provider keeps the read pointer of the client, which starts at the
+/// origin and moves on each intersection and update.
+pub trait ChainProvider<C> {
+    /// Moves the read pointer to the first of the points (in order of
+    /// preference) that is part of the chain, returning it along with the
+    /// current tip. `None` if there's no intersection.
+    fn find_intersect(
+        &mut self,
+        points: &[Point],
+    ) -> Result<(Option<Point>, Tip), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// The update that follows the read pointer, `None` if the client is
+    /// already at the tip
+    fn request_next(
+        &mut self,
+    ) -> Result<Option<ChainUpdate<C>>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Blocks until the chain moves past the read pointer, once the client
+    /// was told to await
+    fn await_next(&mut self) -> Result<ChainUpdate<C>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// The server side of chain-sync, which serves the chain of a
+/// [ChainProvider]
+#[derive(Debug)]
+pub struct Producer<C> {
+    pub state: State,
+    pub requested_points: Vec<Point>,
+
+    // as recommended here: https://doc.rust-lang.org/error-index.html#E0207
+    _phantom: Option<C>,
+}
+
+impl<C> Producer<C>
+where
+    C: EncodePayload + DecodePayload + Debug,
+{
+    pub fn initial() -> Self {
+        Self {
+            state: State::Idle,
+            requested_points: Vec::new(),
+            _phantom: None,
+        }
+    }
+
+    fn send_update(self, tx: &impl MachineOutput, update: ChainUpdate<C>) -> Transition<Self> {
+        let msg = match update {
+            ChainUpdate::RollForward(content, tip) => Message::RollForward(content, tip),
+            ChainUpdate::RollBackward(point, tip) => Message::RollBackward(point, tip),
+        };
+
+        tx.send_msg(&msg)?;
+
+        Ok(Self {
+            state: State::Idle,
+            ..self
+        })
+    }
+
+    fn send_intersect(
+        self,
+        tx: &impl MachineOutput,
+        provider: &mut impl ChainProvider<C>,
+    ) -> Transition<Self> {
+        let msg = match provider.find_intersect(&self.requested_points)? {
+            (Some(point), tip) => Message::<C>::IntersectFound(point, tip),
+            (None, tip) => Message::<C>::IntersectNotFound(tip),
+        };
+
+        debug!("replying to find intersect: {:?}", msg);
+        tx.send_msg(&msg)?;
+
+        Ok(Self {
+            state: State::Idle,
+            requested_points: Vec::new(),
+            ..self
+        })
+    }
+
+    fn send_next_update(
+        self,
+        tx: &impl MachineOutput,
+        provider: &mut impl ChainProvider<C>,
+    ) -> Transition<Self> {
+        match provider.request_next()? {
+            Some(update) => self.send_update(tx, update),
+            None => {
+                debug!("client reached the tip, asking it to await");
+                tx.send_msg(&Message::<C>::AwaitReply)?;
+
+                Ok(Self {
+                    state: State::MustReply,
+                    ..self
+                })
+            }
+        }
+    }
+
+    fn send_awaited_update(
+        self,
+        tx: &impl MachineOutput,
+        provider: &mut impl ChainProvider<C>,
+    ) -> Transition<Self> {
+        let update = provider.await_next()?;
+        self.send_update(tx, update)
+    }
+
+    fn on_find_intersect(self, points: Vec<Point>) -> Transition<Self> {
+        debug!("intersect requested for points: {:?}", points);
+
+        Ok(Self {
+            state: State::Intersect,
+            requested_points: points,
+            ..self
+        })
+    }
+
+    fn on_request_next(self) -> Transition<Self> {
+        Ok(Self {
+            state: State::CanAwait,
+            ..self
+        })
+    }
+
+    fn on_done(self) -> Transition<Self> {
+        debug!("client is done");
+
+        Ok(Self {
+            state: State::Done,
+            ..self
+        })
+    }
+}
+
+impl<C, P> Responder<P> for Producer<C>
+where
+    C: EncodePayload + DecodePayload + Debug + 'static,
+    P: ChainProvider<C>,
+{
+    type Message = Message<C>;
+
+    fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    fn has_agency(&self) -> bool {
+        match self.state {
+            State::Idle => false,
+            State::CanAwait => true,
+            State::MustReply => true,
+            State::Intersect => true,
+            State::Done => false,
+        }
+    }
+
+    fn send_next(self, tx: &impl MachineOutput, provider: &mut P) -> Transition<Self> {
+        match self.state {
+            State::Intersect => self.send_intersect(tx, provider),
+            State::CanAwait => self.send_next_update(tx, provider),
+            State::MustReply => self.send_awaited_update(tx, provider),
+            _ => panic!("I don't have agency, don't know what to do"),
+        }
+    }
+
+    fn receive_next(self, msg: Self::Message) -> Transition<Self> {
+        match (&self.state, msg) {
+            (State::Idle, Message::FindIntersect(points)) => self.on_find_intersect(points),
+            (State::Idle, Message::RequestNext) => self.on_request_next(),
+            (State::Idle, Message::Done) => self.on_done(),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use std::{
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use pallas_chainsync::{
+    BlockLike, ChainProvider, ChainUpdate, Consumer, Message, NoopObserver, Observer,
+    PipelinedConsumer, Producer, State, Tip, TipFinder,
+};
+use pallas_machines::{
+    primitives::Point,
+    run_agent, run_pipelined, run_responder,
+    testing::{ScriptError, ScriptedPeer},
+    to_payload, CodecError, DecodePayload, Driver, EncodePayload, Exchange, MachineError,
+    MachineOutput, PayloadDecoder, PayloadEncoder, StateTransition, TransitionObserver,
+};
+use pallas_multiplexer::{Channel, MemoryBearer, Multiplexer, MuxError, Role};
+
+fn setup_muxers() -> (Multiplexer, Multiplexer) {
+    let (client_bearer, server_bearer) = MemoryBearer::pair();
+
+    let client = Multiplexer::setup(client_bearer, Role::Initiator, &[2]).unwrap();
+    let server = Multiplexer::setup(server_bearer, Role::Responder, &[2]).unwrap();
+
+    (client, server)
+}
+
+/// A dummy block that carries its own point
+#[derive(Debug)]
+struct Content(u64, Vec<u8>);
+
+impl EncodePayload for Content {
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
+        e.array(2)?.u64(self.0)?.bytes(&self.1)?;
+        Ok(())
+    }
+}
+
+impl DecodePayload for Content {
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
+        d.array()?;
+        let slot = d.u64()?;
+        let hash = d.bytes()?;
+        Ok(Content(slot, Vec::from(hash)))
+    }
+}
+
+impl BlockLike for Content {
+    fn block_point(&self) -> Result<Point, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Point::Specific(self.0, self.1.as_slice().try_into()?))
+    }
+}
+
+#[derive(Debug)]
+struct SlotReporter(Sender<u64>);
+
+impl Observer<Content> for SlotReporter {
+    fn on_block(
+        &self,
+        _cursor: &Option<Point>,
+        content: &Content,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.0.send(content.0)?;
+        Ok(())
+    }
+}
+
+fn tip() -> Tip {
+    Tip(Point::Specific(100, [0xff; 32]), 50)
+}
+
+#[test]
+fn tip_finder_reports_remote_tip() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(2).unwrap();
+    let Channel(server_tx, server_rx) = server_muxer.use_channel(2).unwrap();
+
+    let point = Point::Specific(10, [0xaa; 32]);
+    let request = Message::<Content>::FindIntersect(vec![point.clone()]);
+    let request = to_payload(&request).unwrap();
+
+    let client =
+        thread::spawn(move || run_agent(TipFinder::initial(point), &mut client_channel).unwrap());
+
+    assert_eq!(server_rx.recv().unwrap().unwrap(), request);
+    server_tx
+        .send_msg(&Message::<Content>::IntersectNotFound(tip()))
+        .unwrap();
+
+    let client = client.join().unwrap();
+    let Tip(point, block) = client.output.unwrap();
+    assert_eq!((point.slot_or_default(), block), (100, 50));
+}
+
+#[test]
+fn consumer_follows_the_chain() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(2).unwrap();
+    let Channel(server_tx, server_rx) = server_muxer.use_channel(2).unwrap();
+
+    let (slots_tx, slots_rx) = mpsc::channel();
+    let known_points = vec![Point::Specific(10, [0xaa; 32])];
+
+    let request_next = to_payload(&Message::<Content>::RequestNext).unwrap();
+    let find_intersect = Message::<Content>::FindIntersect(known_points.clone());
+    let find_intersect = to_payload(&find_intersect).unwrap();
+
+    let client = thread::spawn(move || {
+        let agent = Consumer::<Content, _>::initial(known_points, SlotReporter(slots_tx));
+        // agent errors can't leave the thread, keep the mux failure only
+        run_agent(agent, &mut client_channel)
+            .map(|_| ())
+            .map_err(|err| match err {
+                MachineError::Mux(reason) => Some(reason),
+                _ => None,
+            })
+    });
+
+    assert_eq!(server_rx.recv().unwrap().unwrap(), find_intersect);
+    server_tx
+        .send_msg(&Message::<Content>::IntersectFound(
+            Point::Specific(10, [0xaa; 32]),
+            tip(),
+        ))
+        .unwrap();
+
+    for slot in [11, 12] {
+        assert_eq!(server_rx.recv().unwrap().unwrap(), request_next);
+        let block = Content(slot, vec![slot as u8; 32]);
+        server_tx
+            .send_msg(&Message::RollForward(block, tip()))
+            .unwrap();
+        assert_eq!(slots_rx.recv().unwrap(), slot);
+    }
+
+    assert_eq!(server_rx.recv().unwrap().unwrap(), request_next);
+    server_tx.send_msg(&Message::<Content>::AwaitReply).unwrap();
+
+    // the consumer never finishes on its own, closing the connection stops it
+    server_muxer.shutdown().unwrap();
+    assert!(matches!(client.join().unwrap(), Err(Some(MuxError::Eof))));
+}
+
+#[test]
+fn unexpected_message_is_invalid_for_state() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(2).unwrap();
+    let Channel(server_tx, server_rx) = server_muxer.use_channel(2).unwrap();
+
+    let server = thread::spawn(move || {
+        server_rx.recv().unwrap().unwrap();
+        server_tx.send_msg(&Message::<Content>::AwaitReply).unwrap();
+    });
+
+    let agent = TipFinder::initial(Point::Specific(10, [0xaa; 32]));
+    let result = run_agent(agent, &mut client_channel);
+    server.join().unwrap();
+
+    assert!(matches!(
+        result,
+        Err(MachineError::InvalidMsgForState { .. })
+    ));
+}
+
+/// A chain of dummy blocks, with a new block showing up once it's awaited
+struct FixedChain {
+    blocks: Vec<u64>,
+    cursor: usize,
+    awaited: Option<u64>,
+}
+
+impl ChainProvider<Content> for FixedChain {
+    fn find_intersect(
+        &mut self,
+        points: &[Point],
+    ) -> Result<(Option<Point>, Tip), Box<dyn std::error::Error + Send + Sync>> {
+        let found = points.iter().find_map(|p| {
+            self.blocks
+                .iter()
+                .position(|slot| *slot == p.slot_or_default())
+        });
+
+        match found {
+            Some(index) => {
+                self.cursor = index + 1;
+                let point = Point::Specific(self.blocks[index], [self.blocks[index] as u8; 32]);
+                Ok((Some(point), tip()))
+            }
+            None => Ok((None, tip())),
+        }
+    }
+
+    fn request_next(
+        &mut self,
+    ) -> Result<Option<ChainUpdate<Content>>, Box<dyn std::error::Error + Send + Sync>> {
+        let next = self.blocks.get(self.cursor).map(|slot| {
+            let block = Content(*slot, vec![*slot as u8; 32]);
+            ChainUpdate::RollForward(block, tip())
+        });
+
+        self.cursor += 1;
+
+        Ok(next)
+    }
+
+    fn await_next(
+        &mut self,
+    ) -> Result<ChainUpdate<Content>, Box<dyn std::error::Error + Send + Sync>> {
+        let slot = self.awaited.take().ok_or("no more blocks")?;
+        let block = Content(slot, vec![slot as u8; 32]);
+
+        Ok(ChainUpdate::RollForward(block, tip()))
+    }
+}
+
+#[test]
+fn producer_serves_the_chain_to_a_consumer() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(2).unwrap();
+    let mut server_channel = server_muxer.use_channel(2).unwrap();
+
+    let server = thread::spawn(move || {
+        let mut chain = FixedChain {
+            blocks: vec![10, 11, 12],
+            cursor: 0,
+            awaited: Some(13),
+        };
+
+        // agent errors can't leave the thread, keep the description only
+        run_responder(Producer::initial(), &mut chain, &mut server_channel)
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    });
+
+    let (slots_tx, slots_rx) = mpsc::channel();
+    let known_points = vec![
+        Point::Specific(99, [0x99; 32]),
+        Point::Specific(10, [0xaa; 32]),
+    ];
+
+    thread::spawn(move || {
+        let agent = Consumer::<Content, _>::initial(known_points, SlotReporter(slots_tx));
+        run_agent(agent, &mut client_channel).ok();
+    });
+
+    let slots: Vec<_> = slots_rx.iter().take(3).collect();
+    assert_eq!(slots, vec![11, 12, 13]);
+
+    // the client waits for a 4th block that the chain can't provide
+    assert_eq!(server.join().unwrap(), Err("no more blocks".to_string()));
+}
+
+#[test]
+fn pipelined_consumer_requests_ahead_of_replies() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(2).unwrap();
+    let Channel(server_tx, server_rx) = server_muxer.use_channel(2).unwrap();
+
+    let (slots_tx, slots_rx) = mpsc::channel();
+    let known_points = vec![Point::Specific(10, [0xaa; 32])];
+
+    thread::spawn(move || {
+        let agent = PipelinedConsumer::<Content, _>::initial(known_points, SlotReporter(slots_tx));
+        run_pipelined(agent, 3, &mut client_channel).ok();
+    });
+
+    // the intersection isn't pipelined
+    server_rx.recv().unwrap().unwrap();
+    server_tx
+        .send_msg(&Message::<Content>::IntersectFound(
+            Point::Specific(10, [0xaa; 32]),
+            tip(),
+        ))
+        .unwrap();
+
+    // three requests arrive before any of them is answered
+    let request_next = to_payload(&Message::<Content>::RequestNext).unwrap();
+    let mut pending = Vec::new();
+
+    while pending.len() < request_next.len() * 3 {
+        let payload = server_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        pending.extend(payload.unwrap());
+    }
+
+    assert_eq!(pending, request_next.repeat(3));
+
+    server_tx.send_msg(&Message::<Content>::AwaitReply).unwrap();
+
+    for slot in [11, 12, 13] {
+        let block = Content(slot, vec![slot as u8; 32]);
+        server_tx
+            .send_msg(&Message::RollForward(block, tip()))
+            .unwrap();
+    }
+
+    let slots: Vec<_> = slots_rx.iter().take(3).collect();
+    assert_eq!(slots, vec![11, 12, 13]);
+
+    // each answered request makes room for a new one
+    let payload = server_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(payload.unwrap().starts_with(&request_next));
+}
+
+#[test]
+fn scripted_consumer_rolls_forward_and_backward() {
+    let known_points = vec![Point::Specific(10, [0xaa; 32])];
+    let agent = Consumer::<Content, _>::initial(known_points.clone(), NoopObserver {});
+
+    let agent = ScriptedPeer::new()
+        .expects(&Message::<Content>::FindIntersect(known_points))
+        .sends(&Message::<Content>::IntersectFound(
+            Point::Specific(10, [0xaa; 32]),
+            tip(),
+        ))
+        .expects(&Message::<Content>::RequestNext)
+        .sends(&Message::RollForward(Content(11, vec![0x11; 32]), tip()))
+        .expects(&Message::<Content>::RequestNext)
+        .sends(&Message::<Content>::AwaitReply)
+        .sends(&Message::<Content>::RollBackward(
+            Point::Specific(10, [0xaa; 32]),
+            tip(),
+        ))
+        .run(agent)
+        .unwrap();
+
+    assert_eq!(agent.state, State::Idle);
+    assert!(matches!(agent.cursor, Some(Point::Specific(10, _))));
+}
+
+#[test]
+fn scripted_consumer_rejects_intersection_while_awaiting() {
+    let known_points = vec![Point::Specific(10, [0xaa; 32])];
+    let agent = Consumer::<Content, _>::initial(known_points.clone(), NoopObserver {});
+
+    let result = ScriptedPeer::new()
+        .expects(&Message::<Content>::FindIntersect(known_points))
+        .sends(&Message::<Content>::IntersectFound(
+            Point::Specific(10, [0xaa; 32]),
+            tip(),
+        ))
+        .expects(&Message::<Content>::RequestNext)
+        .sends(&Message::<Content>::IntersectNotFound(tip()))
+        .run(agent);
+
+    assert!(matches!(
+        result,
+        Err(ScriptError::Agent(MachineError::InvalidMsgForState { .. }))
+    ));
+}
+
+#[test]
+fn scripted_tip_finder_is_done_after_intersection() {
+    let point = Point::Specific(10, [0xaa; 32]);
+
+    let agent = ScriptedPeer::new()
+        .expects(&Message::<Content>::FindIntersect(vec![point.clone()]))
+        .sends(&Message::<Content>::IntersectFound(point.clone(), tip()))
+        .run(TipFinder::initial(point))
+        .unwrap();
+
+    assert_eq!(agent.state, State::Done);
+    assert!(matches!(
+        agent.output,
+        Some(Tip(Point::Specific(100, _), 50))
+    ));
+}
+
+#[derive(Default)]
+struct Timeline(Mutex<Vec<StateTransition>>);
+
+impl TransitionObserver for Timeline {
+    fn on_transition(&self, transition: &StateTransition) {
+        self.0.lock().unwrap().push(transition.clone());
+    }
+}
+
+#[test]
+fn tip_finder_transitions_can_be_observed() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(2).unwrap();
+    let Channel(server_tx, server_rx) = server_muxer.use_channel(2).unwrap();
+
+    let timeline = Arc::new(Timeline::default());
+    let driver = Driver::new().with_observer(timeline.clone());
+
+    let point = Point::Specific(10, [0xaa; 32]);
+    let client = thread::spawn(move || {
+        driver
+            .run(TipFinder::initial(point), &mut client_channel)
+            .unwrap()
+    });
+
+    server_rx.recv().unwrap().unwrap();
+    server_tx
+        .send_msg(&Message::<Content>::IntersectNotFound(tip()))
+        .unwrap();
+    client.join().unwrap();
+
+    let transitions = timeline.0.lock().unwrap();
+    let states: Vec<_> = transitions
+        .iter()
+        .map(|t| (t.from.as_str(), t.to.as_str()))
+        .collect();
+    assert_eq!(states, vec![("Idle", "Intersect"), ("Intersect", "Done")]);
+
+    assert!(
+        matches!(&transitions[0].exchange, Exchange::Sent(msgs) if msgs[0].starts_with("[4, "))
+    );
+    assert!(matches!(
+        &transitions[1].exchange,
+        Exchange::Received(msg) if msg.starts_with("IntersectNotFound")
+    ));
+}
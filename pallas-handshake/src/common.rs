@@ -1,9 +1,11 @@
 use itertools::Itertools;
-use pallas_machines::{CodecError, DecodePayload, EncodePayload, PayloadEncoder};
-use std::{collections::HashMap, fmt::Debug};
-
-pub const TESTNET_MAGIC: u64 = 1097911063;
-pub const MAINNET_MAGIC: u64 = 764824073;
+use pallas_machines::{
+    CodecError, DecodePayload, EncodePayload, MachineError, PayloadDecoder, PayloadEncoder,
+};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display},
+};
 
 #[derive(Debug, Clone)]
 pub struct VersionTable<T>
@@ -17,7 +19,7 @@ impl<T> EncodePayload for VersionTable<T>
 where
     T: Debug + Clone + EncodePayload + DecodePayload,
 {
-    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), Box<dyn std::error::Error>> {
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
         e.map(self.values.len() as u64)?;
 
         for key in self.values.keys().sorted() {
@@ -29,75 +31,134 @@ where
     }
 }
 
+impl<T> DecodePayload for VersionTable<T>
+where
+    T: Debug + Clone + EncodePayload + DecodePayload,
+{
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
+        let len = d
+            .map()?
+            .ok_or(CodecError::UnexpectedCbor("expecting definite-length map"))?;
+
+        let mut values = HashMap::new();
+
+        for _ in 0..len {
+            let key = d.u64()?;
+            let value = T::decode_payload(d)?;
+            values.insert(key, value);
+        }
+
+        Ok(VersionTable { values })
+    }
+}
+
+impl<T> VersionTable<T>
+where
+    T: Debug + Clone + EncodePayload + DecodePayload,
+{
+    /// Picks the highest version of the proposal that's also in this table,
+    /// letting the policy decide if it can be used with the proposed data
+    pub(crate) fn negotiate(
+        &self,
+        proposal: &VersionTable<T>,
+        policy: &impl AcceptancePolicy<T>,
+    ) -> Result<(VersionNumber, T), RefuseReason> {
+        match self.highest_common(proposal) {
+            Some(version) => policy
+                .accept(version, &self.values[&version], &proposal.values[&version])
+                .map(|data| (version, data))
+                .map_err(|reason| RefuseReason::Refused(version, reason)),
+            None => {
+                let supported = self.values.keys().copied().sorted().collect();
+                Err(RefuseReason::VersionMismatch(supported))
+            }
+        }
+    }
+}
+
+impl<T> VersionTable<T>
+where
+    T: Debug + Clone + EncodePayload + DecodePayload,
+{
+    /// The highest version of the proposal that's also in this table
+    pub(crate) fn highest_common(&self, proposal: &VersionTable<T>) -> Option<VersionNumber> {
+        proposal
+            .values
+            .keys()
+            .filter(|version| self.values.contains_key(version))
+            .max()
+            .copied()
+    }
+}
+
+/// Decides if a handshake server accepts a version with the data proposed by
+/// the client
+pub trait AcceptancePolicy<T> {
+    /// The data to accept the version with, or the reason to refuse it
+    fn accept(&self, version: VersionNumber, supported: &T, proposed: &T) -> Result<T, String>;
+}
+
+/// The eras of the Cardano chain, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Era {
+    Byron,
+    Shelley,
+    Allegra,
+    Mary,
+    Alonzo,
+    Babbage,
+    Conway,
+}
+
 pub type NetworkMagic = u64;
 
 pub type VersionNumber = u64;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, EncodePayload, DecodePayload)]
 pub enum RefuseReason {
+    #[label(0)]
     VersionMismatch(Vec<VersionNumber>),
+    #[label(1)]
     HandshakeDecodeError(VersionNumber, String),
+    #[label(2)]
     Refused(VersionNumber, String),
 }
 
-impl EncodePayload for RefuseReason {
-    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), Box<dyn std::error::Error>> {
-        match self {
-            RefuseReason::VersionMismatch(versions) => {
-                e.array(2)?;
-                e.u16(0)?;
-                e.array(versions.len() as u64)?;
-                for v in versions.iter() {
-                    e.u64(*v)?;
-                }
-
-                Ok(())
-            }
-            RefuseReason::HandshakeDecodeError(version, msg) => {
-                e.array(3)?;
-                e.u16(1)?;
-                e.u64(*version)?;
-                e.str(msg)?;
-
-                Ok(())
-            }
-            RefuseReason::Refused(version, msg) => {
-                e.array(3)?;
-                e.u16(2)?;
-                e.u64(*version)?;
-                e.str(msg)?;
+/// The ways querying the versions of a peer can fail
+#[derive(Debug)]
+pub enum QueryError {
+    /// The handshake couldn't be completed
+    Machine(MachineError),
+    /// The peer refused the proposal, eg: because it doesn't know any of the
+    /// versions that can be queried
+    Refused(RefuseReason),
+    /// The peer accepted a version instead of replying to the query
+    Accepted(VersionNumber),
+}
 
-                Ok(())
+impl Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryError::Machine(err) => write!(f, "handshake failed: {}", err),
+            QueryError::Refused(reason) => write!(f, "query was refused: {:?}", reason),
+            QueryError::Accepted(version) => {
+                write!(f, "peer accepted version {} instead of replying", version)
             }
         }
     }
 }
 
-impl DecodePayload for RefuseReason {
-    fn decode_payload(
-        d: &mut pallas_machines::PayloadDecoder,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        d.array()?;
-
-        match d.u16()? {
-            0 => {
-                let versions = d.array_iter::<u64>()?;
-                let versions = versions.try_collect()?;
-                Ok(RefuseReason::VersionMismatch(versions))
-            }
-            1 => {
-                let version = d.u64()?;
-                let msg = d.str()?;
-
-                Ok(RefuseReason::HandshakeDecodeError(version, msg.to_string()))
-            }
-            2 => {
-                let version = d.u64()?;
-                let msg = d.str()?;
-
-                Ok(RefuseReason::Refused(version, msg.to_string()))
-            }
-            x => Err(Box::new(CodecError::BadLabel(x))),
+impl std::error::Error for QueryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            QueryError::Machine(err) => Some(err),
+            _ => None,
         }
     }
 }
+
+impl From<MachineError> for QueryError {
+    fn from(err: MachineError) -> Self {
+        QueryError::Machine(err)
+    }
+}
@@ -3,4 +3,5 @@ mod common;
 pub mod n2c;
 pub mod n2n;
 
-pub use common::{MAINNET_MAGIC, TESTNET_MAGIC};
+pub use common::{AcceptancePolicy, Era, QueryError, RefuseReason};
+pub use pallas_network_params::{Network, MAINNET_MAGIC, TESTNET_MAGIC};
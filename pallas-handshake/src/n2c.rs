@@ -1,11 +1,16 @@
 use core::panic;
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::RangeBounds, time::Duration};
 
+use log::debug;
+use minicbor::data::Type;
 use pallas_machines::{
-    Agent, CodecError, DecodePayload, EncodePayload, MachineOutput, PayloadDecoder, PayloadEncoder,
+    run_agent, Agent, CodecError, DecodePayload, EncodePayload, MachineError, MachineOutput,
+    PayloadDecoder, PayloadEncoder, Transition, SHORT_WAIT,
 };
+use pallas_multiplexer::Channel;
+use pallas_network_params::Network;
 
-use crate::common::{NetworkMagic, RefuseReason, VersionNumber};
+use crate::common::{AcceptancePolicy, Era, NetworkMagic, QueryError, RefuseReason, VersionNumber};
 
 pub type VersionTable = crate::common::VersionTable<VersionData>;
 
@@ -19,104 +24,280 @@ const PROTOCOL_V7: u64 = 32775;
 const PROTOCOL_V8: u64 = 32776;
 const PROTOCOL_V9: u64 = 32777;
 const PROTOCOL_V10: u64 = 32778;
+const PROTOCOL_V11: u64 = 32779;
+const PROTOCOL_V12: u64 = 32780;
+const PROTOCOL_V13: u64 = 32781;
+const PROTOCOL_V14: u64 = 32782;
+const PROTOCOL_V15: u64 = 32783;
+const PROTOCOL_V16: u64 = 32784;
 
-impl VersionTable {
-    pub fn v1_and_above(network_magic: u64) -> VersionTable {
-        let values = vec![
-            (PROTOCOL_V1, VersionData(network_magic)),
-            (PROTOCOL_V2, VersionData(network_magic)),
-            (PROTOCOL_V3, VersionData(network_magic)),
-            (PROTOCOL_V4, VersionData(network_magic)),
-            (PROTOCOL_V5, VersionData(network_magic)),
-            (PROTOCOL_V6, VersionData(network_magic)),
-            (PROTOCOL_V7, VersionData(network_magic)),
-            (PROTOCOL_V8, VersionData(network_magic)),
-            (PROTOCOL_V9, VersionData(network_magic)),
-            (PROTOCOL_V10, VersionData(network_magic)),
-        ]
-        .into_iter()
-        .collect::<HashMap<u64, VersionData>>();
+/// The versions of the node-to-client protocols known by this crate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum N2CVersion {
+    V1,
+    /// Adds the local-state-query mini-protocol
+    V2,
+    /// Enables the Shelley era
+    V3,
+    /// Adds more local-state queries
+    V4,
+    /// Enables the Allegra era
+    V5,
+    /// Enables the Mary era
+    V6,
+    /// Adds a local-state query
+    V7,
+    /// Allows to acquire the ledger state at the tip, without a point
+    V8,
+    /// Enables the Alonzo era
+    V9,
+    /// Adds the `GetChainBlockNo` and `GetChainPoint` queries
+    V10,
+    /// Adds the `GetRewardInfoPools` query
+    V11,
+    /// Adds the local-tx-monitor mini-protocol
+    V12,
+    /// Enables the Babbage era
+    V13,
+    /// Adds the pool distribution, pool state and snapshot queries
+    V14,
+    /// Enables the Conway era, adds the query flag to the version data
+    V15,
+    /// Adds more Conway local-state queries
+    V16,
+}
 
-        VersionTable { values }
+impl N2CVersion {
+    pub const ALL: [N2CVersion; 16] = [
+        N2CVersion::V1,
+        N2CVersion::V2,
+        N2CVersion::V3,
+        N2CVersion::V4,
+        N2CVersion::V5,
+        N2CVersion::V6,
+        N2CVersion::V7,
+        N2CVersion::V8,
+        N2CVersion::V9,
+        N2CVersion::V10,
+        N2CVersion::V11,
+        N2CVersion::V12,
+        N2CVersion::V13,
+        N2CVersion::V14,
+        N2CVersion::V15,
+        N2CVersion::V16,
+    ];
+
+    /// The number of the version on the wire
+    pub fn number(self) -> VersionNumber {
+        match self {
+            N2CVersion::V1 => PROTOCOL_V1,
+            N2CVersion::V2 => PROTOCOL_V2,
+            N2CVersion::V3 => PROTOCOL_V3,
+            N2CVersion::V4 => PROTOCOL_V4,
+            N2CVersion::V5 => PROTOCOL_V5,
+            N2CVersion::V6 => PROTOCOL_V6,
+            N2CVersion::V7 => PROTOCOL_V7,
+            N2CVersion::V8 => PROTOCOL_V8,
+            N2CVersion::V9 => PROTOCOL_V9,
+            N2CVersion::V10 => PROTOCOL_V10,
+            N2CVersion::V11 => PROTOCOL_V11,
+            N2CVersion::V12 => PROTOCOL_V12,
+            N2CVersion::V13 => PROTOCOL_V13,
+            N2CVersion::V14 => PROTOCOL_V14,
+            N2CVersion::V15 => PROTOCOL_V15,
+            N2CVersion::V16 => PROTOCOL_V16,
+        }
     }
 
-    pub fn only_v10(network_magic: u64) -> VersionTable {
-        let values = vec![(PROTOCOL_V10, VersionData(network_magic))]
+    /// The version with the given wire number, `None` if it's unknown
+    pub fn from_number(number: VersionNumber) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|version| version.number() == number)
+    }
+
+    pub fn capabilities(self) -> Capabilities {
+        let latest_era = match self {
+            N2CVersion::V1 | N2CVersion::V2 => Era::Byron,
+            N2CVersion::V3 | N2CVersion::V4 => Era::Shelley,
+            N2CVersion::V5 => Era::Allegra,
+            N2CVersion::V6 | N2CVersion::V7 | N2CVersion::V8 => Era::Mary,
+            N2CVersion::V9 | N2CVersion::V10 | N2CVersion::V11 | N2CVersion::V12 => Era::Alonzo,
+            N2CVersion::V13 | N2CVersion::V14 => Era::Babbage,
+            N2CVersion::V15 | N2CVersion::V16 => Era::Conway,
+        };
+
+        Capabilities {
+            latest_era,
+            local_state_query: self >= N2CVersion::V2,
+            acquire_tip: self >= N2CVersion::V8,
+            chain_point_queries: self >= N2CVersion::V10,
+            version_query: self >= N2CVersion::V15,
+        }
+    }
+}
+
+/// What the mini-protocols can do once a node-to-client version is agreed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The newest era of the blocks exchanged through chain-sync
+    pub latest_era: Era,
+    /// The local-state-query mini-protocol is available
+    pub local_state_query: bool,
+    /// Local-state can be acquired without a point, meaning the tip
+    pub acquire_tip: bool,
+    /// The `GetChainBlockNo` and `GetChainPoint` queries are available
+    pub chain_point_queries: bool,
+    /// The handshake can be used to query the versions of the node, see
+    /// [query_versions]
+    pub version_query: bool,
+}
+
+impl VersionTable {
+    /// A table with the versions within the range, all of them with the same
+    /// data in the shape of each version (eg:
+    /// `VersionTable::from_range(N2CVersion::V8.., MAINNET_MAGIC)`)
+    pub fn from_range(
+        versions: impl RangeBounds<N2CVersion>,
+        data: impl Into<VersionData>,
+    ) -> VersionTable {
+        let data = data.into();
+
+        let values = N2CVersion::ALL
             .into_iter()
+            .filter(|version| versions.contains(version))
+            .map(|version| (version.number(), data.clone().for_version(version)))
             .collect::<HashMap<u64, VersionData>>();
 
         VersionTable { values }
     }
+
+    /// Versions 1 to 10, use [VersionTable::from_range] to propose newer ones
+    pub fn v1_and_above(network: impl Into<NetworkMagic>) -> VersionTable {
+        Self::from_range(N2CVersion::V1..=N2CVersion::V10, network.into())
+    }
+
+    /// Versions 15 and above, the ones that can be used to query the versions
+    /// of the node
+    pub fn v15_and_above(network: impl Into<NetworkMagic>) -> VersionTable {
+        Self::from_range(N2CVersion::V15.., network.into())
+    }
+
+    pub fn only_v10(network: impl Into<NetworkMagic>) -> VersionTable {
+        Self::from_range(N2CVersion::V10..=N2CVersion::V10, network.into())
+    }
 }
 
+/// The data proposed along with a version
+///
+/// Up to version 14 it's just the network magic, from version 15 onwards
+/// it's `[magic, query]`. The shape follows the presence of the query flag,
+/// see [VersionData::for_version].
 #[derive(Debug, Clone)]
-pub struct VersionData(NetworkMagic);
+pub struct VersionData {
+    network_magic: NetworkMagic,
+    query: Option<bool>,
+}
 
-impl EncodePayload for VersionData {
-    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), Box<dyn std::error::Error>> {
-        e.u64(self.0)?;
+impl VersionData {
+    /// The data for a network, given as a [Network] or its magic
+    pub fn new(network: impl Into<NetworkMagic>) -> Self {
+        VersionData {
+            network_magic: network.into(),
+            query: None,
+        }
+    }
 
-        Ok(())
+    /// Asks the node to reply with its supported versions instead of
+    /// accepting one
+    pub fn with_query(self, query: bool) -> Self {
+        Self {
+            query: Some(query),
+            ..self
+        }
     }
-}
 
-impl DecodePayload for VersionData {
-    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, Box<dyn std::error::Error>> {
-        let network_magic = d.u64()?;
+    /// The same data in the shape of the version, without a query unless set
+    /// when the version requires the flag
+    pub fn for_version(self, version: N2CVersion) -> Self {
+        let query = match version.capabilities().version_query {
+            true => Some(self.query.unwrap_or(false)),
+            false => None,
+        };
+
+        Self { query, ..self }
+    }
+
+    pub fn network_magic(&self) -> NetworkMagic {
+        self.network_magic
+    }
 
-        Ok(Self(network_magic))
+    pub fn network(&self) -> Network {
+        Network::from_magic(self.network_magic)
+    }
+
+    pub fn query(&self) -> bool {
+        self.query.unwrap_or(false)
     }
 }
 
-#[derive(Debug)]
-pub enum Message {
-    Propose(VersionTable),
-    Accept(VersionNumber, VersionData),
-    Refuse(RefuseReason),
+impl From<NetworkMagic> for VersionData {
+    fn from(network_magic: NetworkMagic) -> Self {
+        VersionData::new(network_magic)
+    }
 }
 
-impl EncodePayload for Message {
-    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), Box<dyn std::error::Error>> {
-        match self {
-            Message::Propose(version_table) => {
-                e.array(2)?.u16(0)?;
-                version_table.encode_payload(e)?;
-            }
-            Message::Accept(version_number, version_data) => {
-                e.array(3)?.u16(1)?;
-                e.u64(*version_number)?;
-                version_data.encode_payload(e)?;
-            }
-            Message::Refuse(reason) => {
-                e.array(2)?.u16(2)?;
-                reason.encode_payload(e)?;
-            }
+impl From<Network> for VersionData {
+    fn from(network: Network) -> Self {
+        VersionData::new(network)
+    }
+}
+
+impl EncodePayload for VersionData {
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
+        match self.query {
+            None => e.u64(self.network_magic)?,
+            Some(query) => e.array(2)?.u64(self.network_magic)?.bool(query)?,
         };
 
         Ok(())
     }
 }
 
-impl DecodePayload for Message {
-    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, Box<dyn std::error::Error>> {
-        d.array()?;
+impl DecodePayload for VersionData {
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
+        match d.datatype()? {
+            Type::Array => match d.array()? {
+                Some(2) => {
+                    let network_magic = d.u64()?;
+                    let query = d.bool()?;
 
-        match d.u16()? {
-            0 => todo!(),
-            1 => {
-                let version_number = d.u64()?;
-                let version_data = VersionData::decode_payload(d)?;
-                Ok(Message::Accept(version_number, version_data))
-            }
-            2 => {
-                let reason = RefuseReason::decode_payload(d)?;
-                Ok(Message::Refuse(reason))
-            }
-            x => Err(Box::new(CodecError::BadLabel(x))),
+                    Ok(Self {
+                        network_magic,
+                        query: Some(query),
+                    })
+                }
+                _ => Err(CodecError::UnexpectedCbor(
+                    "expecting version data with 2 items",
+                )),
+            },
+            _ => Ok(Self::new(d.u64()?)),
         }
     }
 }
 
+#[derive(Debug, EncodePayload, DecodePayload)]
+pub enum Message {
+    #[label(0)]
+    Propose(VersionTable),
+    #[label(1)]
+    Accept(VersionNumber, VersionData),
+    #[label(2)]
+    Refuse(RefuseReason),
+    #[label(3)]
+    QueryReply(VersionTable),
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum State {
     Propose,
@@ -129,6 +310,23 @@ pub enum Output {
     Pending,
     Accepted(VersionNumber, VersionData),
     Refused(RefuseReason),
+    /// The versions supported by the server, in reply to a query
+    Queried(VersionTable),
+}
+
+impl Output {
+    /// The accepted version, `None` if there's none or if it's unknown
+    pub fn version(&self) -> Option<N2CVersion> {
+        match self {
+            Output::Accepted(number, _) => N2CVersion::from_number(*number),
+            _ => None,
+        }
+    }
+
+    /// The capabilities of the accepted version, if any
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        self.version().map(N2CVersion::capabilities)
+    }
 }
 
 #[derive(Debug)]
@@ -155,6 +353,10 @@ impl Agent for Client {
         self.state == State::Done
     }
 
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
     fn has_agency(&self) -> bool {
         match self.state {
             State::Propose => true,
@@ -163,7 +365,7 @@ impl Agent for Client {
         }
     }
 
-    fn send_next(self, tx: &impl MachineOutput) -> Result<Self, Box<dyn std::error::Error>> {
+    fn send_next(self, tx: &impl MachineOutput) -> Transition<Self> {
         match self.state {
             State::Propose => {
                 tx.send_msg(&Message::Propose(self.version_table.clone()))?;
@@ -177,8 +379,8 @@ impl Agent for Client {
         }
     }
 
-    fn receive_next(self, msg: Self::Message) -> Result<Self, Box<dyn std::error::Error>> {
-        match (self.state, msg) {
+    fn receive_next(self, msg: Self::Message) -> Transition<Self> {
+        match (&self.state, msg) {
             (State::Confirm, Message::Accept(version, data)) => Ok(Self {
                 state: State::Done,
                 output: Output::Accepted(version, data),
@@ -189,7 +391,191 @@ impl Agent for Client {
                 output: Output::Refused(reason),
                 ..self
             }),
-            _ => panic!("Current state does't expect to receive a message"),
+            (State::Confirm, Message::QueryReply(table)) => Ok(Self {
+                state: State::Done,
+                output: Output::Queried(table),
+                ..self
+            }),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
         }
     }
+
+    fn state_timeout(&self) -> Option<Duration> {
+        match self.state {
+            State::Confirm => Some(SHORT_WAIT),
+            _ => None,
+        }
+    }
+}
+
+/// Accepts the clients of the same network
+#[derive(Debug, Default)]
+pub struct SameNetwork;
+
+impl AcceptancePolicy<VersionData> for SameNetwork {
+    fn accept(
+        &self,
+        _version: VersionNumber,
+        supported: &VersionData,
+        proposed: &VersionData,
+    ) -> Result<VersionData, String> {
+        if supported.network_magic != proposed.network_magic {
+            return Err(format!(
+                "network magic mismatch, expecting {} but got {}",
+                supported.network_magic, proposed.network_magic
+            ));
+        }
+
+        Ok(supported.clone())
+    }
+}
+
+/// The responder side of the handshake, which picks the highest version
+/// proposed by the client that's also in its own table
+///
+/// If the client sets the query flag in the data of that version, the server
+/// replies with its own table instead.
+#[derive(Debug)]
+pub struct Server<P = SameNetwork> {
+    pub state: State,
+    pub output: Output,
+    pub version_table: VersionTable,
+    pub proposal: Option<VersionTable>,
+    policy: P,
+}
+
+impl Server<SameNetwork> {
+    pub fn initial(version_table: VersionTable) -> Self {
+        Self::with_policy(version_table, SameNetwork)
+    }
+}
+
+impl<P> Server<P>
+where
+    P: AcceptancePolicy<VersionData>,
+{
+    pub fn with_policy(version_table: VersionTable, policy: P) -> Self {
+        Server {
+            state: State::Propose,
+            output: Output::Pending,
+            version_table,
+            proposal: None,
+            policy,
+        }
+    }
+
+    fn send_reply(self, tx: &impl MachineOutput) -> Transition<Self> {
+        let proposal = self
+            .proposal
+            .as_ref()
+            .expect("proposal is received before confirming");
+
+        let queried = self
+            .version_table
+            .highest_common(proposal)
+            .map(|version| proposal.values[&version].query())
+            .unwrap_or(false);
+
+        if queried {
+            debug!("replying to version query");
+            tx.send_msg(&Message::QueryReply(self.version_table.clone()))?;
+
+            return Ok(Self {
+                state: State::Done,
+                output: Output::Queried(self.version_table.clone()),
+                ..self
+            });
+        }
+
+        let output = match self.version_table.negotiate(proposal, &self.policy) {
+            Ok((version, data)) => {
+                debug!("accepting version {}", version);
+                tx.send_msg(&Message::Accept(version, data.clone()))?;
+                Output::Accepted(version, data)
+            }
+            Err(reason) => {
+                debug!("refusing handshake: {:?}", reason);
+                tx.send_msg(&Message::Refuse(reason.clone()))?;
+                Output::Refused(reason)
+            }
+        };
+
+        Ok(Self {
+            state: State::Done,
+            output,
+            ..self
+        })
+    }
+}
+
+impl<P> Agent for Server<P>
+where
+    P: AcceptancePolicy<VersionData>,
+{
+    type Message = Message;
+
+    fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    fn has_agency(&self) -> bool {
+        match self.state {
+            State::Propose => false,
+            State::Confirm => true,
+            State::Done => false,
+        }
+    }
+
+    fn send_next(self, tx: &impl MachineOutput) -> Transition<Self> {
+        match self.state {
+            State::Confirm => self.send_reply(tx),
+            _ => panic!("I don't have agency, nothing to send"),
+        }
+    }
+
+    fn receive_next(self, msg: Self::Message) -> Transition<Self> {
+        match (&self.state, msg) {
+            (State::Propose, Message::Propose(proposal)) => Ok(Self {
+                state: State::Confirm,
+                proposal: Some(proposal),
+                ..self
+            }),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
+        }
+    }
+
+    fn state_timeout(&self) -> Option<Duration> {
+        match self.state {
+            State::Propose => Some(SHORT_WAIT),
+            _ => None,
+        }
+    }
+}
+
+/// Asks the node for the versions it supports, through a handshake with the
+/// query flag set
+///
+/// Only versions 15 and above can be queried, so older nodes refuse the
+/// proposal with the versions they know (see [QueryError::Refused]). The
+/// node doesn't start a session after replying, the connection should be
+/// dropped afterwards.
+pub fn query_versions(
+    channel: &mut Channel,
+    network: impl Into<NetworkMagic>,
+) -> Result<VersionTable, QueryError> {
+    let data = VersionData::new(network).with_query(true);
+    let versions = VersionTable::from_range(N2CVersion::V15.., data);
+
+    let client = run_agent(Client::initial(versions), channel)?;
+
+    match client.output {
+        Output::Queried(table) => Ok(table),
+        Output::Refused(reason) => Err(QueryError::Refused(reason)),
+        Output::Accepted(version, _) => Err(QueryError::Accepted(version)),
+        Output::Pending => unreachable!("handshake is done"),
+    }
 }
@@ -1,11 +1,15 @@
 use core::panic;
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::RangeBounds, time::Duration};
 
+use log::debug;
 use pallas_machines::{
-    Agent, CodecError, DecodePayload, EncodePayload, MachineOutput, PayloadDecoder, PayloadEncoder,
+    run_agent, Agent, CodecError, DecodePayload, EncodePayload, MachineError, MachineOutput,
+    PayloadDecoder, PayloadEncoder, Transition, SHORT_WAIT,
 };
+use pallas_multiplexer::Channel;
+use pallas_network_params::Network;
 
-use crate::common::{RefuseReason, VersionNumber};
+use crate::common::{AcceptancePolicy, Era, NetworkMagic, QueryError, RefuseReason, VersionNumber};
 
 pub type VersionTable = crate::common::VersionTable<VersionData>;
 
@@ -13,118 +17,309 @@ const PROTOCOL_V4: u64 = 4;
 const PROTOCOL_V5: u64 = 5;
 const PROTOCOL_V6: u64 = 6;
 const PROTOCOL_V7: u64 = 7;
+const PROTOCOL_V8: u64 = 8;
+const PROTOCOL_V9: u64 = 9;
+const PROTOCOL_V10: u64 = 10;
+const PROTOCOL_V11: u64 = 11;
+const PROTOCOL_V12: u64 = 12;
+const PROTOCOL_V13: u64 = 13;
+const PROTOCOL_V14: u64 = 14;
+
+/// The versions of the node-to-node protocols known by this crate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum N2NVersion {
+    /// Adds the diffusion mode to the version data
+    V4,
+    /// Enables the Allegra era
+    V5,
+    /// Enables the Mary era, replaces tx-submission with tx-submission2
+    V6,
+    /// Enables the Alonzo era, changes the keep-alive codec
+    V7,
+    /// Enables block-fetch pipelining of headers
+    V8,
+    /// Enables the Babbage era
+    V9,
+    /// Enables full-duplex connections
+    V10,
+    /// Adds peer sharing and the query flag to the version data
+    V11,
+    /// Enables the Conway era
+    V12,
+    /// Drops the private peer sharing mode
+    V13,
+    /// Mandatory since the Plomin hard fork
+    V14,
+}
+
+impl N2NVersion {
+    pub const ALL: [N2NVersion; 11] = [
+        N2NVersion::V4,
+        N2NVersion::V5,
+        N2NVersion::V6,
+        N2NVersion::V7,
+        N2NVersion::V8,
+        N2NVersion::V9,
+        N2NVersion::V10,
+        N2NVersion::V11,
+        N2NVersion::V12,
+        N2NVersion::V13,
+        N2NVersion::V14,
+    ];
+
+    /// The number of the version on the wire
+    pub fn number(self) -> VersionNumber {
+        match self {
+            N2NVersion::V4 => PROTOCOL_V4,
+            N2NVersion::V5 => PROTOCOL_V5,
+            N2NVersion::V6 => PROTOCOL_V6,
+            N2NVersion::V7 => PROTOCOL_V7,
+            N2NVersion::V8 => PROTOCOL_V8,
+            N2NVersion::V9 => PROTOCOL_V9,
+            N2NVersion::V10 => PROTOCOL_V10,
+            N2NVersion::V11 => PROTOCOL_V11,
+            N2NVersion::V12 => PROTOCOL_V12,
+            N2NVersion::V13 => PROTOCOL_V13,
+            N2NVersion::V14 => PROTOCOL_V14,
+        }
+    }
+
+    /// The version with the given wire number, `None` if it's unknown
+    pub fn from_number(number: VersionNumber) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|version| version.number() == number)
+    }
+
+    pub fn capabilities(self) -> Capabilities {
+        let latest_era = match self {
+            N2NVersion::V4 => Era::Shelley,
+            N2NVersion::V5 => Era::Allegra,
+            N2NVersion::V6 => Era::Mary,
+            N2NVersion::V7 | N2NVersion::V8 => Era::Alonzo,
+            N2NVersion::V9 | N2NVersion::V10 | N2NVersion::V11 => Era::Babbage,
+            N2NVersion::V12 | N2NVersion::V13 | N2NVersion::V14 => Era::Conway,
+        };
+
+        Capabilities {
+            latest_era,
+            tx_submission2: self >= N2NVersion::V6,
+            keep_alive_cookies: self >= N2NVersion::V7,
+            peer_sharing: self >= N2NVersion::V11,
+            version_query: self >= N2NVersion::V11,
+        }
+    }
+}
+
+/// What the mini-protocols can do once a node-to-node version is agreed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The newest era of the blocks and headers exchanged through chain-sync
+    /// and block-fetch
+    pub latest_era: Era,
+    /// Tx-submission starts with a hello message from the client
+    pub tx_submission2: bool,
+    /// Keep-alive messages carry a cookie
+    pub keep_alive_cookies: bool,
+    /// The peer-sharing mini-protocol is available, and the version data
+    /// carries the peer sharing mode
+    pub peer_sharing: bool,
+    /// The handshake can be used to query the versions of the peer, see
+    /// [query_versions]
+    pub version_query: bool,
+}
 
 impl VersionTable {
-    pub fn v4_and_above(network_magic: u64) -> VersionTable {
-        let values = vec![
-            (PROTOCOL_V4, VersionData::new(network_magic, false)),
-            (PROTOCOL_V5, VersionData::new(network_magic, false)),
-            (PROTOCOL_V6, VersionData::new(network_magic, false)),
-            (PROTOCOL_V7, VersionData::new(network_magic, false)),
-        ]
-        .into_iter()
-        .collect::<HashMap<u64, VersionData>>();
+    /// A table with the versions within the range, all of them with the same
+    /// data in the shape of each version (eg:
+    /// `VersionTable::from_range(N2NVersion::V6.., data)`)
+    pub fn from_range(versions: impl RangeBounds<N2NVersion>, data: VersionData) -> VersionTable {
+        let values = N2NVersion::ALL
+            .into_iter()
+            .filter(|version| versions.contains(version))
+            .map(|version| (version.number(), data.clone().for_version(version)))
+            .collect::<HashMap<u64, VersionData>>();
 
         VersionTable { values }
     }
 
-    pub fn v6_and_above(network_magic: u64) -> VersionTable {
-        let values = vec![
-            (PROTOCOL_V6, VersionData::new(network_magic, false)),
-            (PROTOCOL_V7, VersionData::new(network_magic, false)),
-        ]
-        .into_iter()
-        .collect::<HashMap<u64, VersionData>>();
+    /// Versions 4 to 7, use [VersionTable::from_range] to propose newer ones
+    pub fn v4_and_above(network: impl Into<NetworkMagic>) -> VersionTable {
+        Self::from_range(
+            N2NVersion::V4..=N2NVersion::V7,
+            VersionData::new(network, false),
+        )
+    }
 
-        VersionTable { values }
+    /// Versions 6 and 7, use [VersionTable::from_range] to propose newer ones
+    pub fn v6_and_above(network: impl Into<NetworkMagic>) -> VersionTable {
+        Self::from_range(
+            N2NVersion::V6..=N2NVersion::V7,
+            VersionData::new(network, false),
+        )
+    }
+
+    /// Versions 11 and above, the ones with peer sharing and version queries
+    pub fn v11_and_above(network: impl Into<NetworkMagic>) -> VersionTable {
+        Self::from_range(N2NVersion::V11.., VersionData::new(network, false))
     }
 }
 
+/// Whether a node is willing to share the addresses of its peers
+///
+/// Versions 11 and 12 distinguish a private and a public mode on the wire,
+/// both are read as [PeerSharing::Enabled], which is sent as the private one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PeerSharing {
+    Disabled,
+    Enabled,
+}
+
+/// The data proposed along with a version
+///
+/// Up to version 10 it's `[magic, diffusion_mode]`, from version 11 onwards
+/// it's `[magic, diffusion_mode, peer_sharing, query]`. The shape follows
+/// the presence of the peer sharing mode, see [VersionData::for_version].
 #[derive(Debug, Clone)]
 pub struct VersionData {
-    network_magic: u64,
+    network_magic: NetworkMagic,
     initiator_and_responder_diffusion_mode: bool,
+    peer_sharing: Option<PeerSharing>,
+    query: bool,
 }
 
 impl VersionData {
-    pub fn new(network_magic: u64, initiator_and_responder_diffusion_mode: bool) -> Self {
+    /// The data for a network, given as a [Network] or its magic
+    pub fn new(
+        network: impl Into<NetworkMagic>,
+        initiator_and_responder_diffusion_mode: bool,
+    ) -> Self {
         VersionData {
-            network_magic,
+            network_magic: network.into(),
             initiator_and_responder_diffusion_mode,
+            peer_sharing: None,
+            query: false,
+        }
+    }
+
+    pub fn with_peer_sharing(self, peer_sharing: PeerSharing) -> Self {
+        Self {
+            peer_sharing: Some(peer_sharing),
+            ..self
+        }
+    }
+
+    /// Asks the server to reply with its supported versions and close the
+    /// connection instead of accepting one (eg: to probe a relay)
+    pub fn with_query(self, query: bool) -> Self {
+        Self { query, ..self }
+    }
+
+    /// The same data in the shape of the version, peer sharing being
+    /// disabled unless set when the version requires it
+    pub fn for_version(self, version: N2NVersion) -> Self {
+        let peer_sharing = match version.capabilities().peer_sharing {
+            true => Some(self.peer_sharing.unwrap_or(PeerSharing::Disabled)),
+            false => None,
+        };
+
+        Self {
+            peer_sharing,
+            ..self
         }
     }
+
+    pub fn network_magic(&self) -> NetworkMagic {
+        self.network_magic
+    }
+
+    pub fn network(&self) -> Network {
+        Network::from_magic(self.network_magic)
+    }
+
+    pub fn initiator_and_responder_diffusion_mode(&self) -> bool {
+        self.initiator_and_responder_diffusion_mode
+    }
+
+    /// The peer sharing mode, `None` for versions before 11
+    pub fn peer_sharing(&self) -> Option<PeerSharing> {
+        self.peer_sharing
+    }
+
+    pub fn query(&self) -> bool {
+        self.query
+    }
 }
 
 impl EncodePayload for VersionData {
-    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), Box<dyn std::error::Error>> {
-        e.array(2)?
-            .u64(self.network_magic)?
-            .bool(self.initiator_and_responder_diffusion_mode)?;
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
+        match self.peer_sharing {
+            None => {
+                e.array(2)?
+                    .u64(self.network_magic)?
+                    .bool(self.initiator_and_responder_diffusion_mode)?;
+            }
+            Some(peer_sharing) => {
+                let peer_sharing = match peer_sharing {
+                    PeerSharing::Disabled => 0,
+                    PeerSharing::Enabled => 1,
+                };
+
+                e.array(4)?
+                    .u64(self.network_magic)?
+                    .bool(self.initiator_and_responder_diffusion_mode)?
+                    .u8(peer_sharing)?
+                    .bool(self.query)?;
+            }
+        }
 
         Ok(())
     }
 }
 
 impl DecodePayload for VersionData {
-    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, Box<dyn std::error::Error>> {
-        d.array()?;
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
+        let len = d.array()?;
         let network_magic = d.u64()?;
         let initiator_and_responder_diffusion_mode = d.bool()?;
 
+        let (peer_sharing, query) = match len {
+            Some(2) => (None, false),
+            Some(4) => {
+                let peer_sharing = match d.u8()? {
+                    0 => PeerSharing::Disabled,
+                    1 | 2 => PeerSharing::Enabled,
+                    _ => return Err(CodecError::UnexpectedCbor("unknown peer sharing mode")),
+                };
+
+                (Some(peer_sharing), d.bool()?)
+            }
+            _ => {
+                return Err(CodecError::UnexpectedCbor(
+                    "expecting version data with 2 or 4 items",
+                ))
+            }
+        };
+
         Ok(Self {
             network_magic,
             initiator_and_responder_diffusion_mode,
+            peer_sharing,
+            query,
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, EncodePayload, DecodePayload)]
 pub enum Message {
+    #[label(0)]
     Propose(VersionTable),
+    #[label(1)]
     Accept(VersionNumber, VersionData),
+    #[label(2)]
     Refuse(RefuseReason),
-}
-
-impl EncodePayload for Message {
-    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), Box<dyn std::error::Error>> {
-        match self {
-            Message::Propose(version_table) => {
-                e.array(2)?.u16(0)?;
-                version_table.encode_payload(e)?;
-            }
-            Message::Accept(version_number, version_data) => {
-                e.array(3)?.u16(1)?;
-                e.u64(*version_number)?;
-                version_data.encode_payload(e)?;
-            }
-            Message::Refuse(reason) => {
-                e.array(2)?.u16(2)?;
-                reason.encode_payload(e)?;
-            }
-        };
-
-        Ok(())
-    }
-}
-
-impl DecodePayload for Message {
-    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, Box<dyn std::error::Error>> {
-        d.array()?;
-
-        match d.u16()? {
-            0 => todo!(),
-            1 => {
-                let version_number = d.u64()?;
-                let version_data = VersionData::decode_payload(d)?;
-                Ok(Message::Accept(version_number, version_data))
-            }
-            2 => {
-                let reason = RefuseReason::decode_payload(d)?;
-                Ok(Message::Refuse(reason))
-            }
-            x => Err(Box::new(CodecError::BadLabel(x))),
-        }
-    }
+    #[label(3)]
+    QueryReply(VersionTable),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -139,6 +334,23 @@ pub enum Output {
     Pending,
     Accepted(VersionNumber, VersionData),
     Refused(RefuseReason),
+    /// The versions supported by the server, in reply to a query
+    Queried(VersionTable),
+}
+
+impl Output {
+    /// The accepted version, `None` if there's none or if it's unknown
+    pub fn version(&self) -> Option<N2NVersion> {
+        match self {
+            Output::Accepted(number, _) => N2NVersion::from_number(*number),
+            _ => None,
+        }
+    }
+
+    /// The capabilities of the accepted version, if any
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        self.version().map(N2NVersion::capabilities)
+    }
 }
 
 #[derive(Debug)]
@@ -165,6 +377,10 @@ impl Agent for Client {
         self.state == State::Done
     }
 
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
     fn has_agency(&self) -> bool {
         match self.state {
             State::Propose => true,
@@ -173,7 +389,7 @@ impl Agent for Client {
         }
     }
 
-    fn send_next(self, tx: &impl MachineOutput) -> Result<Self, Box<dyn std::error::Error>> {
+    fn send_next(self, tx: &impl MachineOutput) -> Transition<Self> {
         match self.state {
             State::Propose => {
                 tx.send_msg(&Message::Propose(self.version_table.clone()))?;
@@ -187,8 +403,8 @@ impl Agent for Client {
         }
     }
 
-    fn receive_next(self, msg: Self::Message) -> Result<Self, Box<dyn std::error::Error>> {
-        match (self.state, msg) {
+    fn receive_next(self, msg: Self::Message) -> Transition<Self> {
+        match (&self.state, msg) {
             (State::Confirm, Message::Accept(version, data)) => Ok(Self {
                 state: State::Done,
                 output: Output::Accepted(version, data),
@@ -199,7 +415,202 @@ impl Agent for Client {
                 output: Output::Refused(reason),
                 ..self
             }),
-            _ => panic!("Current state does't expect to receive a message"),
+            (State::Confirm, Message::QueryReply(table)) => Ok(Self {
+                state: State::Done,
+                output: Output::Queried(table),
+                ..self
+            }),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
+        }
+    }
+
+    fn state_timeout(&self) -> Option<Duration> {
+        match self.state {
+            State::Confirm => Some(SHORT_WAIT),
+            _ => None,
+        }
+    }
+}
+
+/// Accepts the clients of the same network, in initiator-and-responder mode
+/// and sharing peers only if both sides are willing to
+#[derive(Debug, Default)]
+pub struct SameNetwork;
+
+impl AcceptancePolicy<VersionData> for SameNetwork {
+    fn accept(
+        &self,
+        _version: VersionNumber,
+        supported: &VersionData,
+        proposed: &VersionData,
+    ) -> Result<VersionData, String> {
+        if supported.network_magic != proposed.network_magic {
+            return Err(format!(
+                "network magic mismatch, expecting {} but got {}",
+                supported.network_magic, proposed.network_magic
+            ));
+        }
+
+        Ok(VersionData {
+            network_magic: supported.network_magic,
+            initiator_and_responder_diffusion_mode: supported
+                .initiator_and_responder_diffusion_mode
+                && proposed.initiator_and_responder_diffusion_mode,
+            peer_sharing: supported
+                .peer_sharing
+                .zip(proposed.peer_sharing)
+                .map(|(ours, theirs)| ours.min(theirs)),
+            query: proposed.query,
+        })
+    }
+}
+
+/// The responder side of the handshake, which picks the highest version
+/// proposed by the client that's also in its own table
+///
+/// If the client sets the query flag in the data of that version, the server
+/// replies with its own table instead.
+#[derive(Debug)]
+pub struct Server<P = SameNetwork> {
+    pub state: State,
+    pub output: Output,
+    pub version_table: VersionTable,
+    pub proposal: Option<VersionTable>,
+    policy: P,
+}
+
+impl Server<SameNetwork> {
+    pub fn initial(version_table: VersionTable) -> Self {
+        Self::with_policy(version_table, SameNetwork)
+    }
+}
+
+impl<P> Server<P>
+where
+    P: AcceptancePolicy<VersionData>,
+{
+    pub fn with_policy(version_table: VersionTable, policy: P) -> Self {
+        Server {
+            state: State::Propose,
+            output: Output::Pending,
+            version_table,
+            proposal: None,
+            policy,
+        }
+    }
+
+    fn send_reply(self, tx: &impl MachineOutput) -> Transition<Self> {
+        let proposal = self
+            .proposal
+            .as_ref()
+            .expect("proposal is received before confirming");
+
+        let queried = self
+            .version_table
+            .highest_common(proposal)
+            .map(|version| proposal.values[&version].query())
+            .unwrap_or(false);
+
+        if queried {
+            debug!("replying to version query");
+            tx.send_msg(&Message::QueryReply(self.version_table.clone()))?;
+
+            return Ok(Self {
+                state: State::Done,
+                output: Output::Queried(self.version_table.clone()),
+                ..self
+            });
+        }
+
+        let output = match self.version_table.negotiate(proposal, &self.policy) {
+            Ok((version, data)) => {
+                debug!("accepting version {}", version);
+                tx.send_msg(&Message::Accept(version, data.clone()))?;
+                Output::Accepted(version, data)
+            }
+            Err(reason) => {
+                debug!("refusing handshake: {:?}", reason);
+                tx.send_msg(&Message::Refuse(reason.clone()))?;
+                Output::Refused(reason)
+            }
+        };
+
+        Ok(Self {
+            state: State::Done,
+            output,
+            ..self
+        })
+    }
+}
+
+impl<P> Agent for Server<P>
+where
+    P: AcceptancePolicy<VersionData>,
+{
+    type Message = Message;
+
+    fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    fn has_agency(&self) -> bool {
+        match self.state {
+            State::Propose => false,
+            State::Confirm => true,
+            State::Done => false,
+        }
+    }
+
+    fn send_next(self, tx: &impl MachineOutput) -> Transition<Self> {
+        match self.state {
+            State::Confirm => self.send_reply(tx),
+            _ => panic!("I don't have agency, nothing to send"),
+        }
+    }
+
+    fn receive_next(self, msg: Self::Message) -> Transition<Self> {
+        match (&self.state, msg) {
+            (State::Propose, Message::Propose(proposal)) => Ok(Self {
+                state: State::Confirm,
+                proposal: Some(proposal),
+                ..self
+            }),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
+        }
+    }
+
+    fn state_timeout(&self) -> Option<Duration> {
+        match self.state {
+            State::Propose => Some(SHORT_WAIT),
+            _ => None,
         }
     }
 }
+
+/// Asks the peer for the versions it supports, through a handshake with the
+/// query flag set
+///
+/// Only versions 11 and above can be queried, so older peers refuse the
+/// proposal with the versions they know (see [QueryError::Refused]). The
+/// peer doesn't start a session after replying, the connection should be
+/// dropped afterwards.
+pub fn query_versions(
+    channel: &mut Channel,
+    network: impl Into<NetworkMagic>,
+) -> Result<VersionTable, QueryError> {
+    let data = VersionData::new(network, false).with_query(true);
+    let versions = VersionTable::from_range(N2NVersion::V11.., data);
+
+    let client = run_agent(Client::initial(versions), channel)?;
+
+    match client.output {
+        Output::Queried(table) => Ok(table),
+        Output::Refused(reason) => Err(QueryError::Refused(reason)),
+        Output::Accepted(version, _) => Err(QueryError::Accepted(version)),
+        Output::Pending => unreachable!("handshake is done"),
+    }
+}
@@ -0,0 +1,360 @@
+use std::thread;
+
+use minicbor::Decoder;
+use pallas_handshake::n2n::{
+    Client, Message, N2NVersion, Output, PeerSharing, Server, VersionData, VersionTable,
+};
+use pallas_handshake::{n2c, Era, Network, QueryError, RefuseReason, MAINNET_MAGIC, TESTNET_MAGIC};
+use pallas_machines::{
+    run_agent,
+    testing::{ScriptError, ScriptedPeer},
+    to_payload, DecodePayload, MachineError, MachineOutput, PayloadDecoder,
+};
+use pallas_multiplexer::{Channel, MemoryBearer, Multiplexer, Role};
+
+fn setup_muxers() -> (Multiplexer, Multiplexer) {
+    let (client_bearer, server_bearer) = MemoryBearer::pair();
+
+    let client = Multiplexer::setup(client_bearer, Role::Initiator, &[0]).unwrap();
+    let server = Multiplexer::setup(server_bearer, Role::Responder, &[0]).unwrap();
+
+    (client, server)
+}
+
+#[test]
+fn client_gets_accepted_version() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(0).unwrap();
+    let Channel(server_tx, server_rx) = server_muxer.use_channel(0).unwrap();
+
+    let client = thread::spawn(move || {
+        let versions = VersionTable::v4_and_above(MAINNET_MAGIC);
+        run_agent(Client::initial(versions), &mut client_channel).unwrap()
+    });
+
+    let proposal = server_rx.recv().unwrap().unwrap();
+    let expected = Message::Propose(VersionTable::v4_and_above(MAINNET_MAGIC));
+    assert_eq!(proposal, to_payload(&expected).unwrap());
+
+    let accept = Message::Accept(7, VersionData::new(MAINNET_MAGIC, false));
+    server_tx.send_msg(&accept).unwrap();
+
+    let client = client.join().unwrap();
+    assert!(matches!(client.output, Output::Accepted(7, _)));
+}
+
+#[test]
+fn proposals_can_be_decoded() {
+    let versions = VersionTable::v4_and_above(MAINNET_MAGIC);
+    let payload = to_payload(&Message::Propose(versions.clone())).unwrap();
+
+    let mut d = PayloadDecoder(Decoder::new(&payload));
+
+    match Message::decode_payload(&mut d).unwrap() {
+        Message::Propose(decoded) => {
+            let mut numbers: Vec<_> = decoded.values.keys().copied().collect();
+            numbers.sort_unstable();
+            assert_eq!(numbers, (4..=7).collect::<Vec<_>>());
+        }
+        other => panic!("unexpected message {:?}", other),
+    }
+}
+
+#[test]
+fn client_rejects_a_counter_proposal() {
+    let versions = VersionTable::v4_and_above(MAINNET_MAGIC);
+
+    let result = ScriptedPeer::new()
+        .expects(&Message::Propose(versions.clone()))
+        .sends(&Message::Propose(versions.clone()))
+        .run(Client::initial(versions));
+
+    assert!(matches!(
+        result,
+        Err(ScriptError::Agent(MachineError::InvalidMsgForState { .. }))
+    ));
+}
+
+#[test]
+fn server_accepts_highest_common_version() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(0).unwrap();
+    let mut server_channel = server_muxer.use_channel(0).unwrap();
+
+    let server = thread::spawn(move || {
+        let server = Server::initial(VersionTable::v6_and_above(MAINNET_MAGIC));
+        run_agent(server, &mut server_channel).unwrap().output
+    });
+
+    let client = Client::initial(VersionTable::v4_and_above(MAINNET_MAGIC));
+    let client = run_agent(client, &mut client_channel).unwrap();
+
+    assert!(matches!(client.output, Output::Accepted(7, _)));
+    assert!(matches!(server.join().unwrap(), Output::Accepted(7, _)));
+
+    assert_eq!(client.output.version(), Some(N2NVersion::V7));
+    let capabilities = client.output.capabilities().unwrap();
+    assert_eq!(capabilities.latest_era, Era::Alonzo);
+    assert!(capabilities.tx_submission2);
+    assert!(!capabilities.peer_sharing);
+}
+
+#[test]
+fn newer_versions_are_negotiated_when_proposed() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(0).unwrap();
+    let mut server_channel = server_muxer.use_channel(0).unwrap();
+
+    let server = thread::spawn(move || {
+        let versions =
+            VersionTable::from_range(N2NVersion::V6.., VersionData::new(MAINNET_MAGIC, false));
+        run_agent(Server::initial(versions), &mut server_channel)
+            .unwrap()
+            .output
+    });
+
+    let client = Client::initial(VersionTable::v11_and_above(MAINNET_MAGIC));
+    let client = run_agent(client, &mut client_channel).unwrap();
+
+    assert!(matches!(client.output, Output::Accepted(14, _)));
+    assert!(matches!(server.join().unwrap(), Output::Accepted(14, _)));
+
+    let capabilities = client.output.capabilities().unwrap();
+    assert_eq!(capabilities.latest_era, Era::Conway);
+    assert!(capabilities.peer_sharing);
+}
+
+#[test]
+fn server_refuses_unknown_versions() {
+    let proposal = VersionTable {
+        values: vec![(3, VersionData::new(MAINNET_MAGIC, false))]
+            .into_iter()
+            .collect(),
+    };
+
+    let server = ScriptedPeer::new()
+        .sends(&Message::Propose(proposal))
+        .expects(&Message::Refuse(RefuseReason::VersionMismatch(
+            (6..=7).collect(),
+        )))
+        .run(Server::initial(VersionTable::v6_and_above(MAINNET_MAGIC)))
+        .unwrap();
+
+    assert!(matches!(
+        server.output,
+        Output::Refused(RefuseReason::VersionMismatch(_))
+    ));
+}
+
+#[test]
+fn n2c_server_refuses_other_networks() {
+    let reason = RefuseReason::Refused(
+        32778,
+        format!(
+            "network magic mismatch, expecting {} but got {}",
+            MAINNET_MAGIC, TESTNET_MAGIC
+        ),
+    );
+
+    let server = ScriptedPeer::new()
+        .sends(&n2c::Message::Propose(n2c::VersionTable::v1_and_above(
+            TESTNET_MAGIC,
+        )))
+        .expects(&n2c::Message::Refuse(reason))
+        .run(n2c::Server::initial(n2c::VersionTable::v1_and_above(
+            MAINNET_MAGIC,
+        )))
+        .unwrap();
+
+    assert!(matches!(
+        server.output,
+        n2c::Output::Refused(RefuseReason::Refused(32778, _))
+    ));
+}
+
+#[test]
+fn version_tables_are_built_from_ranges() {
+    let table = VersionTable::from_range(
+        N2NVersion::V5..N2NVersion::V7,
+        VersionData::new(MAINNET_MAGIC, false),
+    );
+    let mut numbers: Vec<_> = table.values.keys().copied().collect();
+    numbers.sort_unstable();
+    assert_eq!(numbers, vec![5, 6]);
+
+    let table =
+        n2c::VersionTable::from_range(n2c::N2CVersion::V8..=n2c::N2CVersion::V10, MAINNET_MAGIC);
+    let mut numbers: Vec<_> = table.values.keys().copied().collect();
+    numbers.sort_unstable();
+    assert_eq!(numbers, vec![32776, 32777, 32778]);
+
+    let table = n2c::VersionTable::v1_and_above(MAINNET_MAGIC);
+    let mut numbers: Vec<_> = table.values.keys().copied().collect();
+    numbers.sort_unstable();
+    assert_eq!(numbers.len(), 10);
+    assert_eq!(numbers.last(), Some(&32778));
+
+    let table = n2c::VersionTable::v15_and_above(MAINNET_MAGIC);
+    let mut numbers: Vec<_> = table.values.keys().copied().collect();
+    numbers.sort_unstable();
+    assert_eq!(numbers, vec![32783, 32784]);
+}
+
+#[test]
+fn n2c_accepted_version_maps_to_capabilities() {
+    let client = ScriptedPeer::new()
+        .expects(&n2c::Message::Propose(n2c::VersionTable::v1_and_above(
+            MAINNET_MAGIC,
+        )))
+        .sends(&n2c::Message::Accept(
+            32776,
+            n2c::VersionData::new(MAINNET_MAGIC),
+        ))
+        .run(n2c::Client::initial(n2c::VersionTable::v1_and_above(
+            MAINNET_MAGIC,
+        )))
+        .unwrap();
+
+    assert_eq!(client.output.version(), Some(n2c::N2CVersion::V8));
+    assert_eq!(
+        client.output.capabilities(),
+        Some(n2c::Capabilities {
+            latest_era: Era::Mary,
+            local_state_query: true,
+            acquire_tip: true,
+            chain_point_queries: false,
+            version_query: false,
+        })
+    );
+}
+
+#[test]
+fn version_data_has_the_shape_of_the_version() {
+    let data = VersionData::new(MAINNET_MAGIC, true).with_peer_sharing(PeerSharing::Enabled);
+    let table = VersionTable::from_range(N2NVersion::V10..=N2NVersion::V11, data);
+
+    // the older version drops peer sharing and the query flag
+    let old = to_payload(&table.values[&10]).unwrap();
+    assert_eq!(minicbor::display(&old).to_string(), "[764824073, true]");
+
+    let new = to_payload(&table.values[&11]).unwrap();
+    assert_eq!(
+        minicbor::display(&new).to_string(),
+        "[764824073, true, 1, false]"
+    );
+
+    // the public mode of versions 11 and 12 is read as enabled
+    let mut d = PayloadDecoder(Decoder::new(&[0x84, 0x01, 0xf4, 0x02, 0xf5]));
+    let decoded = VersionData::decode_payload(&mut d).unwrap();
+    assert_eq!(decoded.peer_sharing(), Some(PeerSharing::Enabled));
+    assert!(decoded.query());
+
+    let mut d = PayloadDecoder(Decoder::new(&[0x83, 0x01, 0xf4, 0x00]));
+    assert!(VersionData::decode_payload(&mut d).is_err());
+}
+
+#[test]
+fn server_shares_peers_only_if_both_sides_do() {
+    let proposal = VersionTable::from_range(
+        N2NVersion::V13..,
+        VersionData::new(MAINNET_MAGIC, true).with_peer_sharing(PeerSharing::Enabled),
+    );
+    let accepted = VersionData::new(MAINNET_MAGIC, false).for_version(N2NVersion::V14);
+
+    let server = ScriptedPeer::new()
+        .sends(&Message::Propose(proposal))
+        .expects(&Message::Accept(14, accepted))
+        .run(Server::initial(VersionTable::v11_and_above(MAINNET_MAGIC)))
+        .unwrap();
+
+    match server.output {
+        Output::Accepted(14, data) => {
+            assert_eq!(data.peer_sharing(), Some(PeerSharing::Disabled));
+            assert!(!data.initiator_and_responder_diffusion_mode());
+        }
+        other => panic!("unexpected output {:?}", other),
+    }
+}
+
+#[test]
+fn query_returns_the_server_table() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(0).unwrap();
+    let mut server_channel = server_muxer.use_channel(0).unwrap();
+
+    let server = thread::spawn(move || {
+        let server = Server::initial(VersionTable::v11_and_above(MAINNET_MAGIC));
+        run_agent(server, &mut server_channel).unwrap().output
+    });
+
+    let table = pallas_handshake::n2n::query_versions(&mut client_channel, MAINNET_MAGIC).unwrap();
+
+    let mut numbers: Vec<_> = table.values.keys().copied().collect();
+    numbers.sort_unstable();
+    assert_eq!(numbers, (11..=14).collect::<Vec<_>>());
+
+    assert!(matches!(server.join().unwrap(), Output::Queried(_)));
+}
+
+#[test]
+fn query_is_refused_by_older_nodes() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(0).unwrap();
+    let mut server_channel = server_muxer.use_channel(0).unwrap();
+
+    thread::spawn(move || {
+        let versions = n2c::VersionTable::from_range(..=n2c::N2CVersion::V14, MAINNET_MAGIC);
+        run_agent(n2c::Server::initial(versions), &mut server_channel).unwrap();
+    });
+
+    let result = n2c::query_versions(&mut client_channel, MAINNET_MAGIC);
+
+    match result {
+        Err(QueryError::Refused(RefuseReason::VersionMismatch(versions))) => {
+            assert_eq!(versions.last(), Some(&32782));
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn n2c_version_data_has_the_shape_of_the_version() {
+    let data = n2c::VersionData::new(MAINNET_MAGIC).with_query(true);
+    let table = n2c::VersionTable::from_range(n2c::N2CVersion::V14..=n2c::N2CVersion::V15, data);
+
+    // the query flag can't be sent to older versions
+    let old = to_payload(&table.values[&32782]).unwrap();
+    assert_eq!(minicbor::display(&old).to_string(), "764824073");
+
+    let new = to_payload(&table.values[&32783]).unwrap();
+    assert_eq!(minicbor::display(&new).to_string(), "[764824073, true]");
+
+    let mut d = PayloadDecoder(Decoder::new(&new));
+    assert!(n2c::VersionData::decode_payload(&mut d).unwrap().query());
+
+    // [764824073, true, 0] and [_ 764824073, true]
+    for payload in [
+        &[0x83, 0x1a, 0x2d, 0x96, 0x4a, 0x09, 0xf5, 0x00][..],
+        &[0x9f, 0x1a, 0x2d, 0x96, 0x4a, 0x09, 0xf5, 0xff][..],
+    ] {
+        let mut d = PayloadDecoder(Decoder::new(payload));
+        assert!(n2c::VersionData::decode_payload(&mut d).is_err());
+    }
+}
+
+#[test]
+fn version_tables_are_built_for_a_network() {
+    let table = VersionTable::v6_and_above(Network::Preprod);
+    assert!(table
+        .values
+        .values()
+        .all(|data| data.network() == Network::Preprod));
+
+    let table = n2c::VersionTable::v1_and_above(Network::Custom(42));
+    assert!(table.values.values().all(|data| data.network_magic() == 42));
+}
@@ -1,9 +1,9 @@
 use pallas_handshake::n2c::{Client, VersionTable};
-use pallas_handshake::MAINNET_MAGIC;
+use pallas_handshake::Network;
 use pallas_localstate::queries::RequestV10;
 use pallas_localstate::{queries::QueryV10, OneShotClient};
-use pallas_machines::run_agent;
-use pallas_multiplexer::Multiplexer;
+use pallas_machines::Driver;
+use pallas_multiplexer::{Multiplexer, Role};
 use std::os::unix::net::UnixStream;
 
 fn main() {
@@ -13,16 +13,22 @@ fn main() {
     // path for your environment
     let bearer = UnixStream::connect("/tmp/node.socket").unwrap();
 
-    let mut muxer = Multiplexer::setup(bearer, &vec![0, 7]).unwrap();
+    let mut muxer = Multiplexer::setup(bearer, Role::Initiator, &[0, 7]).unwrap();
 
-    let mut hs_channel = muxer.use_channel(0);
-    let versions = VersionTable::only_v10(MAINNET_MAGIC);
-    let last = run_agent(Client::initial(versions), &mut hs_channel).unwrap();
+    // a local node may take a while to reply, node-to-client protocols run
+    // without the time limits of the spec
+    let driver = Driver::new().without_time_limits();
+
+    let mut hs_channel = muxer.use_channel(0).unwrap();
+    let versions = VersionTable::only_v10(Network::Mainnet);
+    let last = driver
+        .run(Client::initial(versions), &mut hs_channel)
+        .unwrap();
     println!("last hanshake state: {:?}", last);
 
-    let mut ls_channel = muxer.use_channel(7);
+    let mut ls_channel = muxer.use_channel(7).unwrap();
 
     let cs = OneShotClient::<QueryV10>::initial(None, RequestV10::GetChainPoint);
-    let cs = run_agent(cs, &mut ls_channel).unwrap();
+    let cs = driver.run(cs, &mut ls_channel).unwrap();
     println!("{:?}", cs);
 }
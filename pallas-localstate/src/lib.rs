@@ -6,7 +6,8 @@ use std::fmt::Debug;
 use log::debug;
 
 use pallas_machines::{
-    primitives::Point, Agent, DecodePayload, EncodePayload, MachineError, MachineOutput, Transition,
+    primitives::Point, Agent, DecodePayload, EncodePayload, MachineError, MachineOutput, Responder,
+    Transition,
 };
 
 #[derive(Debug, PartialEq, Clone)]
@@ -28,15 +29,23 @@ pub trait Query: Debug {
     type Response: EncodePayload + DecodePayload + Clone + Debug;
 }
 
-#[derive(Debug)]
+#[derive(Debug, EncodePayload, DecodePayload)]
 pub enum Message<Q: Query> {
+    #[label(0, none = 8)]
     Acquire(Option<Point>),
+    #[label(2)]
     Failure(AcquireFailure),
+    #[label(1)]
     Acquired,
+    #[label(3)]
     Query(Q::Request),
+    #[label(4)]
     Result(Q::Response),
+    #[label(6, none = 9)]
     ReAcquire(Option<Point>),
+    #[label(5)]
     Release,
+    #[label(7)]
     Done,
 }
 
@@ -122,7 +131,9 @@ impl<Q: Query> OneShotClient<Q> {
         })
     }
 
-    fn done(self) -> Transition<Self> {
+    fn send_done(self, tx: &impl MachineOutput) -> Transition<Self> {
+        tx.send_msg(&Message::<Q>::Done)?;
+
         Ok(Self {
             state: State::Done,
             ..self
@@ -137,6 +148,10 @@ impl<Q: Query + 'static> Agent for OneShotClient<Q> {
         self.state == State::Done
     }
 
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
     #[allow(clippy::match_like_matches_macro)]
     fn has_agency(&self) -> bool {
         match self.state {
@@ -151,7 +166,7 @@ impl<Q: Query + 'static> Agent for OneShotClient<Q> {
             // if we're idle and without a result, assume start of flow
             (State::Idle, None) => self.send_acquire(tx),
             // if we're idle and with a result, assume end of flow
-            (State::Idle, Some(_)) => self.done(),
+            (State::Idle, Some(_)) => self.send_done(tx),
             // if we don't have an output, assume start of query
             (State::Acquired, None) => self.send_query(tx),
             // if we have an output but still acquired, release the server
@@ -165,7 +180,165 @@ impl<Q: Query + 'static> Agent for OneShotClient<Q> {
             (State::Acquiring, Message::Acquired) => self.on_acquired(),
             (State::Acquiring, Message::Failure(failure)) => self.on_failure(failure),
             (State::Querying, Message::Result(result)) => self.on_result(result),
-            (_, msg) => Err(MachineError::InvalidMsgForState(self.state, msg).into()),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
+        }
+    }
+}
+
+/// The source of the ledger state served by a [Server], provided by the
+/// application
+pub trait StateProvider<Q: Query> {
+    /// Acquires the ledger state at the point (or at the tip if `None`) for
+    /// the queries that follow
+    fn acquire(
+        &mut self,
+        point: Option<&Point>,
+    ) -> Result<Result<(), AcquireFailure>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Runs the query against the acquired ledger state
+    fn query(
+        &mut self,
+        request: &Q::Request,
+    ) -> Result<Q::Response, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// The server side of local-state-query, which answers queries using a
+/// [StateProvider]
+#[derive(Debug)]
+pub struct Server<Q: Query> {
+    pub state: State,
+    pub acquire_point: Option<Point>,
+    pub request: Option<Q::Request>,
+}
+
+impl<Q: Query> Server<Q> {
+    pub fn initial() -> Self {
+        Self {
+            state: State::Idle,
+            acquire_point: None,
+            request: None,
+        }
+    }
+
+    fn send_acquire_result(
+        self,
+        tx: &impl MachineOutput,
+        provider: &mut impl StateProvider<Q>,
+    ) -> Transition<Self> {
+        match provider.acquire(self.acquire_point.as_ref())? {
+            Ok(()) => {
+                tx.send_msg(&Message::<Q>::Acquired)?;
+
+                Ok(Self {
+                    state: State::Acquired,
+                    ..self
+                })
+            }
+            Err(failure) => {
+                debug!("acquire failure: {:?}", failure);
+                tx.send_msg(&Message::<Q>::Failure(failure))?;
+
+                Ok(Self {
+                    state: State::Idle,
+                    ..self
+                })
+            }
+        }
+    }
+
+    fn send_query_result(
+        self,
+        tx: &impl MachineOutput,
+        provider: &mut impl StateProvider<Q>,
+    ) -> Transition<Self> {
+        let request = self.request.as_ref().expect("querying without a request");
+        let response = provider.query(request)?;
+
+        debug!("query result: {:?}", response);
+        tx.send_msg(&Message::<Q>::Result(response))?;
+
+        Ok(Self {
+            state: State::Acquired,
+            request: None,
+            ..self
+        })
+    }
+
+    fn on_acquire(self, point: Option<Point>) -> Transition<Self> {
+        debug!("acquire requested for point: {:?}", point);
+
+        Ok(Self {
+            state: State::Acquiring,
+            acquire_point: point,
+            ..self
+        })
+    }
+
+    fn on_query(self, request: Q::Request) -> Transition<Self> {
+        debug!("query requested: {:?}", request);
+
+        Ok(Self {
+            state: State::Querying,
+            request: Some(request),
+            ..self
+        })
+    }
+
+    fn on_release(self) -> Transition<Self> {
+        Ok(Self {
+            state: State::Idle,
+            ..self
+        })
+    }
+
+    fn on_done(self) -> Transition<Self> {
+        Ok(Self {
+            state: State::Done,
+            ..self
+        })
+    }
+}
+
+impl<Q, P> Responder<P> for Server<Q>
+where
+    Q: Query + 'static,
+    P: StateProvider<Q>,
+{
+    type Message = Message<Q>;
+
+    fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    #[allow(clippy::match_like_matches_macro)]
+    fn has_agency(&self) -> bool {
+        match self.state {
+            State::Acquiring => true,
+            State::Querying => true,
+            _ => false,
+        }
+    }
+
+    fn send_next(self, tx: &impl MachineOutput, provider: &mut P) -> Transition<Self> {
+        match self.state {
+            State::Acquiring => self.send_acquire_result(tx, provider),
+            State::Querying => self.send_query_result(tx, provider),
+            _ => panic!("I don't have agency, don't know what to do"),
+        }
+    }
+
+    fn receive_next(self, msg: Self::Message) -> Transition<Self> {
+        match (&self.state, msg) {
+            (State::Idle, Message::Acquire(point)) => self.on_acquire(point),
+            (State::Idle, Message::Done) => self.on_done(),
+            (State::Acquired, Message::Query(request)) => self.on_query(request),
+            (State::Acquired, Message::ReAcquire(point)) => self.on_acquire(point),
+            (State::Acquired, Message::Release) => self.on_release(),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
         }
     }
 }
@@ -1,5 +1,7 @@
 use minicbor::{data::Cbor, Decoder};
-use pallas_machines::{primitives::Point, DecodePayload, EncodePayload, PayloadDecoder};
+use pallas_machines::{
+    primitives::Point, CodecError, DecodePayload, EncodePayload, PayloadDecoder,
+};
 
 use super::Query;
 
@@ -15,24 +17,21 @@ pub enum RequestV10 {
 }
 
 impl EncodePayload for RequestV10 {
-    fn encode_payload(
-        &self,
-        e: &mut pallas_machines::PayloadEncoder,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    fn encode_payload(&self, e: &mut pallas_machines::PayloadEncoder) -> Result<(), CodecError> {
         match self {
             Self::BlockQuery(..) => {
                 todo!()
             }
             Self::GetSystemStart => {
-                e.u16(1)?;
+                e.array(1)?.u16(1)?;
                 Ok(())
             }
             Self::GetChainBlockNo => {
-                e.u16(2)?;
+                e.array(1)?.u16(2)?;
                 Ok(())
             }
             Self::GetChainPoint => {
-                e.u16(3)?;
+                e.array(1)?.u16(3)?;
                 Ok(())
             }
         }
@@ -40,38 +39,42 @@ impl EncodePayload for RequestV10 {
 }
 
 impl DecodePayload for RequestV10 {
-    fn decode_payload(
-        _d: &mut pallas_machines::PayloadDecoder,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        todo!()
+    fn decode_payload(d: &mut pallas_machines::PayloadDecoder) -> Result<Self, CodecError> {
+        d.array()?;
+        let label = d.u16()?;
+
+        match label {
+            0 => Err(CodecError::UnexpectedCbor(
+                "block queries aren't supported yet",
+            )),
+            1 => Ok(Self::GetSystemStart),
+            2 => Ok(Self::GetChainBlockNo),
+            3 => Ok(Self::GetChainPoint),
+            x => Err(CodecError::BadLabel(x)),
+        }
     }
 }
 
+/// The CBOR of a query result, kept as is
 #[derive(Debug, Clone)]
-pub struct GenericResponse(Vec<u8>);
+pub struct GenericResponse(pub Vec<u8>);
 
 impl EncodePayload for GenericResponse {
-    fn encode_payload(
-        &self,
-        _e: &mut pallas_machines::PayloadEncoder,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        todo!()
+    fn encode_payload(&self, e: &mut pallas_machines::PayloadEncoder) -> Result<(), CodecError> {
+        e.encode(Cbor::from(self.0.as_slice()))?;
+        Ok(())
     }
 }
 
 impl DecodePayload for GenericResponse {
-    fn decode_payload(
-        d: &mut pallas_machines::PayloadDecoder,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        let cbor: Cbor = d.decode()?;
-        let slice = cbor.as_ref();
-        let vec = slice.to_vec();
+    fn decode_payload(d: &mut pallas_machines::PayloadDecoder) -> Result<Self, CodecError> {
+        let vec = d.raw_value()?.to_vec();
         Ok(GenericResponse(vec))
     }
 }
 
 impl TryInto<Point> for GenericResponse {
-    type Error = Box<dyn std::error::Error>;
+    type Error = CodecError;
 
     fn try_into(self) -> Result<Point, Self::Error> {
         let mut d = PayloadDecoder(Decoder::new(self.0.as_slice()));
@@ -0,0 +1,249 @@
+use std::thread;
+
+use minicbor::Encoder;
+use pallas_localstate::{
+    queries::{GenericResponse, QueryV10, RequestV10},
+    AcquireFailure, Message, OneShotClient, Server, State, StateProvider,
+};
+use pallas_machines::{
+    primitives::Point,
+    run_agent, run_responder,
+    testing::{ScriptError, ScriptedPeer},
+    to_payload, MachineError, MachineOutput,
+};
+use pallas_multiplexer::{Channel, MemoryBearer, Multiplexer, Role};
+
+fn setup_muxers() -> (Multiplexer, Multiplexer) {
+    let (client_bearer, server_bearer) = MemoryBearer::pair();
+
+    let client = Multiplexer::setup(client_bearer, Role::Initiator, &[7]).unwrap();
+    let server = Multiplexer::setup(server_bearer, Role::Responder, &[7]).unwrap();
+
+    (client, server)
+}
+
+fn chain_point_result(slot: u64, hash: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    let mut e = Encoder::new(&mut payload);
+    e.array(2).unwrap().u16(4).unwrap();
+    e.array(2).unwrap().u64(slot).unwrap().bytes(hash).unwrap();
+
+    payload
+}
+
+#[test]
+fn one_shot_client_queries_chain_point() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(7).unwrap();
+    let Channel(server_tx, server_rx) = server_muxer.use_channel(7).unwrap();
+
+    let client = thread::spawn(move || {
+        let agent = OneShotClient::<QueryV10>::initial(None, RequestV10::GetChainPoint);
+        run_agent(agent, &mut client_channel).unwrap()
+    });
+
+    let acquire = to_payload(&Message::<QueryV10>::Acquire(None)).unwrap();
+    assert_eq!(server_rx.recv().unwrap().unwrap(), acquire);
+    server_tx.send_msg(&Message::<QueryV10>::Acquired).unwrap();
+
+    let query = to_payload(&Message::<QueryV10>::Query(RequestV10::GetChainPoint)).unwrap();
+    assert_eq!(server_rx.recv().unwrap().unwrap(), query);
+    server_tx
+        .send(chain_point_result(1234, &[0xcc; 32]))
+        .unwrap();
+
+    let release = to_payload(&Message::<QueryV10>::Release).unwrap();
+    assert_eq!(server_rx.recv().unwrap().unwrap(), release);
+
+    let client = client.join().unwrap();
+    assert_eq!(client.state, State::Done);
+
+    let response = client.output.unwrap().unwrap();
+    let point: Point = response.try_into().unwrap();
+    assert_eq!(point, Point::Specific(1234, [0xcc; 32]));
+}
+
+#[test]
+fn one_shot_client_sends_done_when_finished() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(7).unwrap();
+    let Channel(server_tx, server_rx) = server_muxer.use_channel(7).unwrap();
+
+    let client = thread::spawn(move || {
+        let agent = OneShotClient::<QueryV10>::initial(None, RequestV10::GetChainPoint);
+        run_agent(agent, &mut client_channel).unwrap()
+    });
+
+    server_rx.recv().unwrap().unwrap();
+    server_tx.send_msg(&Message::<QueryV10>::Acquired).unwrap();
+
+    server_rx.recv().unwrap().unwrap();
+    server_tx
+        .send(chain_point_result(1234, &[0xcc; 32]))
+        .unwrap();
+
+    // the client releases the state, then ends the protocol
+    let release = to_payload(&Message::<QueryV10>::Release).unwrap();
+    assert_eq!(server_rx.recv().unwrap().unwrap(), release);
+
+    let done = to_payload(&Message::<QueryV10>::Done).unwrap();
+    assert_eq!(server_rx.recv().unwrap().unwrap(), done);
+
+    assert_eq!(client.join().unwrap().state, State::Done);
+}
+
+/// A ledger that only knows its tip, reachable up to a given slot
+struct TipLedger {
+    tip: Point,
+    oldest_slot: u64,
+}
+
+impl StateProvider<QueryV10> for TipLedger {
+    fn acquire(
+        &mut self,
+        point: Option<&Point>,
+    ) -> Result<Result<(), AcquireFailure>, Box<dyn std::error::Error + Send + Sync>> {
+        match point {
+            Some(point) if point.slot_or_default() < self.oldest_slot => {
+                Ok(Err(AcquireFailure::PointTooOld))
+            }
+            _ => Ok(Ok(())),
+        }
+    }
+
+    fn query(
+        &mut self,
+        request: &RequestV10,
+    ) -> Result<GenericResponse, Box<dyn std::error::Error + Send + Sync>> {
+        match request {
+            RequestV10::GetChainPoint => Ok(GenericResponse(to_payload(&self.tip)?)),
+            _ => Err("unsupported query".into()),
+        }
+    }
+}
+
+#[test]
+fn server_answers_one_shot_clients() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(7).unwrap();
+    let mut server_channel = server_muxer.use_channel(7).unwrap();
+
+    let server = thread::spawn(move || {
+        let mut ledger = TipLedger {
+            tip: Point::Specific(1234, [0xcc; 32]),
+            oldest_slot: 1000,
+        };
+
+        let server = Server::initial();
+        run_responder(server, &mut ledger, &mut server_channel)
+            .unwrap()
+            .state
+    });
+
+    let agent = OneShotClient::<QueryV10>::initial(None, RequestV10::GetChainPoint);
+    let client = run_agent(agent, &mut client_channel).unwrap();
+
+    let point: Point = client.output.unwrap().unwrap().try_into().unwrap();
+    assert_eq!(point.slot_or_default(), 1234);
+
+    // the client is done, and so is the server
+    assert_eq!(server.join().unwrap(), State::Done);
+}
+
+#[test]
+fn server_reports_acquire_failures() {
+    let (mut client_muxer, mut server_muxer) = setup_muxers();
+
+    let mut client_channel = client_muxer.use_channel(7).unwrap();
+    let mut server_channel = server_muxer.use_channel(7).unwrap();
+
+    thread::spawn(move || {
+        let mut ledger = TipLedger {
+            tip: Point::Specific(1234, [0xcc; 32]),
+            oldest_slot: 1000,
+        };
+
+        run_responder(Server::initial(), &mut ledger, &mut server_channel).ok();
+    });
+
+    let old_point = Some(Point::Specific(10, [0xaa; 32]));
+    let agent = OneShotClient::<QueryV10>::initial(old_point, RequestV10::GetChainPoint);
+    let client = run_agent(agent, &mut client_channel).unwrap();
+
+    assert!(matches!(
+        client.output,
+        Some(Err(AcquireFailure::PointTooOld))
+    ));
+}
+
+#[test]
+fn scripted_one_shot_client_is_done_after_acquire_failure() {
+    let agent = OneShotClient::<QueryV10>::initial(None, RequestV10::GetChainPoint);
+
+    let agent = ScriptedPeer::new()
+        .expects(&Message::<QueryV10>::Acquire(None))
+        .sends(&Message::<QueryV10>::Failure(AcquireFailure::PointTooOld))
+        .expects(&Message::<QueryV10>::Done)
+        .run(agent)
+        .unwrap();
+
+    assert_eq!(agent.state, State::Done);
+    assert!(matches!(
+        agent.output,
+        Some(Err(AcquireFailure::PointTooOld))
+    ));
+}
+
+#[test]
+fn scripted_one_shot_client_rejects_result_before_acquiring() {
+    let agent = OneShotClient::<QueryV10>::initial(None, RequestV10::GetChainPoint);
+
+    let result = ScriptedPeer::new()
+        .expects(&Message::<QueryV10>::Acquire(None))
+        .sends_raw(chain_point_result(1234, &[0xcc; 32]))
+        .run(agent);
+
+    assert!(matches!(
+        result,
+        Err(ScriptError::Agent(MachineError::InvalidMsgForState { .. }))
+    ));
+}
+
+/// A ledger whose database can't be opened
+struct MissingLedger;
+
+impl StateProvider<QueryV10> for MissingLedger {
+    fn acquire(
+        &mut self,
+        _point: Option<&Point>,
+    ) -> Result<Result<(), AcquireFailure>, Box<dyn std::error::Error + Send + Sync>> {
+        let err = std::io::Error::new(std::io::ErrorKind::NotFound, "ledger db missing");
+        Err(Box::new(err))
+    }
+
+    fn query(
+        &mut self,
+        _request: &RequestV10,
+    ) -> Result<GenericResponse, Box<dyn std::error::Error + Send + Sync>> {
+        unreachable!("nothing can be acquired")
+    }
+}
+
+#[test]
+fn provider_failures_keep_the_original_error() {
+    let result = ScriptedPeer::new()
+        .sends(&Message::<QueryV10>::Acquire(None))
+        .expects(&Message::<QueryV10>::Acquired)
+        .serve(Server::initial(), &mut MissingLedger);
+
+    match result {
+        Err(ScriptError::Agent(MachineError::External(err))) => {
+            let err = err.downcast::<std::io::Error>().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
@@ -0,0 +1,477 @@
+//! Derive macros for the `EncodePayload` and `DecodePayload` traits
+//!
+//! The macros are meant to be used through the re-exports of
+//! `pallas-machines`, the generated code refers to its items.
+
+use std::collections::HashSet;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, TokenStream as TokenStream2, TokenTree};
+use quote::{format_ident, quote, ToTokens};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, parse_quote, Attribute, Data, DataEnum, DeriveInput, Fields,
+    GenericArgument, Generics, Index, LitInt, Path, PathArguments, Token, Type,
+};
+
+/// The arguments of a `#[label(n)]` or `#[label(n, none = m)]` attribute
+struct LabelArgs {
+    label: u16,
+    none: Option<u16>,
+}
+
+impl Parse for LabelArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let label = input.parse::<LitInt>()?.base10_parse::<u16>()?;
+
+        let none = if input.parse::<Option<Token![,]>>()?.is_some() {
+            let key: Ident = input.parse()?;
+
+            if key != "none" {
+                return Err(syn::Error::new_spanned(key, "expected `none = n`"));
+            }
+
+            input.parse::<Token![=]>()?;
+            Some(input.parse::<LitInt>()?.base10_parse::<u16>()?)
+        } else {
+            None
+        };
+
+        Ok(LabelArgs { label, none })
+    }
+}
+
+/// How a field is laid out in the array of the message
+enum FieldShape<'a> {
+    /// A single item, encoded with its own payload codec
+    Item(&'a Type),
+    /// A byte vector holding CBOR data wrapped in a tag 24
+    Wrapped,
+    /// A tuple whose elements are encoded as items of the message itself
+    Flatten(Vec<&'a Type>),
+}
+
+impl FieldShape<'_> {
+    /// The amount of items the field takes in the array of the message
+    fn len(&self) -> u64 {
+        match self {
+            FieldShape::Flatten(elems) => elems.len() as u64,
+            _ => 1,
+        }
+    }
+
+    fn types(&self) -> Vec<&Type> {
+        match self {
+            FieldShape::Item(ty) => vec![ty],
+            FieldShape::Wrapped => vec![],
+            FieldShape::Flatten(elems) => elems.clone(),
+        }
+    }
+}
+
+/// A variant of the message enum along with its label
+struct LabeledVariant<'a> {
+    ident: &'a Ident,
+    label: u16,
+    /// The label of the variant when its optional field is `None`
+    none: Option<NoneLabel<'a>>,
+    fields: &'a Fields,
+    shapes: Vec<FieldShape<'a>>,
+}
+
+/// The alternate label of a variant holding a single `Option<T>` field
+struct NoneLabel<'a> {
+    label: u16,
+    inner: &'a Type,
+}
+
+fn has_attr(attrs: &[Attribute], name: &str) -> bool {
+    attrs.iter().any(|attr| attr.path.is_ident(name))
+}
+
+fn parse_label(variant: &syn::Variant) -> syn::Result<LabelArgs> {
+    let attr = variant
+        .attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("label"))
+        .ok_or_else(|| {
+            syn::Error::new_spanned(variant, "missing #[label(n)] attribute on variant")
+        })?;
+
+    attr.parse_args::<LabelArgs>()
+}
+
+fn field_shape(field: &syn::Field) -> syn::Result<FieldShape<'_>> {
+    let wrapped = has_attr(&field.attrs, "wrapped");
+    let flatten = has_attr(&field.attrs, "flatten");
+
+    match (wrapped, flatten, &field.ty) {
+        (true, true, _) => Err(syn::Error::new_spanned(
+            field,
+            "a field can't be both #[wrapped] and #[flatten]",
+        )),
+        (true, false, _) => Ok(FieldShape::Wrapped),
+        (false, true, Type::Tuple(tuple)) => Ok(FieldShape::Flatten(tuple.elems.iter().collect())),
+        (false, true, _) => Err(syn::Error::new_spanned(
+            field,
+            "#[flatten] can only be used on tuple fields",
+        )),
+        (false, false, ty) => Ok(FieldShape::Item(ty)),
+    }
+}
+
+/// The `T` of a field of type `Option<T>`
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let segment = match ty {
+        Type::Path(path) if path.qself.is_none() => path.path.segments.last()?,
+        _ => return None,
+    };
+
+    if segment.ident != "Option" {
+        return None;
+    }
+
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) if args.args.len() == 1 => match &args.args[0] {
+            GenericArgument::Type(inner) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn none_label<'a>(
+    variant: &'a syn::Variant,
+    label: u16,
+    shapes: &[FieldShape<'a>],
+) -> syn::Result<NoneLabel<'a>> {
+    let inner = match shapes {
+        [FieldShape::Item(ty)] => option_inner(ty),
+        _ => None,
+    };
+
+    inner
+        .map(|inner| NoneLabel { label, inner })
+        .ok_or_else(|| {
+            syn::Error::new_spanned(
+                variant,
+                "#[label(n, none = m)] requires a single field of type Option<T>",
+            )
+        })
+}
+
+fn labeled_variants(input: &DeriveInput) -> syn::Result<Vec<LabeledVariant<'_>>> {
+    let variants = match &input.data {
+        Data::Enum(DataEnum { variants, .. }) => variants,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                input,
+                "payload codecs can only be derived for enums",
+            ))
+        }
+    };
+
+    let mut labeled: Vec<LabeledVariant> = Vec::new();
+    let mut used = HashSet::new();
+
+    for variant in variants {
+        let args = parse_label(variant)?;
+
+        let shapes = variant
+            .fields
+            .iter()
+            .map(field_shape)
+            .collect::<syn::Result<Vec<_>>>()?;
+
+        let none = args
+            .none
+            .map(|label| none_label(variant, label, &shapes))
+            .transpose()?;
+
+        for label in std::iter::once(args.label).chain(args.none) {
+            if !used.insert(label) {
+                return Err(syn::Error::new_spanned(
+                    variant,
+                    format!("label {} is used by more than one variant", label),
+                ));
+            }
+        }
+
+        labeled.push(LabeledVariant {
+            ident: &variant.ident,
+            label: args.label,
+            none,
+            fields: &variant.fields,
+            shapes,
+        });
+    }
+
+    Ok(labeled)
+}
+
+/// Whether any of the tokens of the type is one of the given identifiers
+fn mentions_any(tokens: TokenStream2, idents: &[Ident]) -> bool {
+    tokens.into_iter().any(|token| match token {
+        TokenTree::Ident(ident) => idents.contains(&ident),
+        TokenTree::Group(group) => mentions_any(group.stream(), idents),
+        _ => false,
+    })
+}
+
+/// Requires each field type that depends on a type parameter to implement
+/// the derived trait, which also covers associated types (eg: `Q::Request`)
+fn bound_generics(generics: &Generics, variants: &[LabeledVariant], bound: &Path) -> Generics {
+    let mut generics = generics.clone();
+    let params: Vec<_> = generics.type_params().map(|p| p.ident.clone()).collect();
+
+    let mut seen = HashSet::new();
+    let bounded: Vec<&Type> = variants
+        .iter()
+        .flat_map(|variant| match &variant.none {
+            Some(none) => vec![none.inner],
+            None => variant.shapes.iter().flat_map(|s| s.types()).collect(),
+        })
+        .filter(|ty| mentions_any(ty.to_token_stream(), &params))
+        .filter(|ty| seen.insert(ty.to_token_stream().to_string()))
+        .collect();
+
+    let where_clause = generics.make_where_clause();
+
+    for ty in bounded {
+        where_clause.predicates.push(parse_quote!(#ty: #bound));
+    }
+
+    generics
+}
+
+/// Names used to bind the fields of a variant in match arms
+fn field_bindings(fields: &Fields) -> Vec<Ident> {
+    (0..fields.len())
+        .map(|i| format_ident!("field_{}", i))
+        .collect()
+}
+
+fn variant_pattern(variant: &LabeledVariant, bindings: &[TokenStream2]) -> TokenStream2 {
+    let ident = variant.ident;
+
+    match variant.fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|f| &f.ident);
+            quote!(Self::#ident { #(#names: #bindings),* })
+        }
+        Fields::Unnamed(_) => quote!(Self::#ident ( #(#bindings),* )),
+        Fields::Unit => quote!(Self::#ident),
+    }
+}
+
+fn encode_field(shape: &FieldShape, binding: &Ident) -> TokenStream2 {
+    match shape {
+        FieldShape::Item(_) => {
+            quote!(::pallas_machines::EncodePayload::encode_payload(#binding, e)?;)
+        }
+        FieldShape::Wrapped => quote!(::pallas_machines::encode_wrapped_bytes(e, #binding)?;),
+        FieldShape::Flatten(elems) => {
+            let indexes = (0..elems.len()).map(Index::from);
+            quote!(#(::pallas_machines::EncodePayload::encode_payload(&#binding.#indexes, e)?;)*)
+        }
+    }
+}
+
+fn decode_field(shape: &FieldShape, binding: &Ident) -> TokenStream2 {
+    match shape {
+        FieldShape::Item(_) => {
+            quote!(let #binding = ::pallas_machines::DecodePayload::decode_payload(d)?;)
+        }
+        FieldShape::Wrapped => quote!(let #binding = ::pallas_machines::decode_wrapped_bytes(d)?;),
+        FieldShape::Flatten(elems) => {
+            let items = elems
+                .iter()
+                .map(|_| quote!(::pallas_machines::DecodePayload::decode_payload(d)?));
+            quote!(let #binding = (#(#items,)*);)
+        }
+    }
+}
+
+/// Fails the decoding when the array doesn't hold the expected amount of items
+fn check_len(len: u64) -> TokenStream2 {
+    quote! {
+        if matches!(array_len, Some(len) if len != #len) {
+            return Err(::pallas_machines::CodecError::UnexpectedCbor(
+                "unexpected amount of items for message",
+            ));
+        }
+    }
+}
+
+fn expand_encode(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let variants = labeled_variants(input)?;
+
+    let arms = variants.iter().map(|variant| {
+        let bindings = field_bindings(variant.fields);
+        let label = variant.label;
+
+        if let Some(none) = &variant.none {
+            let binding = &bindings[0];
+            let some_pattern =
+                variant_pattern(variant, &[quote!(::core::option::Option::Some(#binding))]);
+            let none_pattern = variant_pattern(variant, &[quote!(::core::option::Option::None)]);
+            let none_label = none.label;
+
+            return quote! {
+                #some_pattern => {
+                    e.array(2)?.u16(#label)?;
+                    ::pallas_machines::EncodePayload::encode_payload(#binding, e)?;
+                }
+                #none_pattern => {
+                    e.array(1)?.u16(#none_label)?;
+                }
+            };
+        }
+
+        let pattern = variant_pattern(variant, &to_tokens(&bindings));
+        let len = variant.shapes.iter().map(FieldShape::len).sum::<u64>() + 1;
+        let fields = variant
+            .shapes
+            .iter()
+            .zip(&bindings)
+            .map(|(shape, binding)| encode_field(shape, binding));
+
+        quote! {
+            #pattern => {
+                e.array(#len)?.u16(#label)?;
+                #(#fields)*
+            }
+        }
+    });
+
+    let name = &input.ident;
+    let generics = bound_generics(
+        &input.generics,
+        &variants,
+        &parse_quote!(::pallas_machines::EncodePayload),
+    );
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::pallas_machines::EncodePayload for #name #ty_generics #where_clause {
+            fn encode_payload(
+                &self,
+                e: &mut ::pallas_machines::PayloadEncoder,
+            ) -> Result<(), ::pallas_machines::CodecError> {
+                match self {
+                    #(#arms)*
+                }
+
+                Ok(())
+            }
+        }
+    })
+}
+
+fn expand_decode(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let variants = labeled_variants(input)?;
+
+    let arms = variants.iter().map(|variant| {
+        let bindings = field_bindings(variant.fields);
+        let pattern = variant_pattern(variant, &to_tokens(&bindings));
+        let label = variant.label;
+
+        if let Some(none) = &variant.none {
+            let binding = &bindings[0];
+            let none_label = none.label;
+            let some_len = check_len(2);
+            let none_len = check_len(1);
+
+            return quote! {
+                #label => {
+                    #some_len
+                    let #binding = ::core::option::Option::Some(
+                        ::pallas_machines::DecodePayload::decode_payload(d)?,
+                    );
+                    #pattern
+                }
+                #none_label => {
+                    #none_len
+                    let #binding = ::core::option::Option::None;
+                    #pattern
+                }
+            };
+        }
+
+        let len = check_len(variant.shapes.iter().map(FieldShape::len).sum::<u64>() + 1);
+        let fields = variant
+            .shapes
+            .iter()
+            .zip(&bindings)
+            .map(|(shape, binding)| decode_field(shape, binding));
+
+        quote! {
+            #label => {
+                #len
+                #(#fields)*
+                #pattern
+            }
+        }
+    });
+
+    let name = &input.ident;
+    let generics = bound_generics(
+        &input.generics,
+        &variants,
+        &parse_quote!(::pallas_machines::DecodePayload),
+    );
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::pallas_machines::DecodePayload for #name #ty_generics #where_clause {
+            fn decode_payload(
+                d: &mut ::pallas_machines::PayloadDecoder,
+            ) -> Result<Self, ::pallas_machines::CodecError> {
+                let array_len = d.array()?;
+
+                let msg = match d.u16()? {
+                    #(#arms)*
+                    x => return Err(::pallas_machines::CodecError::BadLabel(x)),
+                };
+
+                ::pallas_machines::decode_array_end(d, array_len)?;
+
+                Ok(msg)
+            }
+        }
+    })
+}
+
+fn to_tokens(bindings: &[Ident]) -> Vec<TokenStream2> {
+    bindings.iter().map(|b| b.to_token_stream()).collect()
+}
+
+/// Derives `EncodePayload` for an enum whose variants are annotated with
+/// `#[label(n)]`, encoding each one as `[n, fields...]`
+///
+/// A variant holding a single `Option<T>` field can be annotated with
+/// `#[label(n, none = m)]` to be encoded as `[m]` when the field is `None`.
+/// Fields annotated with `#[wrapped]` are byte vectors encoded as tag 24
+/// wrapped CBOR, tuple fields annotated with `#[flatten]` add each of their
+/// elements to the array of the message.
+#[proc_macro_derive(EncodePayload, attributes(label, wrapped, flatten))]
+pub fn derive_encode_payload(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand_encode(&input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Derives `DecodePayload` for an enum whose variants are annotated with
+/// `#[label(n)]`, decoding each one from `[n, fields...]`
+///
+/// Takes the same attributes as the `EncodePayload` derive.
+#[proc_macro_derive(DecodePayload, attributes(label, wrapped, flatten))]
+pub fn derive_decode_payload(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand_decode(&input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
@@ -0,0 +1,161 @@
+use minicbor::{Decoder, Encoder};
+use pallas_machines::{to_payload, CodecError, DecodePayload, EncodePayload, PayloadDecoder};
+
+#[derive(Debug, PartialEq, EncodePayload, DecodePayload)]
+enum Message<C>
+where
+    C: EncodePayload + DecodePayload,
+{
+    #[label(0)]
+    Ping,
+    #[label(3)]
+    Content(C, u64),
+    #[label(7)]
+    Range { from: u64, to: u32 },
+    #[label(9)]
+    Named(Vec<String>),
+}
+
+fn decode(payload: &[u8]) -> Result<Message<Vec<u8>>, CodecError> {
+    let mut d = PayloadDecoder(Decoder::new(payload));
+    Message::decode_payload(&mut d)
+}
+
+fn roundtrip(msg: Message<Vec<u8>>) {
+    let payload = to_payload(&msg).unwrap();
+    assert_eq!(decode(&payload).unwrap(), msg);
+}
+
+#[test]
+fn variants_are_encoded_as_labeled_arrays() {
+    let payload = to_payload(&Message::<Vec<u8>>::Ping).unwrap();
+    assert_eq!(payload, vec![0x81, 0x00]);
+
+    let payload = to_payload(&Message::Content(vec![0xaau8], 5)).unwrap();
+    assert_eq!(payload, vec![0x83, 0x03, 0x41, 0xaa, 0x05]);
+}
+
+#[test]
+fn variants_survive_a_roundtrip() {
+    roundtrip(Message::Ping);
+    roundtrip(Message::Content(vec![1, 2, 3], u64::MAX));
+    roundtrip(Message::Range { from: 1, to: 2 });
+    roundtrip(Message::Named(vec!["a".into(), "b".into()]));
+}
+
+#[test]
+fn unknown_labels_are_rejected() {
+    let mut payload = Vec::new();
+    Encoder::new(&mut payload).array(1).unwrap().u16(4).unwrap();
+
+    assert!(matches!(decode(&payload), Err(CodecError::BadLabel(4))));
+}
+
+#[test]
+fn unexpected_amount_of_items_is_rejected() {
+    let mut payload = Vec::new();
+    let mut e = Encoder::new(&mut payload);
+    e.array(2).unwrap().u16(3).unwrap().bytes(&[0xaa]).unwrap();
+
+    assert!(matches!(
+        decode(&payload),
+        Err(CodecError::UnexpectedCbor(_))
+    ));
+}
+
+#[test]
+fn indefinite_envelopes_are_closed_by_a_break() {
+    // [_ 3, h'aa', 5], followed by the start of the next message
+    let payload = [0x9f, 0x03, 0x41, 0xaa, 0x05, 0xff, 0x81];
+
+    let mut d = PayloadDecoder(Decoder::new(&payload));
+    let msg = Message::<Vec<u8>>::decode_payload(&mut d).unwrap();
+
+    assert_eq!(msg, Message::Content(vec![0xaa], 5));
+    assert_eq!(d.position(), 6);
+
+    // an item beyond the fields of the variant where the break should be
+    let payload = [0x9f, 0x03, 0x41, 0xaa, 0x05, 0x06, 0xff];
+    assert!(matches!(
+        decode(&payload),
+        Err(CodecError::UnexpectedCbor(_))
+    ));
+}
+
+trait Protocol {
+    type Request;
+}
+
+#[derive(Debug, PartialEq)]
+struct Ping;
+
+impl Protocol for Ping {
+    type Request = u32;
+}
+
+#[derive(Debug, PartialEq, EncodePayload, DecodePayload)]
+enum Shaped<P: Protocol> {
+    #[label(0, none = 8)]
+    Acquire(Option<u64>),
+    #[label(1)]
+    Request(P::Request),
+    #[label(2)]
+    Range {
+        #[flatten]
+        range: (u64, u64),
+    },
+    #[label(4)]
+    Block {
+        #[wrapped]
+        body: Vec<u8>,
+    },
+}
+
+fn decode_shaped(payload: &[u8]) -> Result<Shaped<Ping>, CodecError> {
+    let mut d = PayloadDecoder(Decoder::new(payload));
+    Shaped::decode_payload(&mut d)
+}
+
+fn roundtrip_shaped(msg: Shaped<Ping>) {
+    let payload = to_payload(&msg).unwrap();
+    assert_eq!(decode_shaped(&payload).unwrap(), msg);
+}
+
+#[test]
+fn optional_fields_switch_to_the_none_label() {
+    let payload = to_payload(&Shaped::<Ping>::Acquire(Some(3))).unwrap();
+    assert_eq!(payload, vec![0x82, 0x00, 0x03]);
+
+    let payload = to_payload(&Shaped::<Ping>::Acquire(None)).unwrap();
+    assert_eq!(payload, vec![0x81, 0x08]);
+
+    roundtrip_shaped(Shaped::Acquire(Some(3)));
+    roundtrip_shaped(Shaped::Acquire(None));
+}
+
+#[test]
+fn flattened_tuples_are_items_of_the_message() {
+    let payload = to_payload(&Shaped::<Ping>::Range { range: (1, 2) }).unwrap();
+    assert_eq!(payload, vec![0x83, 0x02, 0x01, 0x02]);
+
+    roundtrip_shaped(Shaped::Range { range: (1, 2) });
+}
+
+#[test]
+fn wrapped_bytes_are_tagged_as_cbor() {
+    let payload = to_payload(&Shaped::<Ping>::Block { body: vec![0xf6] }).unwrap();
+    assert_eq!(payload, vec![0x82, 0x04, 0xd8, 0x18, 0x41, 0xf6]);
+
+    roundtrip_shaped(Shaped::Block { body: vec![0xf6] });
+
+    let mut payload = Vec::new();
+    let mut e = Encoder::new(&mut payload);
+    e.array(2).unwrap().u16(4).unwrap().bytes(&[0xf6]).unwrap();
+
+    assert!(decode_shaped(&payload).is_err());
+}
+
+#[test]
+fn associated_types_of_parameters_are_bounded() {
+    roundtrip_shaped(Shaped::Request(42));
+}
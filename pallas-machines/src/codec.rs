@@ -1,19 +1,118 @@
+use minicbor::data::{Tag, Type};
+
 use super::payloads::*;
 use super::primitives::*;
+use super::CodecError;
 
+/// The origin is encoded as an empty array, other points as `[slot, hash]`
 impl EncodePayload for Point {
-    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), Box<dyn std::error::Error>> {
-        e.array(2)?.u64(self.0)?.bytes(&self.1)?;
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
+        match self {
+            Point::Origin => {
+                e.array(0)?;
+            }
+            Point::Specific(slot, hash) => {
+                e.array(2)?.u64(*slot)?.bytes(hash)?;
+            }
+        }
+
         Ok(())
     }
 }
 
 impl DecodePayload for Point {
-    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, Box<dyn std::error::Error>> {
-        d.array()?;
-        let slot = d.u64()?;
-        let hash = d.bytes()?;
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
+        match d.array()? {
+            Some(0) => Ok(Point::Origin),
+            Some(2) => {
+                let slot = d.u64()?;
+                let hash = Hash32::try_from(d.bytes()?)
+                    .map_err(|_| CodecError::UnexpectedCbor("expecting 32 bytes point hash"))?;
+
+                Ok(Point::Specific(slot, hash))
+            }
+            _ => Err(CodecError::UnexpectedCbor(
+                "unexpected amount of items for point",
+            )),
+        }
+    }
+}
+
+/// Implements the payload codec of a primitive using the matching methods
+/// of the CBOR encoder and decoder
+macro_rules! primitive_payload {
+    ($type:ty, $method:ident) => {
+        impl EncodePayload for $type {
+            fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
+                e.$method(*self)?;
+                Ok(())
+            }
+        }
+
+        impl DecodePayload for $type {
+            fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
+                Ok(d.$method()?)
+            }
+        }
+    };
+}
+
+primitive_payload!(bool, bool);
+primitive_payload!(u16, u16);
+primitive_payload!(u32, u32);
+primitive_payload!(u64, u64);
+
+impl EncodePayload for String {
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
+        e.str(self)?;
+        Ok(())
+    }
+}
+
+impl DecodePayload for String {
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
+        Ok(d.str()?.to_string())
+    }
+}
+
+/// Byte vectors map to CBOR byte strings instead of arrays of numbers
+impl EncodePayload for Vec<u8> {
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
+        e.bytes(self)?;
+        Ok(())
+    }
+}
+
+impl DecodePayload for Vec<u8> {
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
+        match d.datatype()? {
+            // indefinite-length byte strings come in chunks
+            Type::BytesIndef => {
+                let mut output = Vec::new();
+
+                for chunk in d.bytes_iter()? {
+                    output.extend_from_slice(chunk?);
+                }
+
+                Ok(output)
+            }
+            _ => Ok(d.bytes()?.to_vec()),
+        }
+    }
+}
+
+/// Encodes bytes holding CBOR data of their own wrapped in a tag 24, the way
+/// the mini-protocols carry block bodies
+pub fn encode_wrapped_bytes(e: &mut PayloadEncoder, bytes: &[u8]) -> Result<(), CodecError> {
+    e.tag(Tag::Cbor)?.bytes(bytes)?;
+    Ok(())
+}
 
-        Ok(Point(slot, Vec::from(hash)))
+/// Decodes the bytes of CBOR data wrapped in a tag 24, without decoding the
+/// data itself
+pub fn decode_wrapped_bytes(d: &mut PayloadDecoder) -> Result<Vec<u8>, CodecError> {
+    match d.tag()? {
+        Tag::Cbor => Vec::<u8>::decode_payload(d),
+        _ => Err(CodecError::UnexpectedCbor("expecting tag 24 wrapped cbor")),
     }
 }
@@ -0,0 +1,394 @@
+use std::{
+    cell::RefCell,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use log::{debug, trace};
+use pallas_multiplexer::{Channel, DemuxReceiver, MuxSender, Payload};
+
+use crate::{
+    to_payload, Agent, DecodePayload, EncodePayload, Exchange, MachineError, MachineOutput,
+    PayloadDeconstructor, PipelinedAgent, Reply, Responder, StateTransition, Transition,
+    TransitionObserver,
+};
+
+/// The `shortWait` time limit of the Ouroboros network spec
+pub const SHORT_WAIT: Duration = Duration::from_secs(10);
+
+/// The `longWait` time limit of the Ouroboros network spec
+pub const LONG_WAIT: Duration = Duration::from_secs(60);
+
+/// How often a driver waiting for the peer checks if it was cancelled
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A handle to stop running agents from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the agents driven with this token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Runs a responder as an agent, lending it the handler on each send
+pub(crate) struct Serving<'h, R, H> {
+    pub(crate) responder: R,
+    pub(crate) handler: &'h mut H,
+}
+
+impl<'h, R: Debug, H> Debug for Serving<'h, R, H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.responder.fmt(f)
+    }
+}
+
+impl<'h, R, H> Agent for Serving<'h, R, H>
+where
+    R: Responder<H> + Debug,
+{
+    type Message = R::Message;
+
+    fn is_done(&self) -> bool {
+        self.responder.is_done()
+    }
+
+    fn has_agency(&self) -> bool {
+        self.responder.has_agency()
+    }
+
+    fn send_next(self, tx: &impl MachineOutput) -> Transition<Self> {
+        let Serving { responder, handler } = self;
+        let responder = responder.send_next(tx, handler)?;
+
+        Ok(Serving { responder, handler })
+    }
+
+    fn receive_next(self, msg: Self::Message) -> Transition<Self> {
+        let responder = self.responder.receive_next(msg)?;
+
+        Ok(Serving { responder, ..self })
+    }
+
+    fn state_timeout(&self) -> Option<Duration> {
+        self.responder.state_timeout()
+    }
+
+    fn state_name(&self) -> String {
+        self.responder.state_name()
+    }
+}
+
+/// Sends the messages of the agent, keeping a description of them while
+/// transitions are observed
+struct TracedOutput<'a> {
+    tx: &'a MuxSender,
+    sent: Option<RefCell<Vec<String>>>,
+}
+
+impl<'a> TracedOutput<'a> {
+    fn new(tx: &'a MuxSender, tracing: bool) -> Self {
+        Self {
+            tx,
+            sent: tracing.then(RefCell::default),
+        }
+    }
+
+    fn take_sent(&self) -> Option<Exchange> {
+        self.sent.as_ref().map(|sent| Exchange::Sent(sent.take()))
+    }
+}
+
+impl<'a> MachineOutput for TracedOutput<'a> {
+    fn send_msg(&self, data: &impl EncodePayload) -> Result<(), MachineError> {
+        let payload = to_payload(data)?;
+
+        if let Some(sent) = &self.sent {
+            let described = minicbor::display(&payload).to_string();
+            sent.borrow_mut().push(described);
+        }
+
+        self.tx.send(payload)?;
+
+        Ok(())
+    }
+}
+
+/// Runs agents and responders until they're done, bounding how long they
+/// wait for the peer
+///
+/// While the peer has agency, the driver waits at most the time limit the
+/// agent reports for its current state (see [Agent::state_timeout]) and fails
+/// with [MachineError::Timeout] once it elapses. Cancelling the [CancelToken]
+/// of the driver stops the agent with [MachineError::Cancelled] the next time
+/// it sends or waits for a message.
+///
+/// Either way the agent is out of sync with the peer afterwards, so the
+/// protocol channel shouldn't be used to run another agent.
+///
+/// Each transition of the agent can be reported to a [TransitionObserver],
+/// see [Driver::with_observer].
+#[derive(Clone, Default)]
+pub struct Driver {
+    cancel: Option<CancelToken>,
+    ignore_time_limits: bool,
+    observer: Option<Arc<dyn TransitionObserver>>,
+}
+
+impl Debug for Driver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Driver")
+            .field("cancel", &self.cancel)
+            .field("ignore_time_limits", &self.ignore_time_limits)
+            .field("observed", &self.observer.is_some())
+            .finish()
+    }
+}
+
+impl Driver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_cancel_token(self, token: CancelToken) -> Self {
+        Self {
+            cancel: Some(token),
+            ..self
+        }
+    }
+
+    /// Waits for the peer without a time limit, regardless of the state (eg:
+    /// for node-to-client protocols, which the spec doesn't bound)
+    pub fn without_time_limits(self) -> Self {
+        Self {
+            ignore_time_limits: true,
+            ..self
+        }
+    }
+
+    /// Reports every transition of the agents to the observer
+    pub fn with_observer(self, observer: Arc<dyn TransitionObserver>) -> Self {
+        Self {
+            observer: Some(observer),
+            ..self
+        }
+    }
+
+    fn tracing(&self) -> bool {
+        self.observer.is_some()
+    }
+
+    /// Reports a transition if there's an observer, restarting the clock of
+    /// the time spent in the state
+    fn observe(
+        &self,
+        from: Option<String>,
+        exchange: Option<Exchange>,
+        to: impl FnOnce() -> String,
+        entered: &mut Instant,
+    ) {
+        if let (Some(observer), Some(from), Some(exchange)) = (&self.observer, from, exchange) {
+            observer.on_transition(&StateTransition {
+                from,
+                exchange,
+                to: to(),
+                elapsed: entered.elapsed(),
+            });
+        }
+
+        *entered = Instant::now();
+    }
+
+    fn check_cancelled(&self) -> Result<(), MachineError> {
+        match &self.cancel {
+            Some(token) if token.is_cancelled() => Err(MachineError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    fn fetch_payload(
+        &self,
+        rx: &DemuxReceiver,
+        limit: Option<(Duration, Instant)>,
+    ) -> Result<Payload, MachineError> {
+        loop {
+            self.check_cancelled()?;
+
+            let wait = match (limit, &self.cancel) {
+                (None, None) => return Ok(rx.recv()??),
+                (None, Some(_)) => CANCEL_CHECK_INTERVAL,
+                (Some((_, deadline)), None) => deadline.saturating_duration_since(Instant::now()),
+                (Some((_, deadline)), Some(_)) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(CANCEL_CHECK_INTERVAL),
+            };
+
+            match rx.recv_timeout(wait) {
+                Ok(egress) => return Ok(egress?),
+                Err(RecvTimeoutError::Disconnected) => return Err(MachineError::ChannelClosed),
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some((limit, deadline)) = limit {
+                        if Instant::now() >= deadline {
+                            return Err(MachineError::Timeout(limit));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits for the next message, for at most the time limit if any
+    fn receive<M: DecodePayload + Debug>(
+        &self,
+        input: &mut PayloadDeconstructor,
+        time_limit: Option<Duration>,
+    ) -> Result<M, MachineError> {
+        // the limit applies to the whole message, even if it spans several
+        // segments
+        let limit = match self.ignore_time_limits {
+            true => None,
+            false => time_limit.map(|limit| (limit, Instant::now() + limit)),
+        };
+
+        let msg = input.consume_with::<M, _>(|rx| self.fetch_payload(rx, limit))?;
+        trace!("procesing inbound msg: {:?}", msg);
+
+        Ok(msg)
+    }
+
+    pub fn run<T: Agent + Debug>(
+        &self,
+        agent: T,
+        channel: &mut Channel,
+    ) -> Result<T, MachineError> {
+        let Channel(tx, rx) = channel;
+
+        let output = TracedOutput::new(tx, self.tracing());
+        let mut input = PayloadDeconstructor::new(rx);
+
+        let mut agent = agent;
+        let mut entered = Instant::now();
+
+        while !agent.is_done() {
+            self.check_cancelled()?;
+
+            debug!("evaluating agent {:?}", agent);
+
+            let from = self.tracing().then(|| agent.state_name());
+
+            let exchange = match agent.has_agency() {
+                true => {
+                    agent = agent.send_next(&output)?;
+                    output.take_sent()
+                }
+                false => {
+                    let msg = self.receive::<T::Message>(&mut input, agent.state_timeout())?;
+                    let exchange = self
+                        .tracing()
+                        .then(|| Exchange::Received(format!("{:?}", msg)));
+                    agent = agent.receive_next(msg)?;
+                    exchange
+                }
+            };
+
+            self.observe(from, exchange, || agent.state_name(), &mut entered);
+        }
+
+        Ok(agent)
+    }
+
+    /// Runs a responder until it's done, pulling its replies from the handler
+    pub fn serve<H, R>(
+        &self,
+        responder: R,
+        handler: &mut H,
+        channel: &mut Channel,
+    ) -> Result<R, MachineError>
+    where
+        R: Responder<H> + Debug,
+    {
+        let serving = Serving { responder, handler };
+        let serving = self.run(serving, channel)?;
+
+        Ok(serving.responder)
+    }
+
+    /// Runs a pipelined agent until it's done, keeping up to `depth` requests
+    /// waiting for a reply
+    ///
+    /// Requests are sent as long as the agent has any and the pipeline has
+    /// room, otherwise the driver waits for the next reply. It also stops
+    /// once the agent has nothing to send and no replies to wait for.
+    pub fn run_pipelined<T: PipelinedAgent + Debug>(
+        &self,
+        agent: T,
+        depth: usize,
+        channel: &mut Channel,
+    ) -> Result<T, MachineError> {
+        let Channel(tx, rx) = channel;
+
+        let output = TracedOutput::new(tx, self.tracing());
+        let mut input = PayloadDeconstructor::new(rx);
+
+        let mut agent = agent;
+        let mut outstanding = 0;
+        let mut entered = Instant::now();
+
+        while !agent.is_done() {
+            self.check_cancelled()?;
+
+            debug!(
+                "evaluating pipelined agent {:?} ({} outstanding)",
+                agent, outstanding
+            );
+
+            let from = self.tracing().then(|| agent.state_name());
+
+            if outstanding < depth.max(1) && agent.has_request() {
+                agent = agent.send_request(&output)?;
+                outstanding += 1;
+
+                let exchange = output.take_sent();
+                self.observe(from, exchange, || agent.state_name(), &mut entered);
+                continue;
+            }
+
+            if outstanding == 0 {
+                debug!("agent has nothing to send nor to wait for");
+                break;
+            }
+
+            let msg = self.receive::<T::Message>(&mut input, agent.reply_timeout())?;
+            let exchange = self
+                .tracing()
+                .then(|| Exchange::Received(format!("{:?}", msg)));
+
+            agent = match agent.receive_reply(msg)? {
+                Reply::Partial(agent) => agent,
+                Reply::Complete(agent) => {
+                    outstanding -= 1;
+                    agent
+                }
+            };
+
+            self.observe(from, exchange, || agent.state_name(), &mut entered);
+        }
+
+        Ok(agent)
+    }
+}
@@ -1,56 +1,130 @@
 mod codec;
+mod driver;
 mod payloads;
 pub mod primitives;
+pub mod testing;
+mod transitions;
 
-use log::{debug, trace};
-use pallas_multiplexer::{Channel, Payload};
+use pallas_multiplexer::{Channel, MuxError, MuxSender};
 use std::borrow::Borrow;
 use std::fmt::{Debug, Display};
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::RecvError;
+use std::time::Duration;
 
+pub use codec::{decode_wrapped_bytes, encode_wrapped_bytes};
+pub use driver::{CancelToken, Driver, LONG_WAIT, SHORT_WAIT};
+pub use pallas_machines_derive::{DecodePayload, EncodePayload};
 pub use payloads::*;
+pub use transitions::*;
 
+/// Failures of a state machine, grouped by kind so that callers can decide
+/// whether to reconnect, resync or abort
 #[derive(Debug)]
-pub enum MachineError<State, Msg>
-where
-    State: Debug,
-    Msg: Debug,
-{
-    InvalidMsgForState(State, Msg),
+pub enum MachineError {
+    /// The peer sent a message that isn't valid in the current state
+    InvalidMsgForState { state: String, msg: String },
+    /// A message couldn't be encoded or decoded
+    Codec(CodecError),
+    /// The multiplexer connection ended or failed
+    Mux(MuxError),
+    /// The protocol channel was disconnected from the multiplexer
+    ChannelClosed,
+    /// The peer didn't reply within the time limit of the current state
+    Timeout(Duration),
+    /// The agent was stopped through its [CancelToken]
+    Cancelled,
+    /// A failure reported by application code (eg: the observer of a
+    /// chain-sync consumer or the provider of a server)
+    External(Box<dyn std::error::Error + Send + Sync>),
 }
 
-impl<S, M> Display for MachineError<S, M>
-where
-    S: Debug,
-    M: Debug,
-{
+impl MachineError {
+    pub fn invalid_msg<S: Debug, M: Debug>(state: S, msg: M) -> Self {
+        MachineError::InvalidMsgForState {
+            state: format!("{:?}", state),
+            msg: format!("{:?}", msg),
+        }
+    }
+}
+
+impl Display for MachineError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            MachineError::InvalidMsgForState(msg, state) => {
+            MachineError::InvalidMsgForState { state, msg } => {
                 write!(
                     f,
-                    "received invalid message ({:?}) for current state ({:?})",
+                    "received invalid message ({}) for current state ({})",
                     msg, state
                 )
             }
+            MachineError::Codec(err) => write!(f, "codec error: {}", err),
+            MachineError::Mux(err) => write!(f, "multiplexer error: {}", err),
+            MachineError::ChannelClosed => write!(f, "protocol channel closed"),
+            MachineError::Timeout(limit) => {
+                write!(f, "peer didn't reply within {:?}", limit)
+            }
+            MachineError::Cancelled => write!(f, "agent cancelled"),
+            MachineError::External(err) => write!(f, "{}", err),
         }
     }
 }
 
-impl<S, M> std::error::Error for MachineError<S, M>
-where
-    S: Debug,
-    M: Debug,
-{
+impl std::error::Error for MachineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MachineError::Codec(err) => Some(err),
+            MachineError::Mux(err) => Some(err),
+            MachineError::External(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<CodecError> for MachineError {
+    fn from(err: CodecError) -> Self {
+        MachineError::Codec(err)
+    }
+}
+
+impl From<MuxError> for MachineError {
+    fn from(err: MuxError) -> Self {
+        MachineError::Mux(err)
+    }
+}
+
+impl From<RecvError> for MachineError {
+    fn from(_: RecvError) -> Self {
+        MachineError::ChannelClosed
+    }
+}
+
+impl From<Box<dyn std::error::Error + Send + Sync>> for MachineError {
+    fn from(err: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        MachineError::External(err)
+    }
 }
 
 #[derive(Debug)]
 pub enum CodecError {
     BadLabel(u16),
     UnexpectedCbor(&'static str),
+    /// The underlying CBOR decoder failed
+    Decoding(minicbor::decode::Error),
+    /// The underlying CBOR encoder failed
+    Encoding(minicbor::encode::Error<std::io::Error>),
+    /// An inbound message grew beyond the provided max size, in bytes
+    MessageTooLarge(usize),
 }
 
-impl std::error::Error for CodecError {}
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CodecError::Decoding(err) => Some(err),
+            CodecError::Encoding(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl Display for CodecError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -61,16 +135,33 @@ impl Display for CodecError {
             CodecError::UnexpectedCbor(msg) => {
                 write!(f, "unexpected cbor: {}", msg)
             }
+            CodecError::Decoding(err) => write!(f, "error decoding cbor: {}", err),
+            CodecError::Encoding(err) => write!(f, "error encoding cbor: {}", err),
+            CodecError::MessageTooLarge(max) => {
+                write!(f, "message exceeds the max size of {} bytes", max)
+            }
         }
     }
 }
 
+impl From<minicbor::decode::Error> for CodecError {
+    fn from(err: minicbor::decode::Error) -> Self {
+        CodecError::Decoding(err)
+    }
+}
+
+impl From<minicbor::encode::Error<std::io::Error>> for CodecError {
+    fn from(err: minicbor::encode::Error<std::io::Error>) -> Self {
+        CodecError::Encoding(err)
+    }
+}
+
 pub trait MachineOutput {
-    fn send_msg(&self, data: &impl EncodePayload) -> Result<(), Box<dyn std::error::Error>>;
+    fn send_msg(&self, data: &impl EncodePayload) -> Result<(), MachineError>;
 }
 
-impl MachineOutput for Sender<Payload> {
-    fn send_msg(&self, data: &impl EncodePayload) -> Result<(), Box<dyn std::error::Error>> {
+impl MachineOutput for MuxSender {
+    fn send_msg(&self, data: &impl EncodePayload) -> Result<(), MachineError> {
         let payload = to_payload(data.borrow())?;
         self.send(payload)?;
 
@@ -78,7 +169,7 @@ impl MachineOutput for Sender<Payload> {
     }
 }
 
-pub type Transition<T> = Result<T, Box<dyn std::error::Error>>;
+pub type Transition<T> = Result<T, MachineError>;
 
 pub trait Agent: Sized {
     type Message: DecodePayload + Debug;
@@ -87,35 +178,132 @@ pub trait Agent: Sized {
     fn has_agency(&self) -> bool;
     fn send_next(self, tx: &impl MachineOutput) -> Transition<Self>;
     fn receive_next(self, msg: Self::Message) -> Transition<Self>;
+
+    /// How long to wait for the peer while it has agency in the current
+    /// state, `None` meaning there's no limit. Enforced by the [Driver].
+    fn state_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Describes the current state when reporting transitions to a
+    /// [TransitionObserver], the whole agent by default
+    fn state_name(&self) -> String
+    where
+        Self: Debug,
+    {
+        format!("{:?}", self)
+    }
 }
 
-pub fn run_agent<T: Agent + Debug>(
-    agent: T,
-    channel: &mut Channel,
-) -> Result<T, Box<dyn std::error::Error>> {
-    let Channel(tx, rx) = channel;
+/// The outcome of processing a reply of a pipelined request
+pub enum Reply<T> {
+    /// The request expects further replies
+    Partial(T),
+    /// The reply completes the oldest outstanding request
+    Complete(T),
+}
+
+/// An agent that sends requests ahead of the replies of the previous ones
+///
+/// Pipelining hides the latency of the link for protocols where the client
+/// makes many requests in a row (eg: chain-sync `RequestNext`). The
+/// [Driver] sends requests while the pipeline has room and tracks how many
+/// of them are still waiting for a reply, replies always arrive in the
+/// order of the requests.
+pub trait PipelinedAgent: Sized {
+    type Message: DecodePayload + Debug;
 
-    let mut input = PayloadDeconstructor {
-        rx,
-        remaining: Vec::new(),
-    };
+    fn is_done(&self) -> bool;
 
-    let mut agent = agent;
+    /// Whether the agent has a request to send right away
+    fn has_request(&self) -> bool;
 
-    while !agent.is_done() {
-        debug!("evaluating agent {:?}", agent);
+    fn send_request(self, tx: &impl MachineOutput) -> Transition<Self>;
+    fn receive_reply(self, msg: Self::Message) -> Transition<Reply<Self>>;
 
-        match agent.has_agency() {
-            true => {
-                agent = agent.send_next(tx)?;
-            }
-            false => {
-                let msg = input.consume_next_message::<T::Message>()?;
-                trace!("procesing inbound msg: {:?}", msg);
-                agent = agent.receive_next(msg)?;
-            }
-        }
+    /// How long to wait for the next reply, `None` meaning there's no limit.
+    /// Enforced by the [Driver].
+    fn reply_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Describes the state of the oldest request when reporting transitions to a
+    /// [TransitionObserver], the whole agent by default
+    fn state_name(&self) -> String
+    where
+        Self: Debug,
+    {
+        format!("{:?}", self)
+    }
+}
+
+/// The responder side of a mini-protocol
+///
+/// Works like an [Agent], except that the replies are built out of the data
+/// pulled from a handler provided by the application (eg: the source of the
+/// blocks of a chain-sync server), which is only borrowed while the
+/// responder runs.
+pub trait Responder<H>: Sized {
+    type Message: DecodePayload + Debug;
+
+    fn is_done(&self) -> bool;
+    fn has_agency(&self) -> bool;
+    fn send_next(self, tx: &impl MachineOutput, handler: &mut H) -> Transition<Self>;
+    fn receive_next(self, msg: Self::Message) -> Transition<Self>;
+
+    /// How long to wait for the initiator while it has agency in the current
+    /// state, `None` meaning there's no limit. Enforced by the [Driver].
+    fn state_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Describes the current state when reporting transitions to a
+    /// [TransitionObserver], the whole responder by default
+    fn state_name(&self) -> String
+    where
+        Self: Debug,
+    {
+        format!("{:?}", self)
     }
+}
 
-    Ok(agent)
+/// Runs the agent until it's done, waiting for the peer as long as it takes
+///
+/// Shorthand for `Driver::new().without_time_limits().run(agent, channel)`,
+/// use a [Driver] to apply the time limits of the agent states or to be able
+/// to cancel it.
+pub fn run_agent<T: Agent + Debug>(agent: T, channel: &mut Channel) -> Result<T, MachineError> {
+    Driver::new().without_time_limits().run(agent, channel)
+}
+
+/// Runs the responder until it's done, pulling replies from the handler
+///
+/// Shorthand for `Driver::new().without_time_limits().serve(responder, handler,
+/// channel)`.
+pub fn run_responder<H, R>(
+    responder: R,
+    handler: &mut H,
+    channel: &mut Channel,
+) -> Result<R, MachineError>
+where
+    R: Responder<H> + Debug,
+{
+    Driver::new()
+        .without_time_limits()
+        .serve(responder, handler, channel)
+}
+
+/// Runs the pipelined agent until it's done, with up to `depth` requests in
+/// flight
+///
+/// Shorthand for `Driver::new().without_time_limits().run_pipelined(agent,
+/// depth, channel)`.
+pub fn run_pipelined<T: PipelinedAgent + Debug>(
+    agent: T,
+    depth: usize,
+    channel: &mut Channel,
+) -> Result<T, MachineError> {
+    Driver::new()
+        .without_time_limits()
+        .run_pipelined(agent, depth, channel)
 }
@@ -1,12 +1,14 @@
 use super::*;
 
 use log::debug;
-use minicbor::{Decoder, Encoder};
-use pallas_multiplexer::Payload;
-use std::{
-    ops::{Deref, DerefMut},
-    sync::mpsc::Receiver,
+use minicbor::{
+    data::{Cbor, Type},
+    decode, Decoder, Encoder,
 };
+use pallas_multiplexer::{DemuxReceiver, Payload};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut};
 
 pub struct PayloadEncoder<'a>(Encoder<&'a mut Vec<u8>>);
 
@@ -25,10 +27,7 @@ impl<'a> DerefMut for PayloadEncoder<'a> {
 }
 
 impl<'a> PayloadEncoder<'a> {
-    pub fn encode_payload<T: EncodePayload>(
-        &mut self,
-        t: &T,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn encode_payload<T: EncodePayload>(&mut self, t: &T) -> Result<(), CodecError> {
         t.encode_payload(self)
     }
 }
@@ -50,16 +49,103 @@ impl<'a> DerefMut for PayloadDecoder<'a> {
 }
 
 impl<'a> PayloadDecoder<'a> {
-    pub fn decode_payload<T: DecodePayload>(&mut self) -> Result<T, Box<dyn std::error::Error>> {
+    pub fn decode_payload<T: DecodePayload>(&mut self) -> Result<T, CodecError> {
         T::decode_payload(self)
     }
+
+    /// Takes the bytes of the current CBOR value without decoding them
+    pub fn raw_value(&mut self) -> Result<&'a [u8], CodecError> {
+        let start = self.position();
+        skip_value(&mut self.0)?;
+        let end = self.position();
+
+        // decoding a `Cbor` takes the rest of the input, so it's trimmed to the
+        // end of the current value
+        self.set_position(start);
+        let rest: &'a [u8] = self.0.decode::<Cbor<'a>>()?.into();
+        self.set_position(end);
+
+        Ok(&rest[..end - start])
+    }
+}
+
+/// Max nesting of arrays, maps and tags accepted by [`skip_value`]
+const MAX_NESTING: usize = 256;
+
+/// Moves the decoder past the current CBOR value, checking that it's complete
+///
+/// Unlike `Decoder::skip`, a byte or text string cut short by the end of the
+/// input fails with `EndOfInput` instead of being taken as complete, which
+/// makes it suitable to find out if a buffer holds a whole message.
+pub fn skip_value(d: &mut Decoder) -> Result<(), decode::Error> {
+    skip_nested(d, 0)
+}
+
+fn skip_nested(d: &mut Decoder, depth: usize) -> Result<(), decode::Error> {
+    if depth > MAX_NESTING {
+        return Err(decode::Error::Message("cbor value nested too deep"));
+    }
+
+    match d.datatype()? {
+        Type::Bytes => {
+            d.bytes()?;
+        }
+        Type::BytesIndef => {
+            for chunk in d.bytes_iter()? {
+                chunk?;
+            }
+        }
+        Type::String => {
+            d.str()?;
+        }
+        Type::StringIndef => {
+            for chunk in d.str_iter()? {
+                chunk?;
+            }
+        }
+        Type::Array | Type::ArrayIndef => match d.array()? {
+            Some(len) => {
+                for _ in 0..len {
+                    skip_nested(d, depth + 1)?;
+                }
+            }
+            None => skip_until_break(d, depth)?,
+        },
+        Type::Map | Type::MapIndef => match d.map()? {
+            Some(len) => {
+                for _ in 0..len {
+                    skip_nested(d, depth + 1)?;
+                    skip_nested(d, depth + 1)?;
+                }
+            }
+            None => skip_until_break(d, depth)?,
+        },
+        Type::Tag => {
+            d.tag()?;
+            skip_nested(d, depth + 1)?;
+        }
+        Type::Break => return Err(decode::Error::TypeMismatch(Type::Break, "unexpected break")),
+        _ => d.skip()?,
+    }
+
+    Ok(())
+}
+
+fn skip_until_break(d: &mut Decoder, depth: usize) -> Result<(), decode::Error> {
+    while d.datatype()? != Type::Break {
+        skip_nested(d, depth + 1)?;
+    }
+
+    // the break is a single byte
+    d.set_position(d.position() + 1);
+    Ok(())
 }
 
 pub trait EncodePayload {
-    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), Box<dyn std::error::Error>>;
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError>;
 }
 
-pub fn to_payload(data: &dyn EncodePayload) -> Result<Payload, Box<dyn std::error::Error>> {
+pub fn to_payload(data: &dyn EncodePayload) -> Result<Payload, CodecError> {
     let mut payload = Vec::new();
     let mut encoder = PayloadEncoder(minicbor::encode::Encoder::new(&mut payload));
     data.encode_payload(&mut encoder)?;
@@ -67,11 +153,82 @@ pub fn to_payload(data: &dyn EncodePayload) -> Result<Payload, Box<dyn std::erro
     Ok(payload)
 }
 
+/// Moves past the break that ends an indefinite-length value, which is a
+/// single byte
+fn skip_break(d: &mut PayloadDecoder) {
+    let position = d.position();
+    d.set_position(position + 1);
+}
+
+/// Moves past the end of an array whose items were decoded one by one: there's
+/// nothing to do for a definite length (`len` as returned by the decoder), an
+/// indefinite one must be closed by a break right after the last item
+pub fn decode_array_end(d: &mut PayloadDecoder, len: Option<u64>) -> Result<(), CodecError> {
+    match len {
+        Some(_) => Ok(()),
+        None if d.datatype()? == Type::Break => {
+            skip_break(d);
+            Ok(())
+        }
+        None => Err(CodecError::UnexpectedCbor(
+            "unexpected amount of items for message",
+        )),
+    }
+}
+
+/// Decodes each item of the array at the current position, which might have
+/// a definite or an indefinite length
+fn decode_items<F>(d: &mut PayloadDecoder, mut item: F) -> Result<(), CodecError>
+where
+    F: FnMut(&mut PayloadDecoder) -> Result<(), CodecError>,
+{
+    match d.array()? {
+        Some(len) => {
+            for _ in 0..len {
+                item(d)?;
+            }
+        }
+        None => {
+            while d.datatype()? != Type::Break {
+                item(d)?;
+            }
+
+            skip_break(d);
+        }
+    }
+
+    Ok(())
+}
+
+/// Decodes each entry of the map at the current position, which might have
+/// a definite or an indefinite length
+fn decode_entries<F>(d: &mut PayloadDecoder, mut entry: F) -> Result<(), CodecError>
+where
+    F: FnMut(&mut PayloadDecoder) -> Result<(), CodecError>,
+{
+    match d.map()? {
+        Some(len) => {
+            for _ in 0..len {
+                entry(d)?;
+            }
+        }
+        None => {
+            while d.datatype()? != Type::Break {
+                entry(d)?;
+            }
+
+            skip_break(d);
+        }
+    }
+
+    Ok(())
+}
+
 impl<D> EncodePayload for Vec<D>
 where
     D: EncodePayload,
 {
-    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), Box<dyn std::error::Error>> {
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
         e.array(self.len() as u64)?;
 
         for item in self {
@@ -86,30 +243,156 @@ impl<D> DecodePayload for Vec<D>
 where
     D: DecodePayload,
 {
-    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, Box<dyn std::error::Error>> {
-        let len = d.array()?.ok_or(CodecError::UnexpectedCbor(
-            "expecting definite-length array",
-        ))? as usize;
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
+        // the length isn't trusted to preallocate, it comes from the peer
+        let mut output = Vec::new();
+        decode_items(d, |d| {
+            output.push(D::decode_payload(d)?);
+            Ok(())
+        })?;
+
+        Ok(output)
+    }
+}
+
+impl<K, V> EncodePayload for BTreeMap<K, V>
+where
+    K: EncodePayload,
+    V: EncodePayload,
+{
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
+        e.map(self.len() as u64)?;
+
+        for (key, value) in self {
+            key.encode_payload(e)?;
+            value.encode_payload(e)?;
+        }
+
+        Ok(())
+    }
+}
 
-        let mut output = Vec::<D>::with_capacity(len);
+impl<K, V> DecodePayload for BTreeMap<K, V>
+where
+    K: DecodePayload + Ord,
+    V: DecodePayload,
+{
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
+        let mut output = BTreeMap::new();
+        decode_entries(d, |d| {
+            let key = K::decode_payload(d)?;
+            let value = V::decode_payload(d)?;
+            output.insert(key, value);
+            Ok(())
+        })?;
+
+        Ok(output)
+    }
+}
+
+impl<K, V> EncodePayload for HashMap<K, V>
+where
+    K: EncodePayload,
+    V: EncodePayload,
+{
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
+        e.map(self.len() as u64)?;
 
-        #[allow(clippy::needless_range_loop)]
-        for i in 0..(len - 1) {
-            output[i] = D::decode_payload(d)?;
+        for (key, value) in self {
+            key.encode_payload(e)?;
+            value.encode_payload(e)?;
         }
 
+        Ok(())
+    }
+}
+
+impl<K, V> DecodePayload for HashMap<K, V>
+where
+    K: DecodePayload + Eq + Hash,
+    V: DecodePayload,
+{
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
+        let mut output = HashMap::new();
+        decode_entries(d, |d| {
+            let key = K::decode_payload(d)?;
+            let value = V::decode_payload(d)?;
+            output.insert(key, value);
+            Ok(())
+        })?;
+
         Ok(output)
     }
 }
 
+/// Implements the payload codec of a tuple as a CBOR array with an item per
+/// element
+macro_rules! tuple_payload {
+    ($len:literal, $($name:ident),+) => {
+        impl<$($name: EncodePayload),+> EncodePayload for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
+                let ($($name,)+) = self;
+                e.array($len)?;
+                $($name.encode_payload(e)?;)+
+                Ok(())
+            }
+        }
+
+        impl<$($name: DecodePayload),+> DecodePayload for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
+                let len = d.array()?;
+
+                if matches!(len, Some(len) if len != $len) {
+                    return Err(CodecError::UnexpectedCbor("unexpected amount of items for tuple"));
+                }
+
+                $(let $name = $name::decode_payload(d)?;)+
+
+                if len.is_none() {
+                    if d.datatype()? != Type::Break {
+                        return Err(CodecError::UnexpectedCbor("unexpected amount of items for tuple"));
+                    }
+
+                    skip_break(d);
+                }
+
+                Ok(($($name,)+))
+            }
+        }
+    };
+}
+
+tuple_payload!(2, A, B);
+tuple_payload!(3, A, B, C);
+tuple_payload!(4, A, B, C, D);
+
 pub trait DecodePayload: Sized {
-    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, Box<dyn std::error::Error>>;
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError>;
+}
+
+/// `None` is encoded as CBOR `null`, both `null` and `undefined` decode as
+/// `None`
+impl<T: EncodePayload> EncodePayload for Option<T> {
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
+        match self {
+            Some(value) => value.encode_payload(e),
+            None => {
+                e.null()?;
+                Ok(())
+            }
+        }
+    }
 }
 
 impl<T: DecodePayload> DecodePayload for Option<T> {
-    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, Box<dyn std::error::Error>> {
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
         match d.datatype()? {
-            minicbor::data::Type::Undefined => Ok(None),
+            Type::Null | Type::Undefined => {
+                d.skip()?;
+                Ok(None)
+            }
             _ => {
                 let value = d.decode_payload()?;
                 Ok(Some(value))
@@ -118,39 +401,88 @@ impl<T: DecodePayload> DecodePayload for Option<T> {
     }
 }
 
+/// Default max size of a single inbound message, large enough for a block
+pub const MAX_MESSAGE_SIZE: usize = 2_500_000;
+
+/// Rebuilds whole messages out of the payloads received through a channel
+///
+/// A message might span several segments and a segment might hold several
+/// messages, so inbound bytes are buffered until they hold a complete CBOR
+/// value.
 pub struct PayloadDeconstructor<'a> {
-    pub(crate) rx: &'a mut Receiver<Payload>,
+    pub(crate) rx: &'a mut DemuxReceiver,
     pub(crate) remaining: Vec<u8>,
+    pub(crate) max_message_size: usize,
 }
 
 impl<'a> PayloadDeconstructor<'a> {
-    pub fn consume_next_message<T: DecodePayload>(
-        &mut self,
-    ) -> Result<T, Box<dyn std::error::Error>> {
+    pub fn new(rx: &'a mut DemuxReceiver) -> Self {
+        PayloadDeconstructor {
+            rx,
+            remaining: Vec::new(),
+            max_message_size: MAX_MESSAGE_SIZE,
+        }
+    }
+
+    /// Sets the max amount of bytes to buffer while waiting for a message to
+    /// be complete
+    pub fn with_max_message_size(self, max_message_size: usize) -> Self {
+        Self {
+            max_message_size,
+            ..self
+        }
+    }
+
+    /// Finds out the length of the first message in the buffer, if complete
+    fn complete_message_len(&self) -> Result<Option<usize>, CodecError> {
         if self.remaining.is_empty() {
-            debug!("no remaining payload, fetching next segment");
-            let payload = self.rx.recv()?;
-            self.remaining.extend(payload);
+            return Ok(None);
         }
 
-        let mut decoder = PayloadDecoder(minicbor::Decoder::new(&self.remaining));
+        let mut decoder = Decoder::new(&self.remaining);
 
-        match T::decode_payload(&mut decoder) {
-            Ok(t) => {
-                let new_pos = decoder.position();
-                self.remaining.drain(0..new_pos);
-                debug!("consumed {} from payload buffer", new_pos);
-                Ok(t)
-            }
-            Err(_err) => {
-                //TODO: we need to match EndOfInput kind of errors
+        match skip_value(&mut decoder) {
+            Ok(()) => Ok(Some(decoder.position())),
+            Err(minicbor::decode::Error::EndOfInput) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
 
-                debug!("payload incomplete, fetching next segment");
-                let payload = self.rx.recv()?;
-                self.remaining.extend(payload);
+    /// Blocks until the next message is complete and decodes it
+    ///
+    /// Fails without waiting for more data if the buffered bytes aren't valid
+    /// CBOR or if the message grows beyond the max message size.
+    pub fn consume_next_message<T: DecodePayload>(&mut self) -> Result<T, MachineError> {
+        self.consume_with(|rx| Ok(rx.recv()??))
+    }
+
+    /// Decodes the next message, getting each payload through `fetch`, which
+    /// is free to give up waiting (eg: once a time limit elapses)
+    pub(crate) fn consume_with<T, F>(&mut self, mut fetch: F) -> Result<T, MachineError>
+    where
+        T: DecodePayload,
+        F: FnMut(&DemuxReceiver) -> Result<Payload, MachineError>,
+    {
+        loop {
+            if let Some(len) = self.complete_message_len()? {
+                let mut decoder = PayloadDecoder(Decoder::new(&self.remaining[..len]));
+                let message = T::decode_payload(&mut decoder);
 
-                self.consume_next_message::<T>()
+                // the message is dropped even if it can't be decoded, there's
+                // no point in trying again with the same bytes
+                self.remaining.drain(..len);
+                debug!("consumed {} from payload buffer", len);
+
+                return Ok(message?);
             }
+
+            if self.remaining.len() > self.max_message_size {
+                return Err(CodecError::MessageTooLarge(self.max_message_size).into());
+            }
+
+            debug!("payload incomplete, fetching next segment");
+            let payload = fetch(self.rx)?;
+            self.remaining.extend(payload);
         }
     }
 }
@@ -1,14 +1,115 @@
-use std::fmt::Debug;
+use std::{
+    fmt::{Debug, Display},
+    num::ParseIntError,
+    str::FromStr,
+};
+
+/// The hash of a block header, which identifies the block
+pub type Hash32 = [u8; 32];
 
 /// A point within a chain
-#[derive(Clone)]
-pub struct Point(pub u64, pub Vec<u8>);
+///
+/// Points are ordered by slot, the origin coming before any other point.
+/// They're written as `slot,hash` (eg: `4492799,f8084c61...`), with the hash
+/// in hex, or as `origin`.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Point {
+    /// The start of the chain, before the first block
+    Origin,
+    /// The block with the given hash, at the given slot
+    Specific(u64, Hash32),
+}
+
+impl Point {
+    /// The slot of the point, 0 for the origin
+    pub fn slot_or_default(&self) -> u64 {
+        match self {
+            Point::Origin => 0,
+            Point::Specific(slot, _) => *slot,
+        }
+    }
+
+    /// The hash of the block at the point, `None` for the origin
+    pub fn hash(&self) -> Option<&Hash32> {
+        match self {
+            Point::Origin => None,
+            Point::Specific(_, hash) => Some(hash),
+        }
+    }
+}
 
 impl Debug for Point {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_tuple("Point")
-            .field(&self.0)
-            .field(&hex::encode(&self.1))
-            .finish()
+        match self {
+            Point::Origin => write!(f, "Origin"),
+            Point::Specific(slot, hash) => f
+                .debug_tuple("Specific")
+                .field(slot)
+                .field(&hex::encode(hash))
+                .finish(),
+        }
+    }
+}
+
+impl Display for Point {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Point::Origin => write!(f, "origin"),
+            Point::Specific(slot, hash) => write!(f, "{},{}", slot, hex::encode(hash)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParsePointError {
+    /// The text isn't in `slot,hash` form
+    MissingHash,
+    BadSlot(ParseIntError),
+    BadHash(hex::FromHexError),
+    /// The hash doesn't have 32 bytes, holds the actual amount
+    BadHashLength(usize),
+}
+
+impl Display for ParsePointError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParsePointError::MissingHash => write!(f, "expecting point as slot,hash"),
+            ParsePointError::BadSlot(err) => write!(f, "invalid point slot: {}", err),
+            ParsePointError::BadHash(err) => write!(f, "invalid point hash: {}", err),
+            ParsePointError::BadHashLength(len) => {
+                write!(f, "point hash has {} bytes, expecting 32", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParsePointError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParsePointError::BadSlot(err) => Some(err),
+            ParsePointError::BadHash(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Point {
+    type Err = ParsePointError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if s.eq_ignore_ascii_case("origin") {
+            return Ok(Point::Origin);
+        }
+
+        let (slot, hash) = s.split_once(',').ok_or(ParsePointError::MissingHash)?;
+
+        let slot = slot.trim().parse().map_err(ParsePointError::BadSlot)?;
+        let hash = hex::decode(hash.trim()).map_err(ParsePointError::BadHash)?;
+        let hash = Hash32::try_from(hash.as_slice())
+            .map_err(|_| ParsePointError::BadHashLength(hash.len()))?;
+
+        Ok(Point::Specific(slot, hash))
     }
 }
@@ -0,0 +1,216 @@
+//! Utilities to test agents against a scripted peer, without a node or a
+//! socket
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fmt::{Debug, Display},
+};
+
+use minicbor::Decoder;
+use pallas_multiplexer::Payload;
+
+use crate::{
+    driver::Serving, to_payload, Agent, DecodePayload, EncodePayload, MachineError, MachineOutput,
+    PayloadDecoder, Responder,
+};
+
+/// A [MachineOutput] that keeps the messages sent through it
+#[derive(Debug, Default)]
+pub struct RecordingOutput(RefCell<Vec<Payload>>);
+
+impl RecordingOutput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the messages sent since the last call, in the order they were
+    /// sent
+    pub fn take_sent(&self) -> Vec<Payload> {
+        self.0.take()
+    }
+}
+
+impl MachineOutput for RecordingOutput {
+    fn send_msg(&self, data: &impl EncodePayload) -> Result<(), MachineError> {
+        let payload = to_payload(data)?;
+        self.0.borrow_mut().push(payload);
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Step {
+    /// The peer sends the message to the agent
+    Send(Payload),
+    /// The agent is expected to send the message to the peer
+    Expect(Payload),
+}
+
+/// The ways an agent can stray from the script of a [ScriptedPeer]
+#[derive(Debug)]
+pub enum ScriptError {
+    /// The agent failed while processing the script
+    Agent(MachineError),
+    /// The agent sent a message other than the one expected by the script
+    UnexpectedMessage { expected: Payload, sent: Payload },
+    /// The agent sent a message while the script expected it to wait
+    UnexpectedSend(Payload),
+    /// The agent waited for the peer while the script expected a message
+    MissingMessage(Payload),
+    /// The agent was done before the end of the script, with the amount of
+    /// steps left
+    Unfinished(usize),
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScriptError::Agent(err) => write!(f, "agent failed: {}", err),
+            ScriptError::UnexpectedMessage { expected, sent } => write!(
+                f,
+                "agent sent {} instead of {}",
+                minicbor::display(sent),
+                minicbor::display(expected)
+            ),
+            ScriptError::UnexpectedSend(sent) => {
+                write!(f, "agent sent {} out of turn", minicbor::display(sent))
+            }
+            ScriptError::MissingMessage(expected) => write!(
+                f,
+                "agent waited instead of sending {}",
+                minicbor::display(expected)
+            ),
+            ScriptError::Unfinished(left) => {
+                write!(f, "agent was done with {} steps left", left)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScriptError::Agent(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<MachineError> for ScriptError {
+    fn from(err: MachineError) -> Self {
+        ScriptError::Agent(err)
+    }
+}
+
+/// A peer that plays a fixed sequence of messages against an agent
+///
+/// The script is a list of steps, each one being either a message the peer
+/// sends or a message the agent is expected to send. Running an agent
+/// against the script drives it as the [Driver](crate::Driver) would, until
+/// the script is over or the agent is done, comparing the encoded messages
+/// of the agent with the expected ones.
+///
+/// ```ignore
+/// let consumer = ScriptedPeer::new()
+///     .expects(&Message::<Content>::FindIntersect(points))
+///     .sends(&Message::<Content>::IntersectFound(point, tip))
+///     .run(consumer)?;
+/// ```
+#[derive(Debug, Default)]
+pub struct ScriptedPeer {
+    steps: VecDeque<Step>,
+}
+
+impl ScriptedPeer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn encode(msg: &impl EncodePayload) -> Payload {
+        to_payload(msg).expect("scripted messages should be encodable")
+    }
+
+    /// Adds a message sent by the peer to the script
+    pub fn sends(mut self, msg: &impl EncodePayload) -> Self {
+        self.steps.push_back(Step::Send(Self::encode(msg)));
+        self
+    }
+
+    /// Adds raw bytes sent by the peer to the script, to check how the agent
+    /// deals with malformed messages
+    pub fn sends_raw(mut self, payload: Payload) -> Self {
+        self.steps.push_back(Step::Send(payload));
+        self
+    }
+
+    /// Adds a message the agent is expected to send to the script
+    pub fn expects(mut self, msg: &impl EncodePayload) -> Self {
+        self.steps.push_back(Step::Expect(Self::encode(msg)));
+        self
+    }
+
+    fn check_sent(&mut self, output: &RecordingOutput) -> Result<(), ScriptError> {
+        for sent in output.take_sent() {
+            match self.steps.pop_front() {
+                Some(Step::Expect(expected)) if expected == sent => (),
+                Some(Step::Expect(expected)) => {
+                    return Err(ScriptError::UnexpectedMessage { expected, sent })
+                }
+                _ => return Err(ScriptError::UnexpectedSend(sent)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn next_inbound<M: DecodePayload>(&mut self) -> Result<M, ScriptError> {
+        match self.steps.pop_front() {
+            Some(Step::Send(payload)) => {
+                let mut decoder = PayloadDecoder(Decoder::new(&payload));
+                let msg = M::decode_payload(&mut decoder).map_err(MachineError::from)?;
+                Ok(msg)
+            }
+            Some(Step::Expect(expected)) => Err(ScriptError::MissingMessage(expected)),
+            None => unreachable!("script is checked for steps before waiting"),
+        }
+    }
+
+    /// Runs the agent through the script, returning it once the script is
+    /// over so that its state can be inspected
+    pub fn run<T: Agent + Debug>(mut self, agent: T) -> Result<T, ScriptError> {
+        let output = RecordingOutput::new();
+        let mut agent = agent;
+
+        while !agent.is_done() && !self.steps.is_empty() {
+            match agent.has_agency() {
+                true => {
+                    agent = agent.send_next(&output)?;
+                    self.check_sent(&output)?;
+                }
+                false => {
+                    let msg = self.next_inbound::<T::Message>()?;
+                    agent = agent.receive_next(msg)?;
+                }
+            }
+        }
+
+        match self.steps.len() {
+            0 => Ok(agent),
+            left => Err(ScriptError::Unfinished(left)),
+        }
+    }
+
+    /// Runs the responder through the script, pulling its replies from the
+    /// handler
+    pub fn serve<H, R>(self, responder: R, handler: &mut H) -> Result<R, ScriptError>
+    where
+        R: Responder<H> + Debug,
+    {
+        let serving = Serving { responder, handler };
+        let serving = self.run(serving)?;
+
+        Ok(serving.responder)
+    }
+}
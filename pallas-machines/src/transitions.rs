@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use log::debug;
+
+/// The messages exchanged by an agent to move from one state to the next
+#[derive(Debug, Clone)]
+pub enum Exchange {
+    /// Messages sent by the agent, in CBOR diagnostic notation since agents
+    /// only hand over their encoded form
+    Sent(Vec<String>),
+    /// The message received from the peer
+    Received(String),
+}
+
+/// A change of state of an agent, reported to the [TransitionObserver] of a
+/// [Driver](crate::Driver)
+#[derive(Debug, Clone)]
+pub struct StateTransition {
+    /// The state before the transition, as described by the agent
+    pub from: String,
+    pub exchange: Exchange,
+    /// The state after the transition, as described by the agent
+    pub to: String,
+    /// Time spent in the previous state, including the wait for the peer
+    pub elapsed: Duration,
+}
+
+/// Receives every transition of the agents run by a [Driver](crate::Driver)
+///
+/// Meant to build protocol-level timelines (eg: to find where a slow sync
+/// session spends its time), the observer is called on the thread running
+/// the agent, right after each transition.
+pub trait TransitionObserver: Send + Sync {
+    fn on_transition(&self, transition: &StateTransition);
+}
+
+/// An observer that logs each transition at debug level
+#[derive(Debug, Default)]
+pub struct TransitionLogger;
+
+impl TransitionObserver for TransitionLogger {
+    fn on_transition(&self, transition: &StateTransition) {
+        match &transition.exchange {
+            Exchange::Sent(msgs) => debug!(
+                "{} -> {} after {:?}, sent {}",
+                transition.from,
+                transition.to,
+                transition.elapsed,
+                msgs.join(", ")
+            ),
+            Exchange::Received(msg) => debug!(
+                "{} -> {} after {:?}, received {}",
+                transition.from, transition.to, transition.elapsed, msg
+            ),
+        }
+    }
+}
@@ -0,0 +1,545 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use minicbor::{Decoder, Encoder};
+
+use pallas_machines::{
+    primitives::Point,
+    run_agent,
+    testing::{ScriptError, ScriptedPeer},
+    to_payload, Agent, CancelToken, CodecError, DecodePayload, Driver, EncodePayload, Exchange,
+    MachineError, MachineOutput, PayloadDecoder, PayloadDeconstructor, StateTransition, Transition,
+    TransitionObserver,
+};
+use pallas_multiplexer::{Channel, MemoryBearer, Multiplexer, MuxSender, Role};
+use rand::{
+    distributions::{Distribution, Standard},
+    rngs::StdRng,
+    Rng, SeedableRng,
+};
+
+/// An agent that waits for a single point from the peer
+#[derive(Debug)]
+struct PointWaiter {
+    limit: Option<Duration>,
+    received: Option<Point>,
+}
+
+impl Agent for PointWaiter {
+    type Message = Point;
+
+    fn is_done(&self) -> bool {
+        self.received.is_some()
+    }
+
+    fn has_agency(&self) -> bool {
+        false
+    }
+
+    fn send_next(self, _: &impl MachineOutput) -> Transition<Self> {
+        unreachable!("the peer always has agency")
+    }
+
+    fn receive_next(self, msg: Self::Message) -> Transition<Self> {
+        Ok(Self {
+            received: Some(msg),
+            ..self
+        })
+    }
+
+    fn state_timeout(&self) -> Option<Duration> {
+        self.limit
+    }
+}
+
+fn setup_channels() -> (Multiplexer, Multiplexer, MuxSender, Channel) {
+    let (client_bearer, server_bearer) = MemoryBearer::pair();
+
+    let mut client = Multiplexer::setup(client_bearer, Role::Initiator, &[2]).unwrap();
+    let mut server = Multiplexer::setup(server_bearer, Role::Responder, &[2]).unwrap();
+
+    let Channel(server_tx, _) = server.use_channel(2).unwrap();
+    let client_channel = client.use_channel(2).unwrap();
+
+    (client, server, server_tx, client_channel)
+}
+
+#[test]
+fn messages_are_rebuilt_across_segments() {
+    let (_client, _server, server_tx, mut channel) = setup_channels();
+
+    let first = to_payload(&Point::Specific(10, [0xaa; 32])).unwrap();
+    let second = to_payload(&Point::Specific(11, [0xbb; 32])).unwrap();
+
+    // first message split in two segments, the second one shares a segment
+    // with the tail of the first
+    let mut tail = first[10..].to_vec();
+    tail.extend(&second);
+    server_tx.send(first[..10].to_vec()).unwrap();
+    server_tx.send(tail).unwrap();
+
+    let mut input = PayloadDeconstructor::new(&mut channel.1);
+
+    let point = input.consume_next_message::<Point>().unwrap();
+    assert_eq!(point.slot_or_default(), 10);
+
+    let point = input.consume_next_message::<Point>().unwrap();
+    assert_eq!(point.slot_or_default(), 11);
+}
+
+#[test]
+fn garbage_fails_instead_of_waiting_for_more_data() {
+    let (_client, _server, server_tx, mut channel) = setup_channels();
+
+    // a break code outside of an indefinite-length value is never valid
+    server_tx.send(vec![0xff, 0x01, 0x02]).unwrap();
+
+    let mut input = PayloadDeconstructor::new(&mut channel.1);
+
+    assert!(matches!(
+        input.consume_next_message::<Point>(),
+        Err(MachineError::Codec(CodecError::Decoding(_)))
+    ));
+}
+
+#[test]
+fn unexpected_message_shape_is_a_codec_error() {
+    let (_client, _server, server_tx, mut channel) = setup_channels();
+
+    // a complete cbor value, but not a point
+    server_tx.send(vec![0x81, 0x01]).unwrap();
+    server_tx
+        .send(to_payload(&Point::Specific(12, [0xcc; 32])).unwrap())
+        .unwrap();
+
+    let mut input = PayloadDeconstructor::new(&mut channel.1);
+
+    assert!(matches!(
+        input.consume_next_message::<Point>(),
+        Err(MachineError::Codec(_))
+    ));
+
+    // the bad message is discarded, next ones are still readable
+    let point = input.consume_next_message::<Point>().unwrap();
+    assert_eq!(point.slot_or_default(), 12);
+}
+
+#[test]
+fn oversized_messages_are_rejected() {
+    let (_client, _server, server_tx, mut channel) = setup_channels();
+
+    // the header of an array of 1000 bytestrings, followed by a few of them
+    let mut payload = vec![0x99, 0x03, 0xe8];
+    payload.extend(
+        [0x58, 0x20]
+            .iter()
+            .chain([0u8; 32].iter())
+            .cycle()
+            .take(34 * 10),
+    );
+    server_tx.send(payload).unwrap();
+
+    let mut input = PayloadDeconstructor::new(&mut channel.1).with_max_message_size(100);
+
+    assert!(matches!(
+        input.consume_next_message::<Point>(),
+        Err(MachineError::Codec(CodecError::MessageTooLarge(100)))
+    ));
+}
+
+#[test]
+fn silent_peer_times_out() {
+    let (_client, _server, _server_tx, mut channel) = setup_channels();
+
+    let agent = PointWaiter {
+        limit: Some(Duration::from_millis(50)),
+        received: None,
+    };
+
+    assert!(matches!(
+        Driver::new().run(agent, &mut channel),
+        Err(MachineError::Timeout(_))
+    ));
+}
+
+#[test]
+fn time_limits_can_be_ignored() {
+    let (_client, _server, server_tx, mut channel) = setup_channels();
+
+    let agent = PointWaiter {
+        limit: Some(Duration::from_millis(10)),
+        received: None,
+    };
+
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        server_tx
+            .send(to_payload(&Point::Specific(13, [0xdd; 32])).unwrap())
+            .unwrap();
+    });
+
+    let agent = Driver::new()
+        .without_time_limits()
+        .run(agent, &mut channel)
+        .unwrap();
+
+    assert!(matches!(agent.received, Some(Point::Specific(13, _))));
+}
+
+#[test]
+fn run_agent_waits_without_time_limits() {
+    let (_client, _server, server_tx, mut channel) = setup_channels();
+
+    let agent = PointWaiter {
+        limit: Some(Duration::from_millis(10)),
+        received: None,
+    };
+
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        server_tx
+            .send(to_payload(&Point::Specific(14, [0xee; 32])).unwrap())
+            .unwrap();
+    });
+
+    let agent = run_agent(agent, &mut channel).unwrap();
+
+    assert!(matches!(agent.received, Some(Point::Specific(14, _))));
+}
+
+#[test]
+fn machine_errors_can_be_sent_across_threads() {
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<MachineError>();
+
+    let external: Box<dyn std::error::Error + Send + Sync> = "provider failed".into();
+    let err = MachineError::from(external);
+
+    let message = thread::spawn(move || err.to_string()).join().unwrap();
+    assert_eq!(message, "provider failed");
+}
+
+#[test]
+fn agents_can_be_cancelled_from_another_thread() {
+    let (_client, _server, _server_tx, mut channel) = setup_channels();
+
+    let token = CancelToken::new();
+    let driver = Driver::new().with_cancel_token(token.clone());
+
+    let agent = PointWaiter {
+        limit: None,
+        received: None,
+    };
+
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        token.cancel();
+    });
+
+    assert!(matches!(
+        driver.run(agent, &mut channel),
+        Err(MachineError::Cancelled)
+    ));
+}
+
+#[test]
+fn scripted_peer_feeds_messages_to_the_agent() {
+    let agent = PointWaiter {
+        limit: None,
+        received: None,
+    };
+
+    let agent = ScriptedPeer::new()
+        .sends(&Point::Specific(14, [0xee; 32]))
+        .run(agent)
+        .unwrap();
+
+    assert!(matches!(agent.received, Some(Point::Specific(14, _))));
+}
+
+#[test]
+fn scripted_peer_reports_unused_steps() {
+    let agent = PointWaiter {
+        limit: None,
+        received: None,
+    };
+
+    let result = ScriptedPeer::new()
+        .sends(&Point::Specific(15, [0xee; 32]))
+        .sends(&Point::Specific(16, [0xee; 32]))
+        .run(agent);
+
+    assert!(matches!(result, Err(ScriptError::Unfinished(1))));
+}
+
+#[test]
+fn scripted_peer_reports_malformed_messages() {
+    let agent = PointWaiter {
+        limit: None,
+        received: None,
+    };
+
+    let result = ScriptedPeer::new().sends_raw(vec![0x81, 0x01]).run(agent);
+
+    assert!(matches!(
+        result,
+        Err(ScriptError::Agent(MachineError::Codec(_)))
+    ));
+}
+
+#[derive(Default)]
+struct Timeline(Mutex<Vec<StateTransition>>);
+
+impl TransitionObserver for Timeline {
+    fn on_transition(&self, transition: &StateTransition) {
+        self.0.lock().unwrap().push(transition.clone());
+    }
+}
+
+#[test]
+fn transitions_are_reported_to_the_observer() {
+    let (_client, _server, server_tx, mut channel) = setup_channels();
+
+    let timeline = Arc::new(Timeline::default());
+    let driver = Driver::new().with_observer(timeline.clone());
+
+    let agent = PointWaiter {
+        limit: None,
+        received: None,
+    };
+
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        server_tx
+            .send(to_payload(&Point::Specific(17, [0x17; 32])).unwrap())
+            .unwrap();
+    });
+
+    driver.run(agent, &mut channel).unwrap();
+
+    let transitions = timeline.0.lock().unwrap();
+    assert_eq!(transitions.len(), 1);
+
+    let transition = &transitions[0];
+    assert!(transition.from.contains("received: None"));
+    assert!(transition.to.contains("received: Some(Specific(17"));
+    assert!(
+        matches!(&transition.exchange, Exchange::Received(msg) if msg.starts_with("Specific(17"))
+    );
+    assert!(transition.elapsed >= Duration::from_millis(20));
+}
+
+fn decode_whole<T: DecodePayload>(payload: &[u8]) -> Result<T, CodecError> {
+    let mut decoder = PayloadDecoder(Decoder::new(payload));
+    let value = T::decode_payload(&mut decoder)?;
+    assert_eq!(
+        decoder.position(),
+        payload.len(),
+        "value not fully consumed"
+    );
+
+    Ok(value)
+}
+
+fn round_trip<T>(value: &T)
+where
+    T: EncodePayload + DecodePayload + PartialEq + Debug,
+{
+    let payload = to_payload(value).unwrap();
+    assert_eq!(&decode_whole::<T>(&payload).unwrap(), value);
+}
+
+fn random_bytes(rng: &mut StdRng) -> Vec<u8> {
+    let len = rng.gen_range(0..40);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+fn random_option<T>(rng: &mut StdRng) -> Option<T>
+where
+    Standard: Distribution<T>,
+{
+    match rng.gen_bool(0.5) {
+        true => Some(rng.gen()),
+        false => None,
+    }
+}
+
+#[test]
+fn containers_round_trip() {
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+
+    for _ in 0..200 {
+        let len = rng.gen_range(0..20);
+
+        let numbers: Vec<u64> = (0..len).map(|_| rng.gen()).collect();
+        round_trip(&numbers);
+
+        let blobs: Vec<Vec<u8>> = (0..len).map(|_| random_bytes(&mut rng)).collect();
+        round_trip(&blobs);
+
+        let names: BTreeMap<u16, String> = (0..len)
+            .map(|_| (rng.gen(), (0..len).map(|_| rng.gen::<char>()).collect()))
+            .collect();
+        round_trip(&names);
+
+        let index: HashMap<u64, Vec<u8>> = (0..len)
+            .map(|_| (rng.gen(), random_bytes(&mut rng)))
+            .collect();
+        round_trip(&index);
+
+        let flags: Vec<bool> = (0..len).map(|_| rng.gen()).collect();
+        let tuple = (rng.gen::<u32>(), random_option::<u64>(&mut rng), flags);
+        round_trip(&tuple);
+
+        let nested: Vec<(u64, BTreeMap<u32, Option<bool>>)> = (0..len)
+            .map(|_| {
+                let entries = (0..len)
+                    .map(|_| (rng.gen(), random_option(&mut rng)))
+                    .collect();
+                (rng.gen(), entries)
+            })
+            .collect();
+        round_trip(&nested);
+    }
+}
+
+#[test]
+fn indefinite_containers_decode_like_definite_ones() {
+    let mut rng = StdRng::seed_from_u64(0x1def);
+
+    for _ in 0..100 {
+        let len = rng.gen_range(0..20);
+        let numbers: Vec<u64> = (0..len).map(|_| rng.gen()).collect();
+        let blob = random_bytes(&mut rng);
+
+        let mut payload = Vec::new();
+        let mut e = Encoder::new(&mut payload);
+        e.begin_array().unwrap();
+        for number in &numbers {
+            e.u64(*number).unwrap();
+        }
+        e.end().unwrap();
+        assert_eq!(decode_whole::<Vec<u64>>(&payload).unwrap(), numbers);
+
+        let mut payload = Vec::new();
+        let mut e = Encoder::new(&mut payload);
+        e.begin_map().unwrap();
+        for number in &numbers {
+            e.u64(*number).unwrap().bool(number % 2 == 0).unwrap();
+        }
+        e.end().unwrap();
+        let expected: BTreeMap<u64, bool> = numbers.iter().map(|n| (*n, n % 2 == 0)).collect();
+        assert_eq!(
+            decode_whole::<BTreeMap<u64, bool>>(&payload).unwrap(),
+            expected
+        );
+
+        let mut payload = Vec::new();
+        let mut e = Encoder::new(&mut payload);
+        e.begin_bytes().unwrap();
+        for chunk in blob.chunks(7) {
+            e.bytes(chunk).unwrap();
+        }
+        e.end().unwrap();
+        assert_eq!(decode_whole::<Vec<u8>>(&payload).unwrap(), blob);
+
+        let mut payload = Vec::new();
+        let mut e = Encoder::new(&mut payload);
+        e.begin_array().unwrap();
+        e.u16(len).unwrap().bytes(&blob).unwrap();
+        e.end().unwrap();
+        assert_eq!(
+            decode_whole::<(u16, Vec<u8>)>(&payload).unwrap(),
+            (len, blob)
+        );
+    }
+}
+
+#[test]
+fn arrays_keep_every_item() {
+    let decoded = decode_whole::<Vec<u64>>(&[0x83, 0x01, 0x02, 0x03]).unwrap();
+    assert_eq!(decoded, vec![1, 2, 3]);
+}
+
+#[test]
+fn null_and_undefined_decode_as_none() {
+    assert_eq!(decode_whole::<Option<u64>>(&[0xf6]).unwrap(), None);
+    assert_eq!(decode_whole::<Option<u64>>(&[0xf7]).unwrap(), None);
+
+    // the missing value is consumed, the next items are still readable
+    let decoded = decode_whole::<(Option<u64>, u64)>(&[0x82, 0xf6, 0x05]).unwrap();
+    assert_eq!(decoded, (None, 5));
+}
+
+#[test]
+fn tuples_check_their_length() {
+    assert!(matches!(
+        decode_whole::<(u64, u64)>(&[0x83, 0x01, 0x02, 0x03]),
+        Err(CodecError::UnexpectedCbor(_))
+    ));
+
+    assert!(matches!(
+        decode_whole::<(u64, u64)>(&[0x9f, 0x01, 0x02, 0x03, 0xff]),
+        Err(CodecError::UnexpectedCbor(_))
+    ));
+}
+
+#[test]
+fn origin_is_an_empty_array() {
+    assert_eq!(to_payload(&Point::Origin).unwrap(), vec![0x80]);
+    assert_eq!(decode_whole::<Point>(&[0x80]).unwrap(), Point::Origin);
+
+    round_trip(&Point::Specific(42, [0x42; 32]));
+    round_trip(&vec![Point::Origin, Point::Specific(43, [0x43; 32])]);
+
+    // hashes of other sizes aren't valid points
+    let short = [0x82, 0x01, 0x42, 0xaa, 0xbb];
+    assert!(matches!(
+        decode_whole::<Point>(&short),
+        Err(CodecError::UnexpectedCbor(_))
+    ));
+}
+
+#[test]
+fn points_are_ordered_by_slot() {
+    let mut points = vec![
+        Point::Specific(20, [0x00; 32]),
+        Point::Specific(10, [0xff; 32]),
+        Point::Origin,
+        Point::Specific(10, [0x01; 32]),
+    ];
+
+    points.sort();
+
+    assert_eq!(
+        points,
+        vec![
+            Point::Origin,
+            Point::Specific(10, [0x01; 32]),
+            Point::Specific(10, [0xff; 32]),
+            Point::Specific(20, [0x00; 32]),
+        ]
+    );
+}
+
+#[test]
+fn points_are_parsed_from_slot_and_hash() {
+    let text = "43847831,15b9eeee849dd6386d3770b0745e0450190f7560e5159b1b3ab13b14b2684a45";
+
+    let point: Point = text.parse().unwrap();
+    assert_eq!(point.slot_or_default(), 43847831);
+    assert_eq!(point.to_string(), text);
+
+    assert_eq!("origin".parse::<Point>().unwrap(), Point::Origin);
+    assert_eq!(Point::Origin.to_string(), "origin");
+
+    assert!("43847831".parse::<Point>().is_err());
+    assert!("slot,15b9".parse::<Point>().is_err());
+    assert!("43847831,15b9".parse::<Point>().is_err());
+    assert!("43847831,zz".parse::<Point>().is_err());
+}
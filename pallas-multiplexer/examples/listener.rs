@@ -1,9 +1,9 @@
 use std::{net::TcpListener, thread, time::Duration};
 
 use log::info;
-use pallas_multiplexer::{Channel, Multiplexer};
+use pallas_multiplexer::{Channel, Multiplexer, Role};
 
-const PROTOCOLS: [u16; 2] = [0x8002u16, 0x8003u16];
+const PROTOCOLS: [u16; 2] = [0x0002u16, 0x0003u16];
 
 fn main() {
     env_logger::init();
@@ -13,10 +13,10 @@ fn main() {
     info!("listening for connections on port 3001");
     let (bearer, _) = server.accept().unwrap();
 
-    let mut muxer = Multiplexer::setup(bearer, &PROTOCOLS).unwrap();
+    let mut muxer = Multiplexer::setup(bearer, Role::Responder, &PROTOCOLS).unwrap();
 
     for protocol in PROTOCOLS {
-        let handle = muxer.use_channel(protocol);
+        let handle = muxer.use_channel(protocol).unwrap();
 
         thread::spawn(move || {
             info!("starting thread for protocol: {}", protocol);
@@ -24,7 +24,14 @@ fn main() {
             let Channel(_, rx) = handle;
 
             loop {
-                let payload = rx.recv().unwrap();
+                let payload = match rx.recv().unwrap() {
+                    Ok(payload) => payload,
+                    Err(reason) => {
+                        info!("connection ended for protocol {}: {}", protocol, reason);
+                        break;
+                    }
+                };
+
                 info!(
                     "got message within thread, id:{}, length:{}",
                     protocol,
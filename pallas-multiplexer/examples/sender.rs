@@ -1,7 +1,7 @@
 use std::{net::TcpStream, thread, time::Duration};
 
 use log::info;
-use pallas_multiplexer::{Channel, Multiplexer};
+use pallas_multiplexer::{Channel, Multiplexer, Role};
 
 const PROTOCOLS: [u16; 2] = [0x0002u16, 0x0003u16];
 
@@ -10,10 +10,10 @@ fn main() {
 
     info!("connecting to tcp socket on 127.0.0.1:3001");
     let bearer = TcpStream::connect("127.0.0.1:3001").unwrap();
-    let mut muxer = Multiplexer::setup(bearer, &PROTOCOLS).unwrap();
+    let mut muxer = Multiplexer::setup(bearer, Role::Initiator, &PROTOCOLS).unwrap();
 
     for protocol in PROTOCOLS {
-        let handle = muxer.use_channel(protocol);
+        let handle = muxer.use_channel(protocol).unwrap();
 
         thread::spawn(move || {
             let Channel(tx, _) = handle;
@@ -3,9 +3,54 @@ use log::{debug, log_enabled, trace};
 use std::io::{Read, Write};
 #[cfg(target_family = "unix")]
 use std::os::unix::net::UnixStream;
-use std::{net::TcpStream, time::Instant};
+use std::{
+    collections::VecDeque,
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Condvar, Mutex},
+    time::Instant,
+};
 
-use crate::{Bearer, Payload};
+use crate::{Bearer, Mode, Payload};
+
+/// The 8-byte header that precedes the payload of every segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentHeader {
+    /// The lower 32 bits of the sender's clock, in microseconds
+    pub timestamp: u32,
+    /// The mini-protocol number, without the mode bit
+    pub protocol_id: u16,
+    /// The mode of the sender of the segment
+    pub mode: Mode,
+    pub payload_length: u16,
+}
+
+impl SegmentHeader {
+    pub const SIZE: usize = 8;
+
+    pub fn decode(bytes: &[u8; Self::SIZE]) -> Self {
+        let (protocol_id, mode) = Mode::untag(NetworkEndian::read_u16(&bytes[4..6]));
+
+        SegmentHeader {
+            timestamp: NetworkEndian::read_u32(&bytes[0..4]),
+            protocol_id,
+            mode,
+            payload_length: NetworkEndian::read_u16(&bytes[6..]),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        NetworkEndian::write_u32(&mut bytes[0..4], self.timestamp);
+        NetworkEndian::write_u16(&mut bytes[4..6], self.mode.tag(self.protocol_id));
+        NetworkEndian::write_u16(&mut bytes[6..], self.payload_length);
+        bytes
+    }
+
+    /// The protocol id as it travels in the header, including the mode bit
+    pub fn raw_protocol_id(&self) -> u16 {
+        self.mode.tag(self.protocol_id)
+    }
+}
 
 fn write_segment(
     writer: &mut impl Write,
@@ -33,17 +78,18 @@ fn write_segment(
 }
 
 fn read_segment(reader: &mut impl Read) -> Result<(u16, u32, Payload), std::io::Error> {
-    let mut header = [0u8; 8];
+    let mut header = [0u8; SegmentHeader::SIZE];
 
     reader.read_exact(&mut header)?;
 
     if log_enabled!(log::Level::Trace) {
-        trace!("read segment header: {:?}", hex::encode(&header));
+        trace!("read segment header: {:?}", hex::encode(header));
     }
 
-    let length = NetworkEndian::read_u16(&header[6..]) as usize;
-    let id = NetworkEndian::read_u16(&header[4..6]) as usize ^ 0x8000;
-    let ts = NetworkEndian::read_u32(&header[0..4]);
+    let header = SegmentHeader::decode(&header);
+    let length = header.payload_length as usize;
+    let id = header.raw_protocol_id();
+    let ts = header.timestamp;
 
     debug!(
         "parsed inbound msg, protocol id: {}, ts: {}, payload length: {}",
@@ -57,7 +103,7 @@ fn read_segment(reader: &mut impl Read) -> Result<(u16, u32, Payload), std::io::
         trace!("read segment payload: {:?}", hex::encode(&payload));
     }
 
-    Ok((id as u16, ts, payload))
+    Ok((id, ts, payload))
 }
 
 impl Bearer for TcpStream {
@@ -65,6 +111,10 @@ impl Bearer for TcpStream {
         self.try_clone().expect("error cloning tcp stream")
     }
 
+    fn shutdown(&self) -> Result<(), std::io::Error> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
     fn read_segment(&mut self) -> Result<(u16, u32, Payload), std::io::Error> {
         read_segment(self)
     }
@@ -85,6 +135,134 @@ impl Bearer for UnixStream {
         self.try_clone().expect("error cloning unix stream")
     }
 
+    fn shutdown(&self) -> Result<(), std::io::Error> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn read_segment(&mut self) -> Result<(u16, u32, Payload), std::io::Error> {
+        read_segment(self)
+    }
+
+    fn write_segment(
+        &mut self,
+        clock: Instant,
+        protocol_id: u16,
+        partial_payload: &[u8],
+    ) -> Result<(), std::io::Error> {
+        write_segment(self, clock, protocol_id, partial_payload)
+    }
+}
+
+#[derive(Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+/// One direction of an in-memory connection
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    changed: Condvar,
+}
+
+impl Pipe {
+    fn lock(&self) -> std::sync::MutexGuard<'_, PipeState> {
+        self.state.lock().expect("memory pipe lock poisoned")
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+/// An in-process bearer that moves segments through shared memory buffers
+///
+/// Bearers are created in connected pairs, whatever is written into one end
+/// can be read from the other one. Useful for wiring two multiplexers
+/// together without opening sockets (eg: in tests).
+pub struct MemoryBearer {
+    inbound: Arc<Pipe>,
+    outbound: Arc<Pipe>,
+}
+
+impl MemoryBearer {
+    /// Creates two bearers connected to each other
+    pub fn pair() -> (MemoryBearer, MemoryBearer) {
+        let a_to_b = Arc::new(Pipe::default());
+        let b_to_a = Arc::new(Pipe::default());
+
+        let a = MemoryBearer {
+            inbound: b_to_a.clone(),
+            outbound: a_to_b.clone(),
+        };
+
+        let b = MemoryBearer {
+            inbound: a_to_b,
+            outbound: b_to_a,
+        };
+
+        (a, b)
+    }
+}
+
+impl Read for MemoryBearer {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut state = self.inbound.lock();
+
+        while state.data.is_empty() && !state.closed {
+            state = self
+                .inbound
+                .changed
+                .wait(state)
+                .expect("memory pipe lock poisoned");
+        }
+
+        let count = buf.len().min(state.data.len());
+
+        for (target, source) in buf.iter_mut().zip(state.data.drain(..count)) {
+            *target = source;
+        }
+
+        Ok(count)
+    }
+}
+
+impl Write for MemoryBearer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut state = self.outbound.lock();
+
+        if state.closed {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+
+        state.data.extend(buf);
+        self.outbound.changed.notify_all();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Bearer for MemoryBearer {
+    fn clone(&self) -> Self {
+        MemoryBearer {
+            inbound: self.inbound.clone(),
+            outbound: self.outbound.clone(),
+        }
+    }
+
+    fn shutdown(&self) -> Result<(), std::io::Error> {
+        self.inbound.close();
+        self.outbound.close();
+
+        Ok(())
+    }
+
     fn read_segment(&mut self) -> Result<(u16, u32, Payload), std::io::Error> {
         read_segment(self)
     }
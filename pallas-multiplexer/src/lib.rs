@@ -1,16 +1,31 @@
 mod bearers;
+pub mod recording;
+mod stats;
+
+pub use bearers::{MemoryBearer, SegmentHeader};
+pub use stats::{MuxStats, ProtocolStats};
+
+use stats::StatsRecorder;
 
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
-    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvError, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
     thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
 use log::{debug, error, warn};
 
-pub trait Bearer: Read + Write + Send + Sync + Sized {
+/// A transport able to move multiplexer segments between peers
+///
+/// Protocol ids read from or written to a bearer are the raw values of the
+/// segment header, including the mode bit.
+pub trait Bearer: Send + Sync + Sized {
     fn read_segment(&mut self) -> Result<(u16, u32, Payload), std::io::Error>;
 
     fn write_segment(
@@ -21,164 +36,880 @@ pub trait Bearer: Read + Write + Send + Sync + Sized {
     ) -> Result<(), std::io::Error>;
 
     fn clone(&self) -> Self;
+
+    /// Closes both directions of the underlying connection
+    fn shutdown(&self) -> Result<(), std::io::Error>;
+}
+
+/// The reason why a multiplexed connection stopped working
+#[derive(Debug, Clone)]
+pub enum MuxError {
+    /// The remote peer closed the connection
+    Eof,
+    /// The bearer failed with an IO error
+    Io(Arc<std::io::Error>),
+    /// The remote peer didn't follow the multiplexer protocol
+    ProtocolViolation(String),
+    /// The connection was closed locally via [Multiplexer::shutdown]
+    Shutdown,
 }
 
+impl Display for MuxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MuxError::Eof => write!(f, "connection closed by remote peer"),
+            MuxError::Io(err) => write!(f, "bearer io error: {}", err),
+            MuxError::ProtocolViolation(msg) => write!(f, "protocol violation: {}", msg),
+            MuxError::Shutdown => write!(f, "connection shut down locally"),
+        }
+    }
+}
+
+impl std::error::Error for MuxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MuxError::Io(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MuxError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => MuxError::Eof,
+            _ => MuxError::Io(Arc::new(err)),
+        }
+    }
+}
+
+/// Shared record of the first reason that ended the connection
+#[derive(Clone, Default)]
+struct Outcome(Arc<Mutex<Option<MuxError>>>);
+
+impl Outcome {
+    /// Records the reason if none was set yet and returns the settled one
+    fn settle(&self, reason: MuxError) -> MuxError {
+        let mut guard = self.0.lock().expect("outcome lock poisoned");
+        guard.get_or_insert(reason).clone()
+    }
+
+    fn get(&self) -> Option<MuxError> {
+        self.0.lock().expect("outcome lock poisoned").clone()
+    }
+}
+
+/// The largest payload that fits in a segment, given the 16-bit length field
 const MAX_SEGMENT_PAYLOAD_LENGTH: usize = 65535;
 
+/// The bit of the segment protocol id that flags messages sent by a responder
+const MODE_BIT: u16 = 0x8000;
+
+/// The side of a mini-protocol conversation a channel is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Initiator,
+    Responder,
+}
+
+impl Mode {
+    /// The mode of the remote end of the same conversation
+    pub fn peer(&self) -> Mode {
+        match self {
+            Mode::Initiator => Mode::Responder,
+            Mode::Responder => Mode::Initiator,
+        }
+    }
+
+    /// Builds the segment header id for a protocol sent in this mode
+    fn tag(&self, protocol_id: u16) -> u16 {
+        match self {
+            Mode::Initiator => protocol_id,
+            Mode::Responder => protocol_id | MODE_BIT,
+        }
+    }
+
+    /// Splits a segment header id into the protocol id and the sender's mode
+    fn untag(header_id: u16) -> (u16, Mode) {
+        match header_id & MODE_BIT {
+            0 => (header_id, Mode::Initiator),
+            _ => (header_id & !MODE_BIT, Mode::Responder),
+        }
+    }
+}
+
+/// The role the local end plays in the connection
+///
+/// Clients connecting to a node act as initiators, servers accepting
+/// connections act as responders and a full node-to-node peer can act as
+/// both at the same time (duplex).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Initiator,
+    Responder,
+    InitiatorAndResponder,
+}
+
+impl Role {
+    /// The modes the local channels of this role are allowed to work in
+    pub fn modes(&self) -> &'static [Mode] {
+        match self {
+            Role::Initiator => &[Mode::Initiator],
+            Role::Responder => &[Mode::Responder],
+            Role::InitiatorAndResponder => &[Mode::Initiator, Mode::Responder],
+        }
+    }
+
+    fn supports(&self, mode: Mode) -> bool {
+        self.modes().contains(&mode)
+    }
+}
+
 pub type Payload = Vec<u8>;
 
-enum TxStepError {
-    BearerError(std::io::Error),
-    IngressDisconnected,
-    IngressEmpty,
+/// Work items consumed by the tx loop
+enum IngressMsg {
+    Payload(u16, Payload),
+    Stop,
+}
+
+/// A payload waiting to be written into the bearer, possibly in several
+/// segments
+struct PendingPayload {
+    payload: Payload,
+    offset: usize,
+}
+
+/// Per-protocol queues of outbound payloads served in round-robin order
+struct EgressScheduler {
+    sdu_size: usize,
+    queues: HashMap<u16, VecDeque<PendingPayload>>,
+    rotation: VecDeque<u16>,
+}
+
+impl EgressScheduler {
+    fn new(sdu_size: usize) -> Self {
+        EgressScheduler {
+            sdu_size,
+            queues: HashMap::new(),
+            rotation: VecDeque::new(),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.rotation.is_empty()
+    }
+
+    fn enqueue(&mut self, id: u16, payload: Payload) {
+        let queue = self.queues.entry(id).or_default();
+
+        if queue.is_empty() {
+            self.rotation.push_back(id);
+        }
+
+        queue.push_back(PendingPayload { payload, offset: 0 });
+    }
+
+    /// Takes the next segment, rotating to the next protocol with pending data
+    fn next_segment(&mut self) -> Option<(u16, Payload)> {
+        let id = self.rotation.pop_front()?;
+        let queue = self.queues.get_mut(&id)?;
+        let pending = queue.front_mut()?;
+
+        let end = (pending.offset + self.sdu_size).min(pending.payload.len());
+        let chunk = Vec::from(&pending.payload[pending.offset..end]);
+        pending.offset = end;
+
+        if pending.offset >= pending.payload.len() {
+            queue.pop_front();
+        }
+
+        if !queue.is_empty() {
+            self.rotation.push_back(id);
+        }
+
+        Some((id, chunk))
+    }
 }
 
-fn tx_step<TBearer>(
+fn tx_loop<TBearer>(
     bearer: &mut TBearer,
-    ingress_id: u16,
-    ingress_rx: &mut Receiver<Payload>,
-    clock: Instant,
-) -> Result<(), TxStepError>
-where
+    sdu_size: usize,
+    ingress: Receiver<IngressMsg>,
+    outcome: Outcome,
+    stats: StatsRecorder,
+) where
     TBearer: Bearer,
 {
-    match ingress_rx.try_recv() {
-        Ok(payload) => {
-            let chunks = payload.chunks(MAX_SEGMENT_PAYLOAD_LENGTH);
-
-            for chunk in chunks {
-                bearer
-                    .write_segment(clock, ingress_id, chunk)
-                    .map_err(TxStepError::BearerError)?;
+    let clock = Instant::now();
+    let mut scheduler = EgressScheduler::new(sdu_size);
+
+    loop {
+        // block until there's work to do, then pick up anything else that arrived
+        // in the meantime so that every protocol gets its turn
+        let mut next = match scheduler.is_idle() {
+            true => match ingress.recv() {
+                Ok(msg) => Some(msg),
+                Err(_) => {
+                    debug!("ingress disconnected, stopping tx loop");
+                    return;
+                }
+            },
+            false => ingress.try_recv().ok(),
+        };
+
+        while let Some(msg) = next {
+            match msg {
+                IngressMsg::Payload(id, payload) => {
+                    stats.queued_out(Mode::untag(id), payload.len());
+                    scheduler.enqueue(id, payload);
+                }
+                IngressMsg::Stop => {
+                    debug!("connection ended, stopping tx loop");
+                    return;
+                }
+            }
+
+            next = ingress.try_recv().ok();
+        }
+
+        if let Some((id, chunk)) = scheduler.next_segment() {
+            if let Err(err) = bearer.write_segment(clock, id, &chunk) {
+                error!("{:?}", err);
+                outcome.settle(err.into());
+
+                // unblock the rx loop so that it can report the failure to the protocols
+                if let Err(err) = bearer.shutdown() {
+                    warn!("error shutting down bearer: {:?}", err);
+                }
+
+                return;
             }
 
-            Ok(())
+            stats.segment_out(Mode::untag(id), chunk.len());
         }
-        Err(TryRecvError::Disconnected) => Err(TxStepError::IngressDisconnected),
-        Err(TryRecvError::Empty) => Err(TxStepError::IngressEmpty),
     }
 }
 
-fn tx_loop<TBearer>(bearer: &mut TBearer, ingress: MuxIngress)
-where
+/// Finds out which local channel should receive an inbound segment
+fn demux_target(role: Role, header_id: u16) -> Result<ChannelKey, MuxError> {
+    let (protocol_id, remote_mode) = Mode::untag(header_id);
+    let local_mode = remote_mode.peer();
+
+    match role.supports(local_mode) {
+        true => Ok((protocol_id, local_mode)),
+        false => Err(MuxError::ProtocolViolation(format!(
+            "received segment for protocol {} addressed to {:?} mode, but local role is {:?}",
+            protocol_id, local_mode, role
+        ))),
+    }
+}
+
+/// Hands an inbound payload to the channel of its protocol
+///
+/// Fails if the payload makes the protocol exceed its ingress limit.
+fn deliver(
+    tx_map: &mut HashMap<ChannelKey, DemuxSender>,
+    stats: &StatsRecorder,
+    key: ChannelKey,
+    ts: u32,
+    payload: Payload,
+) -> Result<(), MuxError> {
+    let target = match tx_map.get(&key) {
+        Some(target) => target,
+        None => {
+            warn!("received segment for protocol not being demuxed {:?}", key);
+            return Ok(());
+        }
+    };
+
+    let len = payload.len();
+    stats.segment_in(key, ts, len);
+
+    let buffered = target.buffered.fetch_add(len, Ordering::SeqCst) + len;
+
+    if let Some(limit) = target.limit {
+        if buffered > limit {
+            return Err(MuxError::ProtocolViolation(format!(
+                "protocol {} exceeded its ingress limit of {} bytes",
+                key.0, limit
+            )));
+        }
+    }
+
+    match target.tx.send(Ok(payload)) {
+        Err(err) => {
+            error!(
+                "error sending egress tx to protocol, removing protocol from egress output. {:?}",
+                err
+            );
+            tx_map.remove(&key);
+        }
+        Ok(_) => {
+            debug!("successful tx to egress protocol");
+        }
+    }
+
+    Ok(())
+}
+
+fn rx_loop<TBearer>(
+    bearer: &mut TBearer,
+    role: Role,
+    egress: DemuxerEgress,
+    ingress: Sender<IngressMsg>,
+    outcome: Outcome,
+    stats: StatsRecorder,
+) where
     TBearer: Bearer,
 {
-    let mut rx_map: HashMap<_, _> = ingress.into_iter().collect();
-
     loop {
-        let clock = Instant::now();
+        let result = bearer
+            .read_segment()
+            .map_err(MuxError::from)
+            .and_then(|(id, ts, payload)| {
+                let key = demux_target(role, id)?;
+                let mut tx_map = egress.lock().expect("egress lock poisoned");
+                deliver(&mut tx_map, &stats, key, ts, payload)
+            });
 
-        rx_map.retain(|id, rx| match tx_step(bearer, *id, rx, clock) {
-            Err(TxStepError::BearerError(err)) => {
-                error!("{:?}", err);
-                panic!();
-            }
-            Err(TxStepError::IngressDisconnected) => {
-                warn!("protocol handle {} disconnected", id);
-                false
+        if let Err(err) = result {
+            let reason = outcome.settle(err);
+            debug!("stopping rx loop, reason: {}", reason);
+
+            // unblock the tx loop in case it's still writing to the bearer
+            if let Err(err) = bearer.shutdown() {
+                warn!("error shutting down bearer: {:?}", err);
             }
-            Err(TxStepError::IngressEmpty) => {
-                thread::sleep(Duration::from_millis(10));
-                true
+
+            // the tx loop might be gone already, nothing else to do
+            ingress.send(IngressMsg::Stop).ok();
+
+            // dropping the senders disconnects the channels after the reason
+            let mut tx_map = egress.lock().expect("egress lock poisoned");
+            for (_, target) in tx_map.drain() {
+                // the protocol might be gone already, nothing else to do
+                target.tx.send(Err(reason.clone())).ok();
             }
-            Ok(_) => true,
-        });
+
+            return;
+        }
     }
 }
 
-fn rx_loop<TBearer>(bearer: &mut TBearer, egress: DemuxerEgress)
-where
-    TBearer: Bearer,
-{
-    let mut tx_map: HashMap<_, _> = egress.into_iter().collect();
+/// The outcome of reading from a protocol channel
+///
+/// Once the connection ends, each protocol channel receives a final `Err`
+/// with the reason before being disconnected.
+pub type Egress = Result<Payload, MuxError>;
 
-    loop {
-        match bearer.read_segment() {
-            Err(err) => {
-                error!("{:?}", err);
-                panic!();
+/// A handle for sending payloads of a single protocol into the muxer ingress
+///
+/// All the senders of a multiplexer share the same queue, which wakes the tx
+/// loop as soon as a payload arrives.
+#[derive(Clone)]
+pub struct MuxSender {
+    protocol_id: u16,
+    mode: Mode,
+    ingress: Sender<IngressMsg>,
+    outcome: Outcome,
+}
+
+impl MuxSender {
+    pub fn protocol_id(&self) -> u16 {
+        self.protocol_id
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Queues a payload to be sent to the remote peer
+    ///
+    /// Fails with the reason the connection ended if the muxer isn't running
+    /// anymore.
+    pub fn send(&self, payload: Payload) -> Result<(), MuxError> {
+        self.ingress
+            .send(IngressMsg::Payload(
+                self.mode.tag(self.protocol_id),
+                payload,
+            ))
+            .map_err(|_| self.outcome.get().unwrap_or(MuxError::Shutdown))
+    }
+}
+
+/// A handle for receiving the inbound payloads of a single protocol
+///
+/// Keeps track of the bytes waiting to be consumed, which the demuxer checks
+/// against the ingress limit of the protocol.
+pub struct DemuxReceiver {
+    rx: Receiver<Egress>,
+    buffered: Arc<AtomicUsize>,
+}
+
+impl DemuxReceiver {
+    /// Blocks until the next payload (or the reason the connection ended)
+    /// arrives
+    pub fn recv(&self) -> Result<Egress, RecvError> {
+        let egress = self.rx.recv()?;
+
+        if let Ok(payload) = &egress {
+            self.buffered.fetch_sub(payload.len(), Ordering::SeqCst);
+        }
+
+        Ok(egress)
+    }
+
+    /// Like [DemuxReceiver::recv], but gives up once the timeout elapses
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Egress, RecvTimeoutError> {
+        let egress = self.rx.recv_timeout(timeout)?;
+
+        if let Ok(payload) = &egress {
+            self.buffered.fetch_sub(payload.len(), Ordering::SeqCst);
+        }
+
+        Ok(egress)
+    }
+
+    /// The amount of bytes received from the peer and not consumed yet
+    pub fn buffered(&self) -> usize {
+        self.buffered.load(Ordering::SeqCst)
+    }
+}
+
+/// The demuxer end of a [DemuxReceiver]
+struct DemuxSender {
+    tx: Sender<Egress>,
+    buffered: Arc<AtomicUsize>,
+    limit: Option<usize>,
+}
+
+pub struct Channel(pub MuxSender, pub DemuxReceiver);
+
+/// Identifies a local channel by its protocol id and the mode it works in
+type ChannelKey = (u16, Mode);
+
+/// The demuxer ends of the registered channels, shared with the rx loop so
+/// that channels can be added or removed while the muxer is running
+type DemuxerEgress = Arc<Mutex<HashMap<ChannelKey, DemuxSender>>>;
+
+/// Reasons for not being able to provide a protocol channel
+#[derive(Debug, Clone)]
+pub enum ChannelError {
+    /// The protocol isn't registered for the requested mode
+    NotRegistered(u16, Mode),
+    /// The channel was already taken by a previous call
+    AlreadyTaken(u16, Mode),
+    /// The protocol is already registered in the muxer
+    AlreadyRegistered(u16),
+    /// The protocol isn't registered in the muxer, in any mode
+    UnknownProtocol(u16),
+    /// The connection already ended, with the provided reason
+    ConnectionEnded(MuxError),
+}
+
+impl Display for ChannelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelError::NotRegistered(id, mode) => {
+                write!(f, "protocol {} is not registered as {:?}", id, mode)
             }
-            Ok(segment) => {
-                let (id, _ts, payload) = segment;
-                match tx_map.get(&id) {
-                    Some(tx) => match tx.send(payload) {
-                        Err(err) => {
-                            error!("error sending egress tx to protocol, removing protocol from egress output. {:?}", err);
-                            tx_map.remove(&id);
-                        }
-                        Ok(_) => {
-                            debug!("successful tx to egress protocol");
-                        }
-                    },
-                    None => warn!("received segment for protocol id not being demuxed {}", id),
-                }
+            ChannelError::AlreadyTaken(id, mode) => {
+                write!(
+                    f,
+                    "channel of protocol {} as {:?} was already taken",
+                    id, mode
+                )
+            }
+            ChannelError::AlreadyRegistered(id) => {
+                write!(f, "protocol {} is already registered", id)
+            }
+            ChannelError::UnknownProtocol(id) => {
+                write!(f, "protocol {} is not registered", id)
+            }
+            ChannelError::ConnectionEnded(reason) => {
+                write!(f, "connection already ended: {}", reason)
             }
         }
     }
 }
 
-pub struct Channel(pub Sender<Payload>, pub Receiver<Payload>);
+impl std::error::Error for ChannelError {}
+
+type BearerCloser = Box<dyn Fn() -> Result<(), std::io::Error> + Send + Sync>;
+
+/// Tunables of a multiplexer
+///
+/// The default config uses the largest SDU the segment header allows and
+/// doesn't limit the ingress of any protocol. Use the [node_to_node] and
+/// [node_to_client] presets to match the values of the Ouroboros spec.
+///
+/// [node_to_node]: MultiplexerConfig::node_to_node
+/// [node_to_client]: MultiplexerConfig::node_to_client
+#[derive(Debug, Clone)]
+pub struct MultiplexerConfig {
+    /// Max length of the payload of each outbound segment
+    pub max_sdu_size: usize,
+    /// Max amount of inbound bytes a protocol can have waiting to be
+    /// consumed, keyed by protocol id. Protocols without an entry are
+    /// unbounded.
+    pub ingress_limits: HashMap<u16, usize>,
+}
+
+impl Default for MultiplexerConfig {
+    fn default() -> Self {
+        MultiplexerConfig {
+            max_sdu_size: MAX_SEGMENT_PAYLOAD_LENGTH,
+            ingress_limits: HashMap::new(),
+        }
+    }
+}
+
+impl MultiplexerConfig {
+    /// Values used by nodes talking to each other over TCP
+    ///
+    /// Ingress limits are the ones of the reference implementation for the
+    /// handshake, chainsync, blockfetch, txsubmission and keepalive protocols,
+    /// which already include a 10% safety margin.
+    pub fn node_to_node() -> Self {
+        MultiplexerConfig {
+            max_sdu_size: 12288,
+            ingress_limits: HashMap::from([
+                (0, 5760),
+                (2, 462000),
+                (3, 11534336),
+                (4, 721424),
+                (8, 1408),
+            ]),
+        }
+    }
+
+    /// Values used by local clients talking to a node over a unix socket
+    ///
+    /// Local clients are trusted by the node, so no ingress limit applies.
+    pub fn node_to_client() -> Self {
+        MultiplexerConfig {
+            max_sdu_size: 12288,
+            ingress_limits: HashMap::new(),
+        }
+    }
+
+    /// Sets the ingress limit of a protocol
+    pub fn with_ingress_limit(mut self, protocol_id: u16, bytes: usize) -> Self {
+        self.ingress_limits.insert(protocol_id, bytes);
+        self
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self.max_sdu_size {
+            1..=MAX_SEGMENT_PAYLOAD_LENGTH => Ok(()),
+            size => Err(format!(
+                "invalid SDU size {}, must be between 1 and {}",
+                size, MAX_SEGMENT_PAYLOAD_LENGTH
+            )),
+        }
+    }
+}
+
+/// Creates both ends of a protocol channel
+fn open_channel(
+    key: ChannelKey,
+    ingress: &Sender<IngressMsg>,
+    outcome: &Outcome,
+    limit: Option<usize>,
+) -> (Channel, DemuxSender) {
+    let (demux_tx, demux_rx) = mpsc::channel::<Egress>();
+    let buffered = Arc::new(AtomicUsize::new(0));
+
+    let mux_tx = MuxSender {
+        protocol_id: key.0,
+        mode: key.1,
+        ingress: ingress.clone(),
+        outcome: outcome.clone(),
+    };
 
-type ChannelProtocolHandle = (u16, Channel);
-type ChannelIngressHandle = (u16, Receiver<Payload>);
-type ChannelEgressHandle = (u16, Sender<Payload>);
-type MuxIngress = Vec<ChannelIngressHandle>;
-type DemuxerEgress = Vec<ChannelEgressHandle>;
+    let demux_rx = DemuxReceiver {
+        rx: demux_rx,
+        buffered: buffered.clone(),
+    };
+
+    let demux_tx = DemuxSender {
+        tx: demux_tx,
+        buffered,
+        limit,
+    };
+
+    (Channel(mux_tx, demux_rx), demux_tx)
+}
 
 pub struct Multiplexer {
     tx_thread: JoinHandle<()>,
     rx_thread: JoinHandle<()>,
-    io_handles: HashMap<u16, Channel>,
+    role: Role,
+    io_handles: HashMap<ChannelKey, Channel>,
+    egress: DemuxerEgress,
+    ingress_limits: HashMap<u16, usize>,
+    ingress: Sender<IngressMsg>,
+    outcome: Outcome,
+    closer: BearerCloser,
+    stats: StatsRecorder,
+    buffers: HashMap<ChannelKey, Arc<AtomicUsize>>,
 }
 
 impl Multiplexer {
+    /// Starts multiplexing the bearer for the given protocol ids
+    ///
+    /// Protocol ids are the plain mini-protocol numbers, the mode bit of the
+    /// segments is set and stripped according to the `role`. A duplex role
+    /// registers both an initiator and a responder channel for each id. More
+    /// protocols can be registered later on using [Multiplexer::add_protocol].
     pub fn setup<TBearer>(
         bearer: TBearer,
+        role: Role,
         protocols: &[u16],
     ) -> Result<Multiplexer, Box<dyn std::error::Error>>
     where
         TBearer: Bearer + 'static,
     {
-        let handles = protocols.iter().map(|id| {
-            let (demux_tx, demux_rx) = mpsc::channel::<Payload>();
-            let (mux_tx, mux_rx) = mpsc::channel::<Payload>();
+        Self::setup_with_config(bearer, role, protocols, MultiplexerConfig::default())
+    }
+
+    /// Starts multiplexing the bearer using a custom config
+    ///
+    /// Fails if the config isn't valid. Once running, a peer that makes a
+    /// protocol exceed its ingress limit ends the connection with a
+    /// [MuxError::ProtocolViolation].
+    pub fn setup_with_config<TBearer>(
+        bearer: TBearer,
+        role: Role,
+        protocols: &[u16],
+        config: MultiplexerConfig,
+    ) -> Result<Multiplexer, Box<dyn std::error::Error>>
+    where
+        TBearer: Bearer + 'static,
+    {
+        config.validate()?;
 
-            let channel = Channel(mux_tx, demux_rx);
+        let outcome = Outcome::default();
+        let stats = StatsRecorder::default();
+        let (ingress_tx, ingress_rx) = mpsc::channel::<IngressMsg>();
 
-            let protocol_handle: ChannelProtocolHandle = (*id, channel);
-            let ingress_handle: ChannelIngressHandle = (*id, mux_rx);
-            let egress_handle: ChannelEgressHandle = (*id, demux_tx);
+        let mut io_handles = HashMap::new();
+        let mut egress = HashMap::new();
+        let mut buffers = HashMap::new();
 
-            (protocol_handle, (ingress_handle, egress_handle))
-        });
+        let keys = protocols
+            .iter()
+            .flat_map(|id| role.modes().iter().map(move |mode| (*id, *mode)));
 
-        let (protocol_handles, multiplex_handles): (Vec<_>, Vec<_>) = handles.into_iter().unzip();
+        for key in keys {
+            let limit = config.ingress_limits.get(&key.0).copied();
+            let (channel, demux_tx) = open_channel(key, &ingress_tx, &outcome, limit);
+
+            buffers.insert(key, demux_tx.buffered.clone());
+            stats.register(key);
+            egress.insert(key, demux_tx);
+            io_handles.insert(key, channel);
+        }
 
-        let (ingress, egress): (Vec<_>, Vec<_>) = multiplex_handles.into_iter().unzip();
+        let egress: DemuxerEgress = Arc::new(Mutex::new(egress));
 
         let mut tx_bearer = bearer.clone();
-        let tx_thread = thread::spawn(move || tx_loop(&mut tx_bearer, ingress));
+        let tx_outcome = outcome.clone();
+        let tx_stats = stats.clone();
+        let sdu_size = config.max_sdu_size;
+        let tx_thread = thread::spawn(move || {
+            tx_loop(&mut tx_bearer, sdu_size, ingress_rx, tx_outcome, tx_stats)
+        });
 
         let mut rx_bearer = bearer.clone();
-        let rx_thread = thread::spawn(move || rx_loop(&mut rx_bearer, egress));
+        let rx_egress = egress.clone();
+        let rx_ingress = ingress_tx.clone();
+        let rx_outcome = outcome.clone();
+        let rx_stats = stats.clone();
+        let rx_thread = thread::spawn(move || {
+            rx_loop(
+                &mut rx_bearer,
+                role,
+                rx_egress,
+                rx_ingress,
+                rx_outcome,
+                rx_stats,
+            )
+        });
 
-        let io_handles: HashMap<u16, Channel> = protocol_handles.into_iter().collect();
+        let closer: BearerCloser = Box::new(move || bearer.shutdown());
 
         Ok(Multiplexer {
+            role,
             io_handles,
+            egress,
+            ingress_limits: config.ingress_limits,
             tx_thread,
             rx_thread,
+            ingress: ingress_tx,
+            outcome,
+            closer,
+            stats,
+            buffers,
         })
     }
 
-    pub fn use_channel(&mut self, protocol_id: u16) -> Channel {
-        self.io_handles
-            .remove(&protocol_id)
-            .expect("requested channel not found in multiplexer")
+    /// Registers a new protocol on the running muxer
+    ///
+    /// The channels of the protocol are created for every mode of the muxer
+    /// role and can be taken afterwards using [Multiplexer::use_channel].
+    /// Segments of the protocol that arrived before registering it are
+    /// discarded.
+    pub fn add_protocol(&mut self, protocol_id: u16) -> Result<(), ChannelError> {
+        let mut egress = self.egress.lock().expect("egress lock poisoned");
+
+        // the rx loop settles the outcome before draining the egress map, so
+        // checking it while holding the lock ensures new channels are either
+        // rejected or notified when the connection ends
+        if let Some(reason) = self.outcome.get() {
+            return Err(ChannelError::ConnectionEnded(reason));
+        }
+
+        let modes = self.role.modes();
+
+        if modes
+            .iter()
+            .any(|mode| egress.contains_key(&(protocol_id, *mode)))
+        {
+            return Err(ChannelError::AlreadyRegistered(protocol_id));
+        }
+
+        for mode in modes {
+            let key = (protocol_id, *mode);
+            let limit = self.ingress_limits.get(&protocol_id).copied();
+            let (channel, demux_tx) = open_channel(key, &self.ingress, &self.outcome, limit);
+
+            self.buffers.insert(key, demux_tx.buffered.clone());
+            self.stats.register(key);
+            egress.insert(key, demux_tx);
+            self.io_handles.insert(key, channel);
+        }
+
+        Ok(())
+    }
+
+    /// Unregisters a protocol from the running muxer
+    ///
+    /// Receivers of the protocol get disconnected and any segment of the
+    /// protocol arriving afterwards is discarded. Payloads already queued for
+    /// sending are still delivered.
+    pub fn remove_protocol(&mut self, protocol_id: u16) -> Result<(), ChannelError> {
+        let mut egress = self.egress.lock().expect("egress lock poisoned");
+        let mut found = false;
+
+        for mode in self.role.modes() {
+            let key = (protocol_id, *mode);
+
+            found |= egress.remove(&key).is_some();
+            found |= self.io_handles.remove(&key).is_some();
+            self.buffers.remove(&key);
+            self.stats.unregister(key);
+        }
+
+        match found {
+            true => Ok(()),
+            false => Err(ChannelError::UnknownProtocol(protocol_id)),
+        }
+    }
+
+    /// Takes the channel of a protocol in the main mode of the muxer role
+    ///
+    /// For duplex muxers this is the initiator channel, use
+    /// [Multiplexer::use_channel_as] to get the responder one.
+    pub fn use_channel(&mut self, protocol_id: u16) -> Result<Channel, ChannelError> {
+        let mode = self.role.modes()[0];
+        self.use_channel_as(protocol_id, mode)
+    }
+
+    /// Takes the channel of a protocol working in a particular mode
+    ///
+    /// Each channel can be taken only once.
+    pub fn use_channel_as(
+        &mut self,
+        protocol_id: u16,
+        mode: Mode,
+    ) -> Result<Channel, ChannelError> {
+        let key = (protocol_id, mode);
+
+        if let Some(channel) = self.io_handles.remove(&key) {
+            return Ok(channel);
+        }
+
+        match self.buffers.contains_key(&key) {
+            true => Err(ChannelError::AlreadyTaken(protocol_id, mode)),
+            false => Err(ChannelError::NotRegistered(protocol_id, mode)),
+        }
+    }
+
+    /// Takes a snapshot of the traffic counters of every channel
+    ///
+    /// Counters keep their last values after the connection ends.
+    pub fn stats(&self) -> MuxStats {
+        self.stats.snapshot(&self.buffers)
+    }
+
+    /// Closes the bearer, ending the connection for every protocol channel
+    pub fn shutdown(&self) -> Result<(), MuxError> {
+        self.outcome.settle(MuxError::Shutdown);
+
+        // the tx loop might be gone already, nothing else to do
+        self.ingress.send(IngressMsg::Stop).ok();
+
+        (self.closer)()?;
+
+        Ok(())
     }
 
-    pub fn join(self) {
+    /// Waits for the connection to end and returns the reason
+    ///
+    /// A connection that ended because of a call to [Multiplexer::shutdown]
+    /// is considered successful, any other reason is returned as an error.
+    pub fn join(self) -> Result<(), MuxError> {
         self.tx_thread.join().expect("error joining tx loop thread");
         self.rx_thread.join().expect("error joining rx loop thread");
+
+        match self.outcome.get() {
+            None | Some(MuxError::Shutdown) => Ok(()),
+            Some(reason) => Err(reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scheduler_interleaves_protocols_per_segment() {
+        let mut scheduler = EgressScheduler::new(MAX_SEGMENT_PAYLOAD_LENGTH);
+
+        scheduler.enqueue(3, vec![3; MAX_SEGMENT_PAYLOAD_LENGTH * 2 + 1]);
+        scheduler.enqueue(2, vec![2; 10]);
+        scheduler.enqueue(8, vec![8; 10]);
+        scheduler.enqueue(2, vec![2; 20]);
+
+        let segments: Vec<_> = std::iter::from_fn(|| scheduler.next_segment())
+            .map(|(id, chunk)| (id, chunk.len()))
+            .collect();
+
+        assert_eq!(
+            segments,
+            vec![
+                (3, MAX_SEGMENT_PAYLOAD_LENGTH),
+                (2, 10),
+                (8, 10),
+                (3, MAX_SEGMENT_PAYLOAD_LENGTH),
+                (2, 20),
+                (3, 1),
+            ]
+        );
+
+        assert!(scheduler.is_idle());
     }
 }
@@ -0,0 +1,328 @@
+//! Recording and replay of multiplexer sessions
+//!
+//! A [RecordingBearer] wraps any other bearer and stores every segment that
+//! goes through it. A [ReplayBearer] feeds the inbound segments of a recorded
+//! session back to a multiplexer, which allows re-running a problematic
+//! interaction with a node offline.
+
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use log::{debug, trace, warn};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::Instant,
+};
+
+use crate::{Bearer, Mode, Payload, SegmentHeader};
+
+/// The direction a recorded segment travelled in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The segment was written by the local end
+    Outbound,
+    /// The segment was read from the remote end
+    Inbound,
+}
+
+/// A single segment of a recorded session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub direction: Direction,
+    /// Microseconds since the recording started
+    pub timestamp: u64,
+    /// The `ts` field of the segment header
+    pub segment_ts: u32,
+    /// The raw protocol id of the segment header, including the mode bit
+    pub protocol_id: u16,
+    pub payload: Payload,
+}
+
+impl Record {
+    /// Rebuilds the header the segment had on the wire
+    pub fn header(&self) -> SegmentHeader {
+        let (protocol_id, mode) = Mode::untag(self.protocol_id);
+
+        SegmentHeader {
+            timestamp: self.segment_ts,
+            protocol_id,
+            mode,
+            payload_length: self.payload.len() as u16,
+        }
+    }
+
+    /// Writes the record using the binary format of session files
+    ///
+    /// Each record is encoded as: direction (u8), timestamp (u64), segment ts
+    /// (u32), protocol id (u16), payload length (u16) and the payload bytes,
+    /// all integers in network byte order.
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<(), std::io::Error> {
+        let direction = match self.direction {
+            Direction::Outbound => 0,
+            Direction::Inbound => 1,
+        };
+
+        writer.write_u8(direction)?;
+        writer.write_u64::<NetworkEndian>(self.timestamp)?;
+        writer.write_u32::<NetworkEndian>(self.segment_ts)?;
+        writer.write_u16::<NetworkEndian>(self.protocol_id)?;
+        writer.write_u16::<NetworkEndian>(self.payload.len() as u16)?;
+        writer.write_all(&self.payload)?;
+
+        Ok(())
+    }
+
+    /// Reads the next record, returns `None` if the input is exhausted
+    pub fn read_from(reader: &mut impl Read) -> Result<Option<Record>, std::io::Error> {
+        let direction = match reader.read_u8() {
+            Ok(0) => Direction::Outbound,
+            Ok(1) => Direction::Inbound,
+            Ok(x) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unknown record direction {}", x),
+                ))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let timestamp = reader.read_u64::<NetworkEndian>()?;
+        let segment_ts = reader.read_u32::<NetworkEndian>()?;
+        let protocol_id = reader.read_u16::<NetworkEndian>()?;
+        let length = reader.read_u16::<NetworkEndian>()? as usize;
+
+        let mut payload = vec![0u8; length];
+        reader.read_exact(&mut payload)?;
+
+        Ok(Some(Record {
+            direction,
+            timestamp,
+            segment_ts,
+            protocol_id,
+            payload,
+        }))
+    }
+}
+
+/// Reads all the records of a session from a reader
+pub fn read_records(reader: &mut impl Read) -> Result<Vec<Record>, std::io::Error> {
+    let mut records = Vec::new();
+
+    while let Some(record) = Record::read_from(reader)? {
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+type RecordSink = Arc<Mutex<Box<dyn Write + Send>>>;
+
+/// A bearer that records every segment going through an inner bearer
+pub struct RecordingBearer<B: Bearer> {
+    inner: B,
+    sink: RecordSink,
+    started: Instant,
+}
+
+impl<B: Bearer> RecordingBearer<B> {
+    /// Wraps a bearer, writing the records into the given output
+    pub fn new(inner: B, output: impl Write + Send + 'static) -> Self {
+        RecordingBearer {
+            inner,
+            sink: Arc::new(Mutex::new(Box::new(output))),
+            started: Instant::now(),
+        }
+    }
+
+    /// Wraps a bearer, writing the records into a new file at the given path
+    pub fn create(inner: B, path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        let file = File::create(path)?;
+        Ok(Self::new(inner, BufWriter::new(file)))
+    }
+
+    fn record(
+        &self,
+        direction: Direction,
+        segment_ts: u32,
+        protocol_id: u16,
+        payload: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let record = Record {
+            direction,
+            timestamp: self.started.elapsed().as_micros() as u64,
+            segment_ts,
+            protocol_id,
+            payload: Vec::from(payload),
+        };
+
+        trace!("recording segment {:?}", record);
+
+        let mut sink = self.sink.lock().expect("record sink lock poisoned");
+        record.write_to(&mut *sink)?;
+        sink.flush()
+    }
+}
+
+impl<B: Bearer> Bearer for RecordingBearer<B> {
+    fn read_segment(&mut self) -> Result<(u16, u32, Payload), std::io::Error> {
+        let (id, ts, payload) = self.inner.read_segment()?;
+        self.record(Direction::Inbound, ts, id, &payload)?;
+
+        Ok((id, ts, payload))
+    }
+
+    fn write_segment(
+        &mut self,
+        clock: Instant,
+        protocol_id: u16,
+        partial_payload: &[u8],
+    ) -> Result<(), std::io::Error> {
+        // the inner bearer reads the clock again when writing the header, the
+        // recorded value is an approximation of the one on the wire
+        let ts = clock.elapsed().as_micros() as u32;
+
+        // record before writing so that a quick response can't be recorded
+        // ahead of the segment that triggered it
+        self.record(Direction::Outbound, ts, protocol_id, partial_payload)?;
+        self.inner
+            .write_segment(clock, protocol_id, partial_payload)
+    }
+
+    fn clone(&self) -> Self {
+        RecordingBearer {
+            inner: self.inner.clone(),
+            sink: self.sink.clone(),
+            started: self.started,
+        }
+    }
+
+    fn shutdown(&self) -> Result<(), std::io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+#[derive(Default)]
+struct ReplayState {
+    pending: VecDeque<Record>,
+    closed: bool,
+}
+
+/// A bearer that plays back a recorded session
+///
+/// Inbound segments are delivered in the recorded order, but only after the
+/// multiplexer wrote the outbound segments that preceded them in the
+/// original session, which keeps the causality of the conversation. Once the
+/// recording is over, or the local end writes more than what was recorded,
+/// the bearer reports the end of the connection.
+pub struct ReplayBearer {
+    state: Arc<(Mutex<ReplayState>, Condvar)>,
+}
+
+impl ReplayBearer {
+    pub fn new(records: Vec<Record>) -> Self {
+        let state = ReplayState {
+            pending: records.into(),
+            ..Default::default()
+        };
+
+        ReplayBearer {
+            state: Arc::new((Mutex::new(state), Condvar::new())),
+        }
+    }
+
+    /// Loads the session recorded in the file at the given path
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let records = read_records(&mut reader)?;
+
+        Ok(Self::new(records))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ReplayState> {
+        self.state.0.lock().expect("replay lock poisoned")
+    }
+}
+
+impl Bearer for ReplayBearer {
+    fn read_segment(&mut self) -> Result<(u16, u32, Payload), std::io::Error> {
+        let mut state = self.lock();
+
+        loop {
+            // once the recording runs out of segments there's nothing left to
+            // deliver, waiting would hang the demuxer
+            if state.closed || state.pending.is_empty() {
+                debug!("replay finished, reporting end of session");
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+
+            let next_is_inbound = state
+                .pending
+                .front()
+                .map(|r| r.direction == Direction::Inbound);
+
+            if let Some(true) = next_is_inbound {
+                let record = state.pending.pop_front().unwrap();
+                return Ok((record.protocol_id, record.segment_ts, record.payload));
+            }
+
+            // wait for the local end to write what was sent in the original session
+            state = self.state.1.wait(state).expect("replay lock poisoned");
+        }
+    }
+
+    fn write_segment(
+        &mut self,
+        _clock: Instant,
+        protocol_id: u16,
+        partial_payload: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let mut state = self.lock();
+
+        let next_is_outbound = state
+            .pending
+            .front()
+            .map(|r| r.direction == Direction::Outbound);
+
+        match next_is_outbound {
+            Some(true) => {
+                let record = state.pending.pop_front().unwrap();
+
+                if record.protocol_id != protocol_id || record.payload != partial_payload {
+                    warn!(
+                        "outbound segment for protocol {} diverges from the recorded one",
+                        protocol_id
+                    );
+                }
+            }
+            Some(false) => {
+                warn!(
+                    "unexpected outbound segment for protocol {}, recording expects inbound data",
+                    protocol_id
+                );
+            }
+            None => {
+                debug!("outbound segment beyond the end of the recording");
+            }
+        }
+
+        self.state.1.notify_all();
+
+        Ok(())
+    }
+
+    fn clone(&self) -> Self {
+        ReplayBearer {
+            state: self.state.clone(),
+        }
+    }
+
+    fn shutdown(&self) -> Result<(), std::io::Error> {
+        self.lock().closed = true;
+        self.state.1.notify_all();
+
+        Ok(())
+    }
+}
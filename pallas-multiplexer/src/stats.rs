@@ -0,0 +1,165 @@
+//! Traffic counters of a running multiplexer
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use crate::{ChannelKey, Mode};
+
+/// Traffic counters of a single protocol channel
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProtocolStats {
+    pub segments_in: u64,
+    pub bytes_in: u64,
+    pub segments_out: u64,
+    pub bytes_out: u64,
+    /// Inbound bytes waiting to be consumed by the protocol
+    pub ingress_queue: usize,
+    /// Outbound bytes waiting to be written into the bearer
+    pub egress_queue: usize,
+    /// The last time a segment of the protocol was sent or received
+    pub last_activity: Option<Instant>,
+}
+
+/// A point-in-time snapshot of the traffic of a multiplexer
+#[derive(Debug, Clone, Default)]
+pub struct MuxStats {
+    /// Counters of each registered channel, keyed by protocol id and mode
+    pub protocols: HashMap<(u16, Mode), ProtocolStats>,
+    /// How many microseconds the remote clock moved ahead of the local one
+    /// since the first inbound segment, as seen on the last one. Negative
+    /// values mean the remote clock falls behind. `None` until a segment
+    /// arrives.
+    pub remote_clock_drift: Option<i64>,
+}
+
+impl MuxStats {
+    pub fn protocol(&self, protocol_id: u16, mode: Mode) -> Option<&ProtocolStats> {
+        self.protocols.get(&(protocol_id, mode))
+    }
+}
+
+/// Tracks the remote clock using the `ts` field of inbound segment headers
+///
+/// The field holds the lower 32 bits of the remote clock in microseconds, so
+/// it wraps around every ~71 minutes. Wraps are accounted for as long as
+/// segments arrive more often than that.
+struct RemoteClock {
+    local_origin: Instant,
+    last_ts: u32,
+    remote_elapsed: u64,
+}
+
+impl RemoteClock {
+    fn new(ts: u32) -> Self {
+        RemoteClock {
+            local_origin: Instant::now(),
+            last_ts: ts,
+            remote_elapsed: 0,
+        }
+    }
+
+    fn observe(&mut self, ts: u32) -> i64 {
+        self.remote_elapsed += ts.wrapping_sub(self.last_ts) as u64;
+        self.last_ts = ts;
+
+        let local_elapsed = self.local_origin.elapsed().as_micros() as i64;
+        self.remote_elapsed as i64 - local_elapsed
+    }
+}
+
+#[derive(Default)]
+struct StatsState {
+    protocols: HashMap<ChannelKey, ProtocolStats>,
+    remote_clock: Option<RemoteClock>,
+    remote_clock_drift: Option<i64>,
+}
+
+/// The handle shared by the mux loops to update the counters
+#[derive(Clone, Default)]
+pub(crate) struct StatsRecorder(Arc<Mutex<StatsState>>);
+
+impl StatsRecorder {
+    fn update<F>(&self, key: ChannelKey, f: F)
+    where
+        F: FnOnce(&mut ProtocolStats),
+    {
+        let mut state = self.0.lock().expect("stats lock poisoned");
+
+        // protocols might be removed while they still have queued payloads
+        if let Some(stats) = state.protocols.get_mut(&key) {
+            f(stats);
+        }
+    }
+
+    pub(crate) fn register(&self, key: ChannelKey) {
+        let mut state = self.0.lock().expect("stats lock poisoned");
+        state.protocols.entry(key).or_default();
+    }
+
+    pub(crate) fn unregister(&self, key: ChannelKey) {
+        let mut state = self.0.lock().expect("stats lock poisoned");
+        state.protocols.remove(&key);
+    }
+
+    pub(crate) fn segment_in(&self, key: ChannelKey, ts: u32, len: usize) {
+        let mut state = self.0.lock().expect("stats lock poisoned");
+
+        let drift = match &mut state.remote_clock {
+            Some(clock) => clock.observe(ts),
+            None => {
+                state.remote_clock = Some(RemoteClock::new(ts));
+                0
+            }
+        };
+
+        state.remote_clock_drift = Some(drift);
+
+        if let Some(stats) = state.protocols.get_mut(&key) {
+            stats.segments_in += 1;
+            stats.bytes_in += len as u64;
+            stats.last_activity = Some(Instant::now());
+        }
+    }
+
+    pub(crate) fn queued_out(&self, key: ChannelKey, len: usize) {
+        self.update(key, |stats| stats.egress_queue += len);
+    }
+
+    pub(crate) fn segment_out(&self, key: ChannelKey, len: usize) {
+        self.update(key, |stats| {
+            stats.segments_out += 1;
+            stats.bytes_out += len as u64;
+            stats.egress_queue = stats.egress_queue.saturating_sub(len);
+            stats.last_activity = Some(Instant::now());
+        });
+    }
+
+    pub(crate) fn snapshot(&self, buffers: &HashMap<ChannelKey, Arc<AtomicUsize>>) -> MuxStats {
+        let state = self.0.lock().expect("stats lock poisoned");
+
+        let protocols = state
+            .protocols
+            .iter()
+            .map(|(key, stats)| {
+                let mut stats = stats.clone();
+
+                if let Some(buffered) = buffers.get(key) {
+                    stats.ingress_queue = buffered.load(Ordering::SeqCst);
+                }
+
+                (*key, stats)
+            })
+            .collect();
+
+        MuxStats {
+            protocols,
+            remote_clock_drift: state.remote_clock_drift,
+        }
+    }
+}
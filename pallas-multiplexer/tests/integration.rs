@@ -4,22 +4,26 @@ use std::{
 };
 
 use log::info;
-use pallas_multiplexer::{Channel, Multiplexer};
+use pallas_multiplexer::{
+    recording::{read_records, Direction, Record, RecordingBearer, ReplayBearer},
+    Channel, ChannelError, DemuxReceiver, MemoryBearer, Mode, Multiplexer, MultiplexerConfig,
+    MuxError, ProtocolStats, Role,
+};
 use rand::{distributions::Uniform, Rng};
 
-fn setup_passive_muxer<const P: u16>() -> JoinHandle<Multiplexer> {
-    thread::spawn(|| {
+fn setup_passive_muxer<const P: u16>(role: Role) -> JoinHandle<Multiplexer> {
+    thread::spawn(move || {
         let server = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, P)).unwrap();
         info!("listening for connections on port {}", P);
         let (bearer, _) = server.accept().unwrap();
-        Multiplexer::setup(bearer, &[0x8003u16]).unwrap()
+        Multiplexer::setup(bearer, role, &[0x0003u16]).unwrap()
     })
 }
 
-fn setup_active_muxer<const P: u16>() -> JoinHandle<Multiplexer> {
-    thread::spawn(|| {
+fn setup_active_muxer<const P: u16>(role: Role) -> JoinHandle<Multiplexer> {
+    thread::spawn(move || {
         let bearer = TcpStream::connect(SocketAddrV4::new(Ipv4Addr::LOCALHOST, P)).unwrap();
-        Multiplexer::setup(bearer, &[0x0003u16]).unwrap()
+        Multiplexer::setup(bearer, role, &[0x0003u16]).unwrap()
     })
 }
 
@@ -30,46 +34,463 @@ fn random_payload(size: usize) -> Vec<u8> {
 
 #[test]
 fn one_way_small_payload_is_consistent() {
-    let passive = setup_passive_muxer::<50201>();
+    let passive = setup_passive_muxer::<50201>(Role::Responder);
 
     // HACK: a small sleep seems to be required for Github actions runner to
     // formally expose the port
     thread::sleep(std::time::Duration::from_secs(1));
 
-    let active = setup_active_muxer::<50201>();
+    let active = setup_active_muxer::<50201>(Role::Initiator);
 
     let mut active_muxer = active.join().unwrap();
     let mut passive_muxer = passive.join().unwrap();
 
-    let Channel(tx, _) = active_muxer.use_channel(0x0003u16);
-    let Channel(_, rx) = passive_muxer.use_channel(0x8003u16);
+    let Channel(tx, _) = active_muxer.use_channel(0x0003u16).unwrap();
+    let Channel(_, rx) = passive_muxer.use_channel(0x0003u16).unwrap();
 
     let payload = random_payload(50);
     tx.send(payload.clone()).unwrap();
-    let received_payload = rx.recv().unwrap();
+    let received_payload = rx.recv().unwrap().unwrap();
     assert_eq!(payload, received_payload)
 }
 
 #[test]
 fn one_way_small_sequence_of_payloads_are_consistent() {
-    let passive = setup_passive_muxer::<50301>();
+    let passive = setup_passive_muxer::<50301>(Role::Responder);
 
     // HACK: a small sleep seems to be required for Github actions runner to
     // formally expose the port
     thread::sleep(std::time::Duration::from_secs(1));
 
-    let active = setup_active_muxer::<50301>();
+    let active = setup_active_muxer::<50301>(Role::Initiator);
 
     let mut active_muxer = active.join().unwrap();
     let mut passive_muxer = passive.join().unwrap();
 
-    let Channel(tx, _) = active_muxer.use_channel(0x0003u16);
-    let Channel(_, rx) = passive_muxer.use_channel(0x8003u16);
+    let Channel(tx, _) = active_muxer.use_channel(0x0003u16).unwrap();
+    let Channel(_, rx) = passive_muxer.use_channel(0x0003u16).unwrap();
 
-    for _ in [0..100] {
+    for _ in 0..100 {
         let payload = random_payload(50);
         tx.send(payload.clone()).unwrap();
-        let received_payload = rx.recv().unwrap();
+        let received_payload = rx.recv().unwrap().unwrap();
         assert_eq!(payload, received_payload)
     }
 }
+
+#[test]
+fn shutdown_is_reported_to_both_peers() {
+    let passive = setup_passive_muxer::<50401>(Role::Responder);
+
+    // HACK: a small sleep seems to be required for Github actions runner to
+    // formally expose the port
+    thread::sleep(std::time::Duration::from_secs(1));
+
+    let active = setup_active_muxer::<50401>(Role::Initiator);
+
+    let mut active_muxer = active.join().unwrap();
+    let mut passive_muxer = passive.join().unwrap();
+
+    let Channel(_, active_rx) = active_muxer.use_channel(0x0003u16).unwrap();
+    let Channel(_, passive_rx) = passive_muxer.use_channel(0x0003u16).unwrap();
+
+    active_muxer.shutdown().unwrap();
+
+    assert!(matches!(active_rx.recv().unwrap(), Err(MuxError::Shutdown)));
+    assert!(matches!(passive_rx.recv().unwrap(), Err(MuxError::Eof)));
+
+    // the connection is over, channels get disconnected after the final error
+    assert!(passive_rx.recv().is_err());
+
+    assert!(active_muxer.join().is_ok());
+    assert!(matches!(passive_muxer.join(), Err(MuxError::Eof)));
+}
+
+#[test]
+fn duplex_peers_use_both_modes_of_a_protocol() {
+    let passive = setup_passive_muxer::<50501>(Role::InitiatorAndResponder);
+
+    // HACK: a small sleep seems to be required for Github actions runner to
+    // formally expose the port
+    thread::sleep(std::time::Duration::from_secs(1));
+
+    let active = setup_active_muxer::<50501>(Role::InitiatorAndResponder);
+
+    let mut active_muxer = active.join().unwrap();
+    let mut passive_muxer = passive.join().unwrap();
+
+    let Channel(active_init_tx, active_init_rx) =
+        active_muxer.use_channel_as(3, Mode::Initiator).unwrap();
+    let Channel(active_resp_tx, active_resp_rx) =
+        active_muxer.use_channel_as(3, Mode::Responder).unwrap();
+    let Channel(passive_init_tx, passive_init_rx) =
+        passive_muxer.use_channel_as(3, Mode::Initiator).unwrap();
+    let Channel(passive_resp_tx, passive_resp_rx) =
+        passive_muxer.use_channel_as(3, Mode::Responder).unwrap();
+
+    let payload = random_payload(50);
+    active_init_tx.send(payload.clone()).unwrap();
+    assert_eq!(passive_resp_rx.recv().unwrap().unwrap(), payload);
+
+    let payload = random_payload(50);
+    passive_resp_tx.send(payload.clone()).unwrap();
+    assert_eq!(active_init_rx.recv().unwrap().unwrap(), payload);
+
+    let payload = random_payload(50);
+    passive_init_tx.send(payload.clone()).unwrap();
+    assert_eq!(active_resp_rx.recv().unwrap().unwrap(), payload);
+
+    let payload = random_payload(50);
+    active_resp_tx.send(payload.clone()).unwrap();
+    assert_eq!(passive_init_rx.recv().unwrap().unwrap(), payload);
+}
+
+#[test]
+fn segment_for_unsupported_mode_is_a_protocol_violation() {
+    let passive = setup_passive_muxer::<50601>(Role::Initiator);
+
+    // HACK: a small sleep seems to be required for Github actions runner to
+    // formally expose the port
+    thread::sleep(std::time::Duration::from_secs(1));
+
+    let active = setup_active_muxer::<50601>(Role::Initiator);
+
+    let mut active_muxer = active.join().unwrap();
+    let mut passive_muxer = passive.join().unwrap();
+
+    let Channel(tx, _) = active_muxer.use_channel(0x0003u16).unwrap();
+    let Channel(_, rx) = passive_muxer.use_channel(0x0003u16).unwrap();
+
+    // both ends are initiators, the passive one can't accept initiator segments
+    tx.send(random_payload(50)).unwrap();
+
+    assert!(matches!(
+        rx.recv().unwrap(),
+        Err(MuxError::ProtocolViolation(_))
+    ));
+
+    assert!(matches!(
+        passive_muxer.join(),
+        Err(MuxError::ProtocolViolation(_))
+    ));
+}
+
+/// Receives segments until the expected amount of bytes is gathered
+fn recv_bytes(rx: &DemuxReceiver, len: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(len);
+
+    while output.len() < len {
+        output.extend(rx.recv().unwrap().unwrap());
+    }
+
+    output
+}
+
+#[test]
+fn memory_bearer_pair_moves_payloads_both_ways() {
+    let (client_bearer, server_bearer) = MemoryBearer::pair();
+
+    let mut client_muxer = Multiplexer::setup(client_bearer, Role::Initiator, &[3]).unwrap();
+    let mut server_muxer = Multiplexer::setup(server_bearer, Role::Responder, &[3]).unwrap();
+
+    let Channel(client_tx, client_rx) = client_muxer.use_channel(3).unwrap();
+    let Channel(server_tx, server_rx) = server_muxer.use_channel(3).unwrap();
+
+    for size in [50, 70_000] {
+        let payload = random_payload(size);
+
+        client_tx.send(payload.clone()).unwrap();
+        let received = recv_bytes(&server_rx, size);
+        assert_eq!(payload, received);
+
+        server_tx.send(received).unwrap();
+        let echoed = recv_bytes(&client_rx, size);
+        assert_eq!(payload, echoed);
+    }
+
+    server_muxer.shutdown().unwrap();
+
+    assert!(matches!(client_rx.recv().unwrap(), Err(MuxError::Eof)));
+    assert!(matches!(client_muxer.join(), Err(MuxError::Eof)));
+    assert!(server_muxer.join().is_ok());
+}
+
+#[test]
+fn small_sdu_splits_payloads_into_many_segments() {
+    let (client_bearer, server_bearer) = MemoryBearer::pair();
+
+    let config = MultiplexerConfig {
+        max_sdu_size: 100,
+        ..MultiplexerConfig::default()
+    };
+
+    let mut client_muxer =
+        Multiplexer::setup_with_config(client_bearer, Role::Initiator, &[3], config).unwrap();
+    let mut server_muxer = Multiplexer::setup(server_bearer, Role::Responder, &[3]).unwrap();
+
+    let Channel(client_tx, _) = client_muxer.use_channel(3).unwrap();
+    let Channel(_, server_rx) = server_muxer.use_channel(3).unwrap();
+
+    let payload = random_payload(1_050);
+    client_tx.send(payload.clone()).unwrap();
+
+    let mut segments = 0;
+    let mut received = Vec::new();
+
+    while received.len() < payload.len() {
+        let chunk = server_rx.recv().unwrap().unwrap();
+        assert!(chunk.len() <= 100);
+        received.extend(chunk);
+        segments += 1;
+    }
+
+    assert_eq!(received, payload);
+    assert_eq!(segments, 11);
+}
+
+#[test]
+fn invalid_sdu_size_is_rejected() {
+    let (bearer, _) = MemoryBearer::pair();
+
+    let config = MultiplexerConfig {
+        max_sdu_size: 0,
+        ..MultiplexerConfig::default()
+    };
+
+    assert!(Multiplexer::setup_with_config(bearer, Role::Initiator, &[3], config).is_err());
+}
+
+#[test]
+fn exceeding_the_ingress_limit_is_a_protocol_violation() {
+    let (client_bearer, server_bearer) = MemoryBearer::pair();
+
+    let config = MultiplexerConfig::default().with_ingress_limit(3, 100);
+
+    let mut client_muxer = Multiplexer::setup(client_bearer, Role::Initiator, &[3]).unwrap();
+    let mut server_muxer =
+        Multiplexer::setup_with_config(server_bearer, Role::Responder, &[3], config).unwrap();
+
+    let Channel(client_tx, _) = client_muxer.use_channel(3).unwrap();
+    let Channel(_, server_rx) = server_muxer.use_channel(3).unwrap();
+
+    // consumed payloads don't count towards the limit
+    for _ in 0..5 {
+        client_tx.send(random_payload(60)).unwrap();
+        assert_eq!(server_rx.recv().unwrap().unwrap().len(), 60);
+        assert_eq!(server_rx.buffered(), 0);
+    }
+
+    // the server doesn't consume these, so the second one overflows
+    client_tx.send(random_payload(60)).unwrap();
+    client_tx.send(random_payload(60)).unwrap();
+
+    assert!(matches!(
+        server_muxer.join(),
+        Err(MuxError::ProtocolViolation(_))
+    ));
+
+    assert!(matches!(client_muxer.join(), Err(MuxError::Eof)));
+}
+
+#[test]
+fn stats_count_the_traffic_of_each_protocol() {
+    let (client_bearer, server_bearer) = MemoryBearer::pair();
+
+    let config = MultiplexerConfig {
+        max_sdu_size: 100,
+        ..MultiplexerConfig::default()
+    };
+
+    let mut client_muxer =
+        Multiplexer::setup_with_config(client_bearer, Role::Initiator, &[2, 3], config).unwrap();
+    let mut server_muxer = Multiplexer::setup(server_bearer, Role::Responder, &[2, 3]).unwrap();
+
+    let Channel(client_tx, _) = client_muxer.use_channel(3).unwrap();
+    let Channel(_, server_rx) = server_muxer.use_channel(3).unwrap();
+
+    client_tx.send(random_payload(250)).unwrap();
+    recv_bytes(&server_rx, 250);
+
+    // the counters are updated once the bearer write returns
+    while client_muxer
+        .stats()
+        .protocol(3, Mode::Initiator)
+        .unwrap()
+        .segments_out
+        < 3
+    {
+        thread::yield_now();
+    }
+
+    let client_stats = client_muxer.stats();
+    let sent = client_stats.protocol(3, Mode::Initiator).unwrap();
+    assert_eq!(sent.segments_out, 3);
+    assert_eq!(sent.bytes_out, 250);
+    assert_eq!(sent.egress_queue, 0);
+    assert!(sent.last_activity.is_some());
+
+    let idle = client_stats.protocol(2, Mode::Initiator).unwrap();
+    assert_eq!(idle, &ProtocolStats::default());
+
+    let server_stats = server_muxer.stats();
+    let received = server_stats.protocol(3, Mode::Responder).unwrap();
+    assert_eq!(received.segments_in, 3);
+    assert_eq!(received.bytes_in, 250);
+    assert_eq!(received.ingress_queue, 0);
+    assert!(server_stats.remote_clock_drift.is_some());
+
+    // payloads not consumed yet show up in the ingress queue
+    client_tx.send(random_payload(50)).unwrap();
+    while server_muxer
+        .stats()
+        .protocol(3, Mode::Responder)
+        .unwrap()
+        .segments_in
+        < 4
+    {
+        thread::yield_now();
+    }
+    assert_eq!(
+        server_muxer
+            .stats()
+            .protocol(3, Mode::Responder)
+            .unwrap()
+            .ingress_queue,
+        50
+    );
+}
+
+#[test]
+fn protocols_can_be_added_and_removed_while_running() {
+    let (client_bearer, server_bearer) = MemoryBearer::pair();
+
+    let mut client_muxer = Multiplexer::setup(client_bearer, Role::Initiator, &[0]).unwrap();
+    let mut server_muxer = Multiplexer::setup(server_bearer, Role::Responder, &[0]).unwrap();
+
+    client_muxer.use_channel(0).unwrap();
+
+    assert!(matches!(
+        client_muxer.use_channel(0),
+        Err(ChannelError::AlreadyTaken(0, Mode::Initiator))
+    ));
+
+    assert!(matches!(
+        client_muxer.use_channel(3),
+        Err(ChannelError::NotRegistered(3, Mode::Initiator))
+    ));
+
+    server_muxer.add_protocol(3).unwrap();
+    client_muxer.add_protocol(3).unwrap();
+
+    assert!(matches!(
+        client_muxer.add_protocol(3),
+        Err(ChannelError::AlreadyRegistered(3))
+    ));
+
+    let Channel(client_tx, _) = client_muxer.use_channel(3).unwrap();
+    let Channel(_, server_rx) = server_muxer.use_channel(3).unwrap();
+
+    let payload = random_payload(50);
+    client_tx.send(payload.clone()).unwrap();
+    assert_eq!(server_rx.recv().unwrap().unwrap(), payload);
+
+    server_muxer.remove_protocol(3).unwrap();
+    assert!(server_rx.recv().is_err());
+
+    assert!(matches!(
+        server_muxer.remove_protocol(3),
+        Err(ChannelError::UnknownProtocol(3))
+    ));
+
+    client_muxer.shutdown().unwrap();
+
+    assert!(matches!(
+        client_muxer.add_protocol(2),
+        Err(ChannelError::ConnectionEnded(MuxError::Shutdown))
+    ));
+}
+
+#[test]
+fn recorded_session_can_be_replayed() {
+    let path = std::env::temp_dir().join(format!("pallas-session-{}.bin", std::process::id()));
+
+    let (client_bearer, server_bearer) = MemoryBearer::pair();
+    let client_bearer = RecordingBearer::create(client_bearer, &path).unwrap();
+
+    let mut client_muxer = Multiplexer::setup(client_bearer, Role::Initiator, &[3]).unwrap();
+    let mut server_muxer = Multiplexer::setup(server_bearer, Role::Responder, &[3]).unwrap();
+
+    let Channel(client_tx, client_rx) = client_muxer.use_channel(3).unwrap();
+    let Channel(server_tx, server_rx) = server_muxer.use_channel(3).unwrap();
+
+    let exchange: Vec<_> = (0..3)
+        .map(|_| (random_payload(20), random_payload(30)))
+        .collect();
+
+    for (request, response) in exchange.iter() {
+        client_tx.send(request.clone()).unwrap();
+        assert_eq!(&server_rx.recv().unwrap().unwrap(), request);
+        server_tx.send(response.clone()).unwrap();
+        assert_eq!(&client_rx.recv().unwrap().unwrap(), response);
+    }
+
+    client_muxer.shutdown().unwrap();
+    client_muxer.join().unwrap();
+    server_muxer.join().unwrap_err();
+
+    let records = read_records(&mut std::fs::File::open(&path).unwrap()).unwrap();
+    let directions: Vec<_> = records.iter().map(|r| r.direction).collect();
+    assert_eq!(
+        directions,
+        [Direction::Outbound, Direction::Inbound].repeat(3)
+    );
+    assert!(records.iter().all(|r| r.protocol_id & 0x7fff == 3));
+
+    let bearer = ReplayBearer::open(&path).unwrap();
+    let mut replay_muxer = Multiplexer::setup(bearer, Role::Initiator, &[3]).unwrap();
+    let Channel(replay_tx, replay_rx) = replay_muxer.use_channel(3).unwrap();
+
+    for (request, response) in exchange.iter() {
+        replay_tx.send(request.clone()).unwrap();
+        assert_eq!(&replay_rx.recv().unwrap().unwrap(), response);
+    }
+
+    // the recording ends with the last response, and so does the connection
+    assert!(matches!(replay_rx.recv().unwrap(), Err(MuxError::Eof)));
+    assert!(matches!(replay_muxer.join(), Err(MuxError::Eof)));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn replay_ends_after_last_inbound_segment() {
+    let response = random_payload(30);
+
+    let records = vec![
+        Record {
+            direction: Direction::Outbound,
+            timestamp: 0,
+            segment_ts: 0,
+            protocol_id: 3,
+            payload: random_payload(20),
+        },
+        Record {
+            direction: Direction::Inbound,
+            timestamp: 10,
+            segment_ts: 10,
+            protocol_id: 3 | 0x8000,
+            payload: response.clone(),
+        },
+    ];
+
+    let bearer = ReplayBearer::new(records);
+    let mut replay_muxer = Multiplexer::setup(bearer, Role::Initiator, &[3]).unwrap();
+    let Channel(replay_tx, replay_rx) = replay_muxer.use_channel(3).unwrap();
+
+    replay_tx.send(random_payload(20)).unwrap();
+    assert_eq!(replay_rx.recv().unwrap().unwrap(), response);
+
+    // the recording is over, without anything else written by the local end
+    assert!(matches!(replay_rx.recv().unwrap(), Err(MuxError::Eof)));
+    assert!(matches!(replay_muxer.join(), Err(MuxError::Eof)));
+}
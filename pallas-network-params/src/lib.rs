@@ -0,0 +1,218 @@
+//! Well-known Cardano networks and their parameters
+//!
+//! A [Network] identifies the chain a component works with: the magic sent
+//! in the handshake, the network id in the header of addresses and the
+//! timing of slots. Configuring a single value keeps all of them in sync.
+
+use std::{
+    fmt::Display,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const MAINNET_MAGIC: u64 = 764824073;
+pub const TESTNET_MAGIC: u64 = 1097911063;
+pub const PREPROD_MAGIC: u64 = 1;
+pub const PREVIEW_MAGIC: u64 = 2;
+
+/// The network id of mainnet addresses, every other network uses 0
+pub const MAINNET_NETWORK_ID: u8 = 1;
+pub const TESTNET_NETWORK_ID: u8 = 0;
+
+/// A Cardano network
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Network {
+    Mainnet,
+    /// The legacy public testnet, replaced by the preprod and preview ones
+    Testnet,
+    Preprod,
+    Preview,
+    /// Any other network (eg: a local devnet), identified by its magic
+    Custom(u64),
+}
+
+impl Network {
+    /// The network with the given magic, a preset if it's a known one
+    pub fn from_magic(magic: u64) -> Self {
+        match magic {
+            MAINNET_MAGIC => Network::Mainnet,
+            TESTNET_MAGIC => Network::Testnet,
+            PREPROD_MAGIC => Network::Preprod,
+            PREVIEW_MAGIC => Network::Preview,
+            other => Network::Custom(other),
+        }
+    }
+
+    /// The magic that identifies the network in the handshake
+    pub const fn magic(&self) -> u64 {
+        match self {
+            Network::Mainnet => MAINNET_MAGIC,
+            Network::Testnet => TESTNET_MAGIC,
+            Network::Preprod => PREPROD_MAGIC,
+            Network::Preview => PREVIEW_MAGIC,
+            Network::Custom(magic) => *magic,
+        }
+    }
+
+    /// The network id in the header of Shelley addresses
+    pub const fn network_id(&self) -> u8 {
+        match self {
+            Network::Mainnet => MAINNET_NETWORK_ID,
+            _ => TESTNET_NETWORK_ID,
+        }
+    }
+
+    /// The timing of the slots of the network, `None` for custom networks
+    /// since it depends on their genesis
+    pub fn slot_timing(&self) -> Option<SlotTiming> {
+        match self {
+            Network::Mainnet => Some(SlotTiming {
+                byron_start: 1506203091,
+                byron_slot_length: 20,
+                byron_epoch_length: 21600,
+                shelley_start_slot: 4492800,
+                shelley_start: 1596059091,
+                shelley_slot_length: 1,
+                shelley_epoch_length: 432000,
+            }),
+            Network::Testnet => Some(SlotTiming {
+                byron_start: 1563999616,
+                byron_slot_length: 20,
+                byron_epoch_length: 21600,
+                shelley_start_slot: 1598400,
+                shelley_start: 1595967616,
+                shelley_slot_length: 1,
+                shelley_epoch_length: 432000,
+            }),
+            Network::Preprod => Some(SlotTiming {
+                byron_start: 1654041600,
+                byron_slot_length: 20,
+                byron_epoch_length: 21600,
+                shelley_start_slot: 86400,
+                shelley_start: 1655769600,
+                shelley_slot_length: 1,
+                shelley_epoch_length: 432000,
+            }),
+            // preview starts in the Shelley era straight away
+            Network::Preview => Some(SlotTiming {
+                byron_start: 1666656000,
+                byron_slot_length: 20,
+                byron_epoch_length: 4320,
+                shelley_start_slot: 0,
+                shelley_start: 1666656000,
+                shelley_slot_length: 1,
+                shelley_epoch_length: 86400,
+            }),
+            Network::Custom(_) => None,
+        }
+    }
+}
+
+impl From<Network> for u64 {
+    fn from(network: Network) -> Self {
+        network.magic()
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Preprod => write!(f, "preprod"),
+            Network::Preview => write!(f, "preview"),
+            Network::Custom(magic) => write!(f, "{}", magic),
+        }
+    }
+}
+
+/// The text isn't the name of a known network nor a magic
+#[derive(Debug)]
+pub struct ParseNetworkError(String);
+
+impl Display for ParseNetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown network {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseNetworkError {}
+
+impl FromStr for Network {
+    type Err = ParseNetworkError;
+
+    /// Parses the name of a preset (eg: `mainnet`) or a magic
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "preprod" => Ok(Network::Preprod),
+            "preview" => Ok(Network::Preview),
+            other => other
+                .parse()
+                .map(Network::from_magic)
+                .map_err(|_| ParseNetworkError(s.to_owned())),
+        }
+    }
+}
+
+/// The length of slots and epochs of a network, in the Byron era and from
+/// the Shelley era onwards
+///
+/// Times are in seconds, start times are Unix timestamps and epoch lengths
+/// are in slots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotTiming {
+    /// The start of the first slot of the chain
+    pub byron_start: u64,
+    pub byron_slot_length: u64,
+    pub byron_epoch_length: u64,
+    /// The first slot of the Shelley era
+    pub shelley_start_slot: u64,
+    /// The start of the first slot of the Shelley era
+    pub shelley_start: u64,
+    pub shelley_slot_length: u64,
+    pub shelley_epoch_length: u64,
+}
+
+impl SlotTiming {
+    fn shelley_start_epoch(&self) -> u64 {
+        self.shelley_start_slot / self.byron_epoch_length
+    }
+
+    /// The Unix timestamp of the start of the slot
+    pub fn slot_to_timestamp(&self, slot: u64) -> u64 {
+        match slot.checked_sub(self.shelley_start_slot) {
+            Some(since_shelley) => self.shelley_start + since_shelley * self.shelley_slot_length,
+            None => self.byron_start + slot * self.byron_slot_length,
+        }
+    }
+
+    /// The start of the slot as a system time
+    pub fn slot_to_time(&self, slot: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.slot_to_timestamp(slot))
+    }
+
+    /// The slot that's current at the Unix timestamp, `None` if it's before
+    /// the start of the chain
+    pub fn timestamp_to_slot(&self, timestamp: u64) -> Option<u64> {
+        match timestamp.checked_sub(self.shelley_start) {
+            Some(since_shelley) => {
+                Some(self.shelley_start_slot + since_shelley / self.shelley_slot_length)
+            }
+            None => timestamp
+                .checked_sub(self.byron_start)
+                .map(|since_byron| since_byron / self.byron_slot_length),
+        }
+    }
+
+    /// The epoch the slot belongs to
+    pub fn slot_epoch(&self, slot: u64) -> u64 {
+        match slot.checked_sub(self.shelley_start_slot) {
+            Some(since_shelley) => {
+                self.shelley_start_epoch() + since_shelley / self.shelley_epoch_length
+            }
+            None => slot / self.byron_epoch_length,
+        }
+    }
+}
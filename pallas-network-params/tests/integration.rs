@@ -0,0 +1,54 @@
+use pallas_network_params::{Network, MAINNET_MAGIC};
+
+#[test]
+fn presets_are_found_by_magic() {
+    assert_eq!(Network::from_magic(MAINNET_MAGIC), Network::Mainnet);
+    assert_eq!(Network::from_magic(2), Network::Preview);
+    assert_eq!(Network::from_magic(42), Network::Custom(42));
+
+    for network in [
+        Network::Mainnet,
+        Network::Testnet,
+        Network::Preprod,
+        Network::Preview,
+    ] {
+        assert_eq!(Network::from_magic(network.magic()), network);
+        assert_eq!(network.to_string().parse::<Network>().unwrap(), network);
+    }
+
+    assert_eq!("42".parse::<Network>().unwrap(), Network::Custom(42));
+    assert!("devnet".parse::<Network>().is_err());
+}
+
+#[test]
+fn only_mainnet_addresses_have_network_id_1() {
+    assert_eq!(Network::Mainnet.network_id(), 1);
+    assert_eq!(Network::Preprod.network_id(), 0);
+    assert_eq!(Network::Custom(42).network_id(), 0);
+}
+
+#[test]
+fn mainnet_slots_map_to_time_and_epochs() {
+    let timing = Network::Mainnet.slot_timing().unwrap();
+
+    // last byron slot, first shelley slot
+    assert_eq!(timing.slot_to_timestamp(4492799), 1596059071);
+    assert_eq!(timing.slot_to_timestamp(4492800), 1596059091);
+    assert_eq!(timing.slot_epoch(4492799), 207);
+    assert_eq!(timing.slot_epoch(4492800), 208);
+    assert_eq!(timing.slot_epoch(4492800 + 432000), 209);
+
+    assert_eq!(timing.timestamp_to_slot(1596059071), Some(4492799));
+    assert_eq!(timing.timestamp_to_slot(1596059092), Some(4492801));
+    assert_eq!(timing.timestamp_to_slot(1506203090), None);
+
+    assert!(Network::Custom(42).slot_timing().is_none());
+}
+
+#[test]
+fn preview_starts_in_shelley() {
+    let timing = Network::Preview.slot_timing().unwrap();
+
+    assert_eq!(timing.slot_to_timestamp(0), 1666656000);
+    assert_eq!(timing.slot_epoch(86400), 1);
+}
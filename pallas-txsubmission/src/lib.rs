@@ -29,7 +29,7 @@ pub type TxId = u64;
 pub struct TxIdAndSize(TxId, TxSizeInBytes);
 
 impl EncodePayload for TxIdAndSize {
-    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), Box<dyn std::error::Error>> {
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
         e.array(2)?;
         e.u64(self.0)?;
         e.u32(self.1)?;
@@ -39,7 +39,7 @@ impl EncodePayload for TxIdAndSize {
 }
 
 impl DecodePayload for TxIdAndSize {
-    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, Box<dyn std::error::Error>> {
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
         d.array()?;
         let id = d.u64()?;
         let size = d.u32()?;
@@ -51,7 +51,7 @@ impl DecodePayload for TxIdAndSize {
 pub type TxBody = Vec<u8>;
 
 #[derive(Debug, Clone)]
-pub struct Tx(TxId, TxBody);
+pub struct Tx(pub TxId, pub TxBody);
 
 impl From<&Tx> for TxIdAndSize {
     fn from(other: &Tx) -> Self {
@@ -59,86 +59,20 @@ impl From<&Tx> for TxIdAndSize {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, EncodePayload, DecodePayload)]
 pub enum Message {
+    #[label(0)]
     RequestTxIds(Blocking, TxCount, TxCount),
+    #[label(1)]
     ReplyTxIds(Vec<TxIdAndSize>),
+    #[label(2)]
     RequestTxs(Vec<TxId>),
+    #[label(3)]
     ReplyTxs(Vec<TxBody>),
+    #[label(4)]
     Done,
 }
 
-impl EncodePayload for Message {
-    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), Box<dyn std::error::Error>> {
-        match self {
-            Message::RequestTxIds(blocking, ack, req) => {
-                e.array(4)?.u16(0)?;
-                e.bool(*blocking)?;
-                e.u16(*ack)?;
-                e.u16(*req)?;
-                Ok(())
-            }
-            Message::ReplyTxIds(ids) => {
-                e.array(2)?.u16(1)?;
-                e.array(ids.len() as u64)?;
-                for id in ids {
-                    id.encode_payload(e)?;
-                }
-                Ok(())
-            }
-            Message::RequestTxs(ids) => {
-                e.array(2)?.u16(2)?;
-                e.array(ids.len() as u64)?;
-                for id in ids {
-                    e.u64(*id)?;
-                }
-                Ok(())
-            }
-            Message::ReplyTxs(txs) => {
-                e.array(2)?.u16(3)?;
-                e.array(txs.len() as u64)?;
-                for tx in txs {
-                    e.bytes(tx)?;
-                }
-                Ok(())
-            }
-            Message::Done => {
-                e.array(1)?.u16(4)?;
-                Ok(())
-            }
-        }
-    }
-}
-
-impl DecodePayload for Message {
-    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, Box<dyn std::error::Error>> {
-        d.array()?;
-        let label = d.u16()?;
-
-        match label {
-            0 => {
-                let blocking = d.bool()?;
-                let ack = d.u16()?;
-                let req = d.u16()?;
-                Ok(Message::RequestTxIds(blocking, ack, req))
-            }
-            1 => {
-                let items = Vec::<TxIdAndSize>::decode_payload(d)?;
-                Ok(Message::ReplyTxIds(items))
-            }
-            2 => {
-                let ids = d.array_iter::<TxId>()?.try_collect()?;
-                Ok(Message::RequestTxs(ids))
-            }
-            3 => {
-                todo!()
-            }
-            4 => Ok(Message::Done),
-            x => Err(Box::new(CodecError::BadLabel(x))),
-        }
-    }
-}
-
 /// A very basic tx provider agent with a fixed set of tx to submit
 ///
 /// This provider takes a set of tx from a vec as the single, static source of
@@ -180,14 +114,17 @@ impl NaiveProvider {
 
     fn send_tx_ids(mut self, tx: &impl MachineOutput) -> Transition<Self> {
         debug!("draining {} from tx fifo queue", self.acknowledged_count);
-        self.fifo_txs.drain(0..(self.acknowledged_count - 1));
+        let acknowledged = self.acknowledged_count.min(self.fifo_txs.len());
+        self.fifo_txs.drain(0..acknowledged);
 
         debug!(
             "sending next {} tx ids from fifo queue",
             self.requested_ids_count
         );
-        let to_send = self.fifo_txs[0..self.requested_ids_count]
+        let to_send = self
+            .fifo_txs
             .iter()
+            .take(self.requested_ids_count)
             .map_into()
             .collect_vec();
 
@@ -234,7 +171,7 @@ impl NaiveProvider {
         );
 
         Ok(Self {
-            state: State::Idle,
+            state: State::TxIdsNonBlocking,
             requested_ids_count,
             acknowledged_count,
             ..self
@@ -245,7 +182,7 @@ impl NaiveProvider {
         debug!("new txs request {:?}", requested_txs,);
 
         Ok(Self {
-            state: State::Idle,
+            state: State::Txs,
             requested_txs: Some(requested_txs),
             ..self
         })
@@ -259,6 +196,10 @@ impl Agent for NaiveProvider {
         self.state == State::Done
     }
 
+    fn state_name(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
     fn has_agency(&self) -> bool {
         match self.state {
             State::Idle => false,
@@ -288,7 +229,7 @@ impl Agent for NaiveProvider {
                 ..self
             }),
             (State::Idle, Message::RequestTxs(ids)) => self.on_txs_request(ids),
-            (_, msg) => Err(MachineError::InvalidMsgForState(self.state, msg).into()),
+            (_, msg) => Err(MachineError::invalid_msg(self.state, msg)),
         }
     }
 }
@@ -0,0 +1,86 @@
+use minicbor::{Decoder, Encoder};
+use pallas_machines::{
+    testing::{ScriptError, ScriptedPeer},
+    to_payload, DecodePayload, MachineError, PayloadDecoder,
+};
+use pallas_txsubmission::{Message, NaiveProvider, State, Tx, TxIdAndSize};
+
+fn txs() -> Vec<Tx> {
+    vec![
+        Tx(1, vec![0xaa; 8]),
+        Tx(2, vec![0xbb; 16]),
+        Tx(3, vec![0xcc; 32]),
+    ]
+}
+
+fn ids(txs: &[Tx]) -> Vec<TxIdAndSize> {
+    txs.iter().map(TxIdAndSize::from).collect()
+}
+
+#[test]
+fn naive_provider_serves_ids_and_txs() {
+    let all = txs();
+
+    let agent = ScriptedPeer::new()
+        .sends(&Message::RequestTxIds(false, 0, 2))
+        .expects(&Message::ReplyTxIds(ids(&all[0..2])))
+        .sends(&Message::RequestTxs(vec![2]))
+        .expects(&Message::ReplyTxs(vec![vec![0xbb; 16]]))
+        // acknowledging the first two ids drops them from the queue
+        .sends(&Message::RequestTxIds(false, 2, 2))
+        .expects(&Message::ReplyTxIds(ids(&all[2..])))
+        .run(NaiveProvider::initial(txs()))
+        .unwrap();
+
+    assert_eq!(agent.state, State::Idle);
+    assert_eq!(agent.fifo_txs.len(), 1);
+}
+
+#[test]
+fn naive_provider_is_done_on_blocking_request() {
+    let agent = ScriptedPeer::new()
+        .sends(&Message::RequestTxIds(true, 0, 1))
+        .expects(&Message::Done)
+        .run(NaiveProvider::initial(txs()))
+        .unwrap();
+
+    assert_eq!(agent.state, State::Done);
+}
+
+#[test]
+fn naive_provider_rejects_replies() {
+    let result = ScriptedPeer::new()
+        .sends(&Message::ReplyTxIds(ids(&txs())))
+        .run(NaiveProvider::initial(txs()));
+
+    assert!(matches!(
+        result,
+        Err(ScriptError::Agent(MachineError::InvalidMsgForState { .. }))
+    ));
+}
+
+#[test]
+fn replies_decode_with_indefinite_lists() {
+    // nodes send the ids in an indefinite-length array
+    let mut payload = Vec::new();
+    let mut e = Encoder::new(&mut payload);
+    e.array(2).unwrap().u16(1).unwrap();
+    e.begin_array().unwrap();
+    e.array(2).unwrap().u64(1).unwrap().u32(8).unwrap();
+    e.array(2).unwrap().u64(2).unwrap().u32(16).unwrap();
+    e.end().unwrap();
+
+    let mut d = PayloadDecoder(Decoder::new(&payload));
+    match Message::decode_payload(&mut d).unwrap() {
+        Message::ReplyTxIds(ids) => assert_eq!(ids.len(), 2),
+        other => panic!("unexpected message {:?}", other),
+    }
+
+    let payload = to_payload(&Message::ReplyTxs(vec![vec![0xaa; 8], vec![0xbb; 16]])).unwrap();
+
+    let mut d = PayloadDecoder(Decoder::new(&payload));
+    match Message::decode_payload(&mut d).unwrap() {
+        Message::ReplyTxs(txs) => assert_eq!(txs, vec![vec![0xaa; 8], vec![0xbb; 16]]),
+        other => panic!("unexpected message {:?}", other),
+    }
+}
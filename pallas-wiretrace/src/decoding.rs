@@ -0,0 +1,175 @@
+use std::{collections::HashMap, fmt::Debug};
+
+use minicbor::{data::Cbor, decode, Decoder};
+use pallas_localstate::Query;
+use pallas_machines::{
+    skip_value, CodecError, DecodePayload, EncodePayload, PayloadDecoder, PayloadEncoder,
+};
+use pallas_multiplexer::Mode;
+
+/// The set of mini-protocols that run over the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flavour {
+    NodeToNode,
+    NodeToClient,
+}
+
+/// A CBOR value kept as is, printed using the CBOR diagnostic notation
+///
+/// Used as the content of messages that depend on the era or the query,
+/// which the trace doesn't need to understand.
+#[derive(Clone)]
+pub struct RawCbor(pub Vec<u8>);
+
+impl Debug for RawCbor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", minicbor::display(&self.0))
+    }
+}
+
+impl EncodePayload for RawCbor {
+    fn encode_payload(&self, e: &mut PayloadEncoder) -> Result<(), CodecError> {
+        e.encode(Cbor::from(self.0.as_slice()))?;
+        Ok(())
+    }
+}
+
+impl DecodePayload for RawCbor {
+    fn decode_payload(d: &mut PayloadDecoder) -> Result<Self, CodecError> {
+        Ok(RawCbor(d.raw_value()?.to_vec()))
+    }
+}
+
+#[derive(Debug)]
+pub struct RawQuery;
+
+impl Query for RawQuery {
+    type Request = RawCbor;
+    type Response = RawCbor;
+}
+
+pub fn protocol_name(flavour: Flavour, protocol_id: u16) -> &'static str {
+    match (flavour, protocol_id) {
+        (_, 0) => "handshake",
+        (Flavour::NodeToNode, 2) => "chainsync",
+        (Flavour::NodeToNode, 3) => "blockfetch",
+        (Flavour::NodeToNode, 4) => "txsubmission",
+        (Flavour::NodeToNode, 8) => "keepalive",
+        (Flavour::NodeToClient, 5) => "chainsync",
+        (Flavour::NodeToClient, 6) => "localtxsubmission",
+        (Flavour::NodeToClient, 7) => "localstate",
+        _ => "unknown",
+    }
+}
+
+fn decode_as<T: DecodePayload + Debug>(message: &[u8]) -> Result<String, CodecError> {
+    let mut decoder = PayloadDecoder(Decoder::new(message));
+    let decoded = T::decode_payload(&mut decoder)?;
+    Ok(format!("{:?}", decoded))
+}
+
+/// Decodes a whole message of a mini-protocol
+///
+/// Returns `None` if there's no codec for the protocol.
+pub fn decode_message(
+    flavour: Flavour,
+    protocol_id: u16,
+    message: &[u8],
+) -> Option<Result<String, CodecError>> {
+    let decoded = match (flavour, protocol_id) {
+        (Flavour::NodeToNode, 0) => decode_as::<pallas_handshake::n2n::Message>(message),
+        (Flavour::NodeToClient, 0) => decode_as::<pallas_handshake::n2c::Message>(message),
+        (Flavour::NodeToNode, 2) | (Flavour::NodeToClient, 5) => {
+            decode_as::<pallas_chainsync::Message<RawCbor>>(message)
+        }
+        (Flavour::NodeToNode, 3) => decode_as::<pallas_blockfetch::Message>(message),
+        (Flavour::NodeToNode, 4) => decode_as::<pallas_txsubmission::Message>(message),
+        (Flavour::NodeToClient, 7) => decode_as::<pallas_localstate::Message<RawQuery>>(message),
+        _ => return None,
+    };
+
+    Some(decoded)
+}
+
+/// Splits the segments of each protocol into whole CBOR messages
+///
+/// A message might span several segments and a segment might hold several
+/// messages, so bytes are buffered per protocol and sender until a complete
+/// CBOR value is available.
+#[derive(Default)]
+pub struct Reassembler {
+    buffers: HashMap<(u16, Mode), Vec<u8>>,
+}
+
+impl Reassembler {
+    /// Adds the payload of a segment and takes the messages it completes
+    pub fn push(&mut self, protocol_id: u16, mode: Mode, payload: &[u8]) -> Vec<Vec<u8>> {
+        let buffer = self.buffers.entry((protocol_id, mode)).or_default();
+        buffer.extend_from_slice(payload);
+
+        let mut messages = Vec::new();
+
+        while !buffer.is_empty() {
+            let mut decoder = Decoder::new(buffer);
+
+            match skip_value(&mut decoder) {
+                Ok(()) => {
+                    let end = decoder.position();
+                    messages.push(buffer.drain(..end).collect());
+                }
+                Err(decode::Error::EndOfInput) => break,
+                // not valid cbor, hand it over as is so that the failure shows up
+                Err(_) => {
+                    messages.push(std::mem::take(buffer));
+                }
+            }
+        }
+
+        messages
+    }
+
+    /// The incomplete bytes left for each protocol and sender
+    pub fn leftovers(&self) -> impl Iterator<Item = (&(u16, Mode), &Vec<u8>)> {
+        self.buffers.iter().filter(|(_, buffer)| !buffer.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_split_and_joined_across_segments() {
+        let mut reassembler = Reassembler::default();
+
+        // [0] followed by the first bytes of [1, h'aabb']
+        let first = reassembler.push(2, Mode::Initiator, &[0x81, 0x00, 0x82, 0x01]);
+        assert_eq!(first, vec![vec![0x81, 0x00]]);
+
+        // segments of other senders don't interfere
+        let other = reassembler.push(2, Mode::Responder, &[0x81, 0x02]);
+        assert_eq!(other, vec![vec![0x81, 0x02]]);
+
+        let second = reassembler.push(2, Mode::Initiator, &[0x42, 0xaa, 0xbb]);
+        assert_eq!(second, vec![vec![0x82, 0x01, 0x42, 0xaa, 0xbb]]);
+
+        assert_eq!(reassembler.leftovers().count(), 0);
+    }
+
+    #[test]
+    fn chainsync_content_is_kept_raw() {
+        // MsgRollForward, content [1, h'aa'], tip [[1, h'aaaa...'], 5]
+        let mut message = vec![
+            0x83, 0x02, 0x82, 0x01, 0x41, 0xaa, 0x82, 0x82, 0x01, 0x58, 0x20,
+        ];
+        message.extend([0xaa; 32]);
+        message.push(0x05);
+
+        let decoded = decode_message(Flavour::NodeToNode, 2, &message)
+            .unwrap()
+            .unwrap();
+
+        assert!(decoded.starts_with("RollForward([1, h'aa'], Tip("));
+        assert!(decode_message(Flavour::NodeToNode, 8, &message).is_none());
+    }
+}
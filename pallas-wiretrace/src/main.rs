@@ -0,0 +1,203 @@
+//! Pretty-prints the multiplexer segments of a captured connection
+//!
+//! Reads either a raw byte capture (the bytes of one direction of a bearer,
+//! as written by the multiplexer) or a session file stored by a
+//! `RecordingBearer`, and prints the header of each segment along with the
+//! mini-protocol messages it completes.
+
+mod decoding;
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    process,
+};
+
+use pallas_multiplexer::{
+    recording::{read_records, Direction},
+    Payload, SegmentHeader,
+};
+
+use decoding::{decode_message, protocol_name, Flavour, Reassembler};
+
+const USAGE: &str = "usage: pallas-wiretrace [--session] [--n2c] [--full] <file>
+
+  --session  the file is a session recorded by a RecordingBearer (default: raw capture)
+  --n2c      decode node-to-client mini-protocols (default: node-to-node)
+  --full     don't truncate long messages";
+
+/// Max length of a printed message unless `--full` is set
+const TRUNCATE_AT: usize = 512;
+
+struct Options {
+    session: bool,
+    flavour: Flavour,
+    full: bool,
+    path: String,
+}
+
+impl Options {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut session = false;
+        let mut flavour = Flavour::NodeToNode;
+        let mut full = false;
+        let mut path = None;
+
+        for arg in args {
+            match arg.as_str() {
+                "--session" => session = true,
+                "--n2c" => flavour = Flavour::NodeToClient,
+                "--full" => full = true,
+                x if x.starts_with("--") => return Err(format!("unknown option {}", x)),
+                _ if path.is_some() => return Err("only one file can be traced".into()),
+                _ => path = Some(arg),
+            }
+        }
+
+        let path = path.ok_or("missing file to trace")?;
+
+        Ok(Options {
+            session,
+            flavour,
+            full,
+            path,
+        })
+    }
+}
+
+/// A segment read from the input, with its direction when known
+struct TracedSegment {
+    direction: Option<Direction>,
+    header: SegmentHeader,
+    payload: Payload,
+}
+
+fn read_capture(reader: &mut impl Read) -> Result<Vec<TracedSegment>, std::io::Error> {
+    let mut segments = Vec::new();
+
+    loop {
+        let mut header = [0u8; SegmentHeader::SIZE];
+
+        match reader.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        };
+
+        let header = SegmentHeader::decode(&header);
+        let mut payload = vec![0u8; header.payload_length as usize];
+        reader.read_exact(&mut payload)?;
+
+        segments.push(TracedSegment {
+            direction: None,
+            header,
+            payload,
+        });
+    }
+
+    Ok(segments)
+}
+
+fn read_session(reader: &mut impl Read) -> Result<Vec<TracedSegment>, std::io::Error> {
+    let segments = read_records(reader)?
+        .into_iter()
+        .map(|record| TracedSegment {
+            direction: Some(record.direction),
+            header: record.header(),
+            payload: record.payload,
+        })
+        .collect();
+
+    Ok(segments)
+}
+
+fn truncate(mut text: String, full: bool) -> String {
+    if !full && text.len() > TRUNCATE_AT {
+        let mut end = TRUNCATE_AT;
+
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        text.truncate(end);
+        text.push_str("...");
+    }
+
+    text
+}
+
+fn print_trace(segments: Vec<TracedSegment>, options: &Options) {
+    let mut reassembler = Reassembler::default();
+
+    for segment in segments {
+        let SegmentHeader {
+            timestamp,
+            protocol_id,
+            mode,
+            payload_length,
+        } = segment.header;
+
+        let arrow = match segment.direction {
+            Some(Direction::Outbound) => ">>",
+            Some(Direction::Inbound) => "<<",
+            None => "--",
+        };
+
+        println!(
+            "{:>10} {} {}({}) {:?} {} bytes",
+            timestamp,
+            arrow,
+            protocol_name(options.flavour, protocol_id),
+            protocol_id,
+            mode,
+            payload_length
+        );
+
+        for message in reassembler.push(protocol_id, mode, &segment.payload) {
+            let text = match decode_message(options.flavour, protocol_id, &message) {
+                Some(Ok(decoded)) => decoded,
+                Some(Err(err)) => format!("<decode error: {}> {}", err, hex::encode(&message)),
+                None => hex::encode(&message),
+            };
+
+            println!("           {}", truncate(text, options.full));
+        }
+    }
+
+    for ((protocol_id, mode), leftover) in reassembler.leftovers() {
+        println!(
+            "incomplete message for {}({}) {:?}: {} bytes",
+            protocol_name(options.flavour, *protocol_id),
+            protocol_id,
+            mode,
+            leftover.len()
+        );
+    }
+}
+
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let segments = File::open(&options.path).and_then(|file| {
+        let mut reader = BufReader::new(file);
+
+        match options.session {
+            true => read_session(&mut reader),
+            false => read_capture(&mut reader),
+        }
+    });
+
+    match segments {
+        Ok(segments) => print_trace(segments, &options),
+        Err(err) => {
+            eprintln!("error reading {}: {}", options.path, err);
+            process::exit(1);
+        }
+    }
+}
@@ -9,6 +9,9 @@
 #![warn(missing_docs)]
 #![warn(missing_doc_code_examples)]
 
+#[doc(inline)]
+pub use pallas_network_params as network_params;
+
 pub mod ouroboros;
 
 pub mod ledger;